@@ -0,0 +1,348 @@
+use std::{env};
+use std::path::{Path};
+use std::str::{FromStr};
+
+use clap::{Parser};
+use serde::{Deserialize};
+
+/// Command-line arguments.
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Serves albums of photos over HTTP.",
+    after_help = "\
+Each setting can also be given in the config file, or as an environment \
+variable called PHOTO_SERVER_ followed by the setting name in upper case, \
+e.g. PHOTO_SERVER_DOCUMENT_ROOT. Command-line arguments take precedence over \
+environment variables, which take precedence over the config file.",
+)]
+struct Args {
+    /// The server address and port to listen on [default: 127.0.0.1:8082].
+    #[arg(long)]
+    address: Option<String>,
+
+    /// The publicly visible URL of the server, ending with "/".
+    #[arg(long)]
+    base_url: Option<String>,
+
+    /// The directory containing the photo albums [default: ./document_root].
+    #[arg(long)]
+    document_root: Option<String>,
+
+    /// The directory in which to cache thumbnails and resized photos
+    /// [default: ./thumbnail_root].
+    #[arg(long)]
+    thumbnail_root: Option<String>,
+
+    /// A TOML file containing settings.
+    #[arg(long)]
+    config: Option<String>,
+
+    /// Generate any missing or out-of-date thumbnails, then exit instead of
+    /// serving. The exit status is non-zero if any failed.
+    #[arg(long)]
+    generate_thumbnails_only: bool,
+
+    /// Write the public albums to this directory as a static web site, then
+    /// exit instead of serving. The exit status is non-zero if any file
+    /// could not be written.
+    #[arg(long)]
+    export_to: Option<String>,
+}
+
+/// Settings, from the command line, environment variables and config file.
+///
+/// `None` means the built-in default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The server address and port to listen on.
+    pub address: Option<String>,
+
+    /// The publicly visible URL of the server.
+    pub base_url: Option<String>,
+
+    /// The directory containing the photo albums.
+    pub document_root: Option<String>,
+
+    /// The directory in which to cache thumbnails and resized photos.
+    pub thumbnail_root: Option<String>,
+
+    /// Photos modified within this many days are badged as "new".
+    pub new_days: Option<u32>,
+
+    /// Whether to make resized image URLs content-addressed.
+    pub versioned_urls: Option<bool>,
+
+    /// Whether to use client hints to choose the resolution of thumbnails.
+    pub client_hints: Option<bool>,
+
+    /// If set, generate thumbnails at startup, and then every this many
+    /// minutes (0 means only at startup).
+    pub precache_minutes: Option<u32>,
+
+    /// Whether to use client hints to serve smaller originals to small
+    /// screens.
+    pub cap_originals: Option<bool>,
+
+    /// Whether to ask browsers to preload the photo in a frame.
+    pub preload_images: Option<bool>,
+
+    /// Whether to choose the format of resized photos using `Accept`.
+    pub negotiate_format: Option<bool>,
+
+    /// The width or height above which `negotiate_format` may choose AVIF.
+    pub avif_min_dimension: Option<u32>,
+
+    /// If `true`, generate thumbnails and exit instead of serving.
+    pub generate_thumbnails_only: Option<bool>,
+
+    /// If set, export a static web site to this directory and exit instead
+    /// of serving.
+    pub export_to: Option<String>,
+
+    /// The OS scheduling priority at which to run.
+    pub niceness: Option<i32>,
+
+    /// The number of requests to handle at once.
+    pub threads: Option<u32>,
+
+    /// The number of requests that may be waiting or being handled before
+    /// more are refused.
+    pub max_requests: Option<u32>,
+
+    /// A line of text shown at the bottom of every page.
+    pub footer_text: Option<String>,
+
+    /// A URL to which `footer_text` links.
+    pub footer_url: Option<String>,
+
+    /// The contents of `/robots.txt`, unless the document root has one.
+    pub robots_txt: Option<String>,
+
+    /// The secret that grants access to administrative requests.
+    pub admin_token: Option<String>,
+
+    /// The user name that every request must send, if any.
+    pub auth_user: Option<String>,
+
+    /// The password that goes with `auth_user`.
+    pub auth_password: Option<String>,
+
+    /// The maximum length of a request body, e.g. an upload.
+    pub max_body_bytes: Option<u32>,
+
+    /// How to log each request: `"plain"`, `"clf"` or `"json"`.
+    pub log_format: Option<String>,
+
+    /// Changing this invalidates all cached files.
+    pub cache_version: Option<u32>,
+
+    /// The JPEG quality of resized photos, from 1 to 100.
+    pub jpeg_quality: Option<u32>,
+
+    /// The JPEG quality of thumbnails, from 1 to 100.
+    pub thumbnail_quality: Option<u32>,
+
+    /// The maximum width of thumbnails.
+    pub thumb_width: Option<u32>,
+
+    /// The maximum height of thumbnails.
+    pub thumb_height: Option<u32>,
+
+    /// The maximum width and height of resized photos.
+    pub max_dimension: Option<u32>,
+
+    /// The width of photos when neither the request nor the album says.
+    pub default_width: Option<u32>,
+
+    /// The height of photos when neither the request nor the album says.
+    pub default_height: Option<u32>,
+
+    /// The filenames that can be an album's README, in order of preference.
+    pub readme_names: Option<Vec<String>>,
+
+    /// The number of bytes of a README shown in an index.
+    pub max_readme_bytes: Option<u32>,
+
+    /// The maximum number of files other than photos listed in an index.
+    pub max_others: Option<u32>,
+
+    /// Whether to refuse static files to pages on other sites.
+    pub referer_check: Option<bool>,
+
+    /// Whether `referer_check` allows requests without a `Referer`.
+    pub allow_empty_referer: Option<bool>,
+
+    /// Whether `min_rating` shows photos without an EXIF rating.
+    pub include_unrated: Option<bool>,
+
+    /// The maximum size of a ZIP archive of an album.
+    pub max_zip_bytes: Option<u32>,
+}
+
+/// A type that can be read from an environment variable.
+trait FromEnv: Sized {
+    fn from_env(value: &str) -> Option<Self>;
+}
+
+impl FromEnv for String {
+    fn from_env(value: &str) -> Option<Self> { Some(value.to_owned()) }
+}
+
+impl FromEnv for bool {
+    fn from_env(value: &str) -> Option<Self> {
+        match value.trim() {
+            "1" | "true" | "yes" => Some(true),
+            "0" | "false" | "no" => Some(false),
+            _ => None,
+        }
+    }
+}
+
+/// A comma-separated list.
+impl FromEnv for Vec<String> {
+    fn from_env(value: &str) -> Option<Self> {
+        Some(value.split(',').map(str::trim).filter(|s| !s.is_empty()).map(String::from).collect())
+    }
+}
+
+macro_rules! impl_fromenv_fromstr {
+    ($t:ty) => {
+        impl FromEnv for $t {
+            fn from_env(value: &str) -> Option<Self> { <$t>::from_str(value.trim()).ok() }
+        }
+    };
+}
+
+impl_fromenv_fromstr!(u32);
+impl_fromenv_fromstr!(i32);
+
+/// If environment variable `name` is set to a valid value, overwrite `field`.
+fn override_from_env<T: FromEnv>(field: &mut Option<T>, name: &str) {
+    if let Some(value) = env::var(name).ok().and_then(|value| T::from_env(&value)) {
+        *field = Some(value);
+    }
+}
+
+/// If `value` is `Some`, overwrite `field`.
+fn override_from_args<T>(field: &mut Option<T>, value: Option<T>) {
+    if value.is_some() { *field = value; }
+}
+
+impl Config {
+    /// Read the config file `filename`.
+    pub fn load(filename: &Path) -> Result<Self, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(&std::fs::read_to_string(filename)?)?)
+    }
+
+    /// Overwrite fields of `self` using `PHOTO_SERVER_*` environment
+    /// variables.
+    fn override_from_env(&mut self) {
+        override_from_env(&mut self.address, "PHOTO_SERVER_ADDRESS");
+        override_from_env(&mut self.base_url, "PHOTO_SERVER_BASE_URL");
+        override_from_env(&mut self.document_root, "PHOTO_SERVER_DOCUMENT_ROOT");
+        override_from_env(&mut self.thumbnail_root, "PHOTO_SERVER_THUMBNAIL_ROOT");
+        override_from_env(&mut self.new_days, "PHOTO_SERVER_NEW_DAYS");
+        override_from_env(&mut self.versioned_urls, "PHOTO_SERVER_VERSIONED_URLS");
+        override_from_env(&mut self.client_hints, "PHOTO_SERVER_CLIENT_HINTS");
+        override_from_env(&mut self.cap_originals, "PHOTO_SERVER_CAP_ORIGINALS");
+        override_from_env(&mut self.precache_minutes, "PHOTO_SERVER_PRECACHE_MINUTES");
+        override_from_env(&mut self.preload_images, "PHOTO_SERVER_PRELOAD_IMAGES");
+        override_from_env(&mut self.negotiate_format, "PHOTO_SERVER_NEGOTIATE_FORMAT");
+        override_from_env(&mut self.avif_min_dimension, "PHOTO_SERVER_AVIF_MIN_DIMENSION");
+        override_from_env(&mut self.generate_thumbnails_only, "PHOTO_SERVER_GENERATE_THUMBNAILS_ONLY");
+        override_from_env(&mut self.export_to, "PHOTO_SERVER_EXPORT_TO");
+        override_from_env(&mut self.niceness, "PHOTO_SERVER_NICENESS");
+        override_from_env(&mut self.threads, "PHOTO_SERVER_THREADS");
+        override_from_env(&mut self.max_requests, "PHOTO_SERVER_MAX_REQUESTS");
+        override_from_env(&mut self.footer_text, "PHOTO_SERVER_FOOTER_TEXT");
+        override_from_env(&mut self.footer_url, "PHOTO_SERVER_FOOTER_URL");
+        override_from_env(&mut self.robots_txt, "PHOTO_SERVER_ROBOTS_TXT");
+        override_from_env(&mut self.admin_token, "PHOTO_SERVER_ADMIN_TOKEN");
+        override_from_env(&mut self.auth_user, "PHOTO_SERVER_AUTH_USER");
+        override_from_env(&mut self.auth_password, "PHOTO_SERVER_AUTH_PASSWORD");
+        override_from_env(&mut self.max_body_bytes, "PHOTO_SERVER_MAX_BODY_BYTES");
+        override_from_env(&mut self.log_format, "PHOTO_SERVER_LOG_FORMAT");
+        override_from_env(&mut self.cache_version, "PHOTO_SERVER_CACHE_VERSION");
+        override_from_env(&mut self.jpeg_quality, "PHOTO_SERVER_JPEG_QUALITY");
+        override_from_env(&mut self.thumbnail_quality, "PHOTO_SERVER_THUMBNAIL_QUALITY");
+        override_from_env(&mut self.thumb_width, "PHOTO_SERVER_THUMB_WIDTH");
+        override_from_env(&mut self.thumb_height, "PHOTO_SERVER_THUMB_HEIGHT");
+        override_from_env(&mut self.max_dimension, "PHOTO_SERVER_MAX_DIMENSION");
+        override_from_env(&mut self.default_width, "PHOTO_SERVER_DEFAULT_WIDTH");
+        override_from_env(&mut self.default_height, "PHOTO_SERVER_DEFAULT_HEIGHT");
+        override_from_env(&mut self.readme_names, "PHOTO_SERVER_README_NAMES");
+        override_from_env(&mut self.max_readme_bytes, "PHOTO_SERVER_MAX_README_BYTES");
+        override_from_env(&mut self.max_others, "PHOTO_SERVER_MAX_OTHERS");
+        override_from_env(&mut self.referer_check, "PHOTO_SERVER_REFERER_CHECK");
+        override_from_env(&mut self.allow_empty_referer, "PHOTO_SERVER_ALLOW_EMPTY_REFERER");
+        override_from_env(&mut self.include_unrated, "PHOTO_SERVER_INCLUDE_UNRATED");
+        override_from_env(&mut self.max_zip_bytes, "PHOTO_SERVER_MAX_ZIP_BYTES");
+    }
+
+    /// Parse the command line, read the config file if any, and apply
+    /// environment variables. Exits the process if the command line is
+    /// invalid.
+    pub fn from_command_line() -> Self {
+        let args = Args::parse();
+        let mut config_filename = None;
+        override_from_env(&mut config_filename, "PHOTO_SERVER_CONFIG");
+        override_from_args(&mut config_filename, args.config);
+        let mut ret = if let Some(filename) = config_filename {
+            Self::load(Path::new(&filename)).unwrap_or_else(
+                |e| panic!("Could not read config file {}: {}", filename, e)
+            )
+        } else {
+            Self::default()
+        };
+        ret.override_from_env();
+        override_from_args(&mut ret.address, args.address);
+        override_from_args(&mut ret.base_url, args.base_url);
+        override_from_args(&mut ret.document_root, args.document_root);
+        override_from_args(&mut ret.thumbnail_root, args.thumbnail_root);
+        if args.generate_thumbnails_only { ret.generate_thumbnails_only = Some(true); }
+        override_from_args(&mut ret.export_to, args.export_to);
+        ret
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// The file in an album directory that contains its `AlbumConfig`.
+pub const ALBUM_CONFIG_FILENAME: &str = ".album.toml";
+
+/// Settings for one album, which override the built-in defaults. Request
+/// parameters override these in turn.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AlbumConfig {
+    /// The default width of photos.
+    pub w: Option<u32>,
+
+    /// The default height of photos.
+    pub h: Option<u32>,
+
+    /// The default slideshow order: `"name"`, `"date"` or `"random"`.
+    pub order: Option<String>,
+}
+
+impl AlbumConfig {
+    /// Read the `ALBUM_CONFIG_FILENAME` in `dir_name`. If it is missing or
+    /// invalid, returns the defaults.
+    pub fn load(dir_name: &Path) -> Self {
+        let filename = dir_name.join(ALBUM_CONFIG_FILENAME);
+        match std::fs::read_to_string(&filename) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                println!("Warning: Ignoring {}: {}", filename.display(), e);
+                Self::default()
+            }),
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    println!("Warning: Cannot read {}: {}", filename.display(), e);
+                }
+                Self::default()
+            },
+        }
+    }
+}
@@ -1,15 +1,36 @@
-use std::{env, fmt};
+use std::{fmt, thread};
+use std::collections::{HashMap};
+use std::ffi::{OsStr};
+use std::hash::{Hash};
 use std::fs::{File};
-use std::io::{Read, Write};
-use std::path::{Path};
+use std::io::{Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
+use base64::prelude::{BASE64_STANDARD, Engine};
+use image::{ImageDecoder};
+use image::metadata::{Orientation};
+use serde::{Serialize};
+
+mod config;
+use config::{ALBUM_CONFIG_FILENAME, AlbumConfig, Config};
+
+#[cfg(feature = "raw")]
+mod raw;
+
+mod multipart;
 mod server;
-use server::{Handler, HttpOkay, HttpError, html_escape, remove_extension, validate_name};
+mod tiff;
+use tiff::{Tiff};
+mod zip;
+use server::{Handler, Headers, HttpOkay, HttpError, Url, constant_time_eq, hash, html_escape, http_date, iso_date, html_escape_attribute, remove_extension, validate_name};
 
 // ----------------------------------------------------------------------------
 
 /// Requested size of an image.
-#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+#[derive(Debug, Clone, Hash, PartialEq, Eq, Serialize)]
 struct Dimensions {
     /// The user-requested width.
     pub w: u32,
@@ -18,12 +39,312 @@ struct Dimensions {
     pub h: u32,
 }
 
-impl fmt::Display for Dimensions {
+/// The default maximum width and height of a resized photo.
+const MAX_DIMENSION: u32 = 2048;
+
+/// The default of `PhotoServer::avif_min_dimension`.
+const AVIF_MIN_DIMENSION: u32 = 256;
+
+/// The default size of a photo.
+const DEFAULT_DIMENSIONS: Dimensions = Dimensions {w: 800, h: 600};
+
+/// The default size of a thumbnail.
+const THUMBNAIL_DIMENSIONS: Dimensions = Dimensions {w: 128, h: 96};
+
+/// The size of a preview inlined in the index.
+const PREVIEW_DIMENSIONS: Dimensions = Dimensions {w: 32, h: 24};
+
+/// The default background colour for `Mode::Pad`.
+const BLACK: [u8; 3] = [0, 0, 0];
+
+/// The maximum `inline_previews` parameter.
+const MAX_INLINE_PREVIEWS: u32 = 100;
+
+/// The number of photos on each page of an index that has a `page` but no
+/// `per_page` parameter.
+const DEFAULT_PER_PAGE: u32 = 100;
+
+/// The maximum resolution of a thumbnail, as a multiple of the usual one.
+const MAX_THUMBNAIL_SCALE: u32 = 3;
+
+/// The extensions of photos, which are thumbnailed and resized.
+#[cfg(not(feature = "heif"))]
+const PHOTO_EXTENSIONS: [&str; 1] = ["jpg"];
+
+/// The extensions of photos, which are thumbnailed and resized.
+#[cfg(feature = "heif")]
+const PHOTO_EXTENSIONS: [&str; 3] = ["jpg", "heic", "heif"];
+
+/// The default `PhotoServer::readme_names`.
+const README_NAMES: [&str; 3] = ["README.md", "README.txt", "index.txt"];
+
+/// The name of the animated slideshow of an album.
+const SLIDESHOW_NAME: &str = "preview.gif";
+
+/// The name of the link to the photo of the day, in an album or overall.
+const PHOTO_OF_THE_DAY_NAME: &str = "photo-of-the-day";
+
+/// The name of the icon that browsers show for the site.
+const FAVICON_NAME: &str = "favicon.ico";
+
+/// The icon served as `FAVICON_NAME`, unless the document root has one.
+const FAVICON: &[u8] = include_bytes!("favicon.ico");
+
+/// The name of the file that tells crawlers what they may fetch.
+const ROBOTS_NAME: &str = "robots.txt";
+
+/// The default contents of `ROBOTS_NAME`, which allow everything.
+const ROBOTS_TXT: &str = "User-agent: *\nDisallow:\n";
+
+/// The maximum number of photos in a slideshow.
+const MAX_SLIDESHOW_FRAMES: usize = 50;
+
+/// The default maximum length of a request body, e.g. an upload, in bytes.
+const MAX_BODY_BYTES: u64 = 32 << 20;
+
+/// The default number of requests that may be in flight before more are
+/// refused. Normal use never comes close.
+const MAX_REQUESTS: usize = 1000;
+
+// ----------------------------------------------------------------------------
+
+/// A requested mirroring of an image.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Flip {
+    /// Swap left and right.
+    Horizontal,
+
+    /// Swap top and bottom.
+    Vertical,
+}
+
+impl Flip {
+    /// Parse `"h"` or `"v"`, mapping errors to `None`.
+    fn parse(s: impl AsRef<str>) -> Option<Self> {
+        match s.as_ref().trim() {
+            "h" => Some(Self::Horizontal),
+            "v" => Some(Self::Vertical),
+            _ => None,
+        }
+    }
+
+    /// Apply `self` to `image`.
+    fn apply(self, image: image::DynamicImage) -> image::DynamicImage {
+        match self {
+            Self::Horizontal => image.fliph(),
+            Self::Vertical => image.flipv(),
+        }
+    }
+}
+
+impl fmt::Display for Flip {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self { Self::Horizontal => "h", Self::Vertical => "v" })
+    }
+}
+
+/// How to fit an image into the requested `Dimensions`.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Mode {
+    /// Scale to fit inside, preserving the aspect ratio. The result may be
+    /// smaller than requested in one dimension.
+    Fit,
+
+    /// Like `Fit`, but centred on a background of exactly the requested size.
+    Pad,
+
+    /// Scale to cover, preserving the aspect ratio, and cut off the edges
+    /// that stick out. The result is exactly the requested size.
+    Crop,
+}
+
+impl Mode {
+    /// Parse `"fit"`, `"pad"` or `"crop"`, mapping errors to `None`.
+    fn parse(s: impl AsRef<str>) -> Option<Self> {
+        match s.as_ref().trim() {
+            "fit" => Some(Self::Fit),
+            "pad" => Some(Self::Pad),
+            "crop" => Some(Self::Crop),
+            _ => None,
+        }
+    }
+
+    /// Parse the CSS `object-fit` keywords `"contain"` or `"cover"`,
+    /// mapping errors to `None`.
+    fn parse_fit(s: impl AsRef<str>) -> Option<Self> {
+        match s.as_ref().trim() {
+            "contain" => Some(Self::Fit),
+            "cover" => Some(Self::Crop),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Mode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self { Self::Fit => "fit", Self::Pad => "pad", Self::Crop => "crop" })
+    }
+}
+
+/// The file format of a resized photo.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Format {
+    /// Lossy, and small. The default.
+    Jpeg,
+
+    /// Lossless, and better for sharp edges, e.g. text and screenshots.
+    Png,
+
+    /// Lossless, and usually smaller than `Png`. Falls back to `Jpeg` for
+    /// images that the encoder does not support.
+    WebP,
+
+    /// Lossy, and much smaller than `Jpeg`, but slow to encode. Needs the
+    /// `avif` feature. Falls back to `Jpeg` like `WebP`.
+    Avif,
+}
+
+/// The speed of the AVIF encoder, from 1 (slowest, and smallest) to 10.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 6;
+
+impl Format {
+    /// Parse `"jpeg"` (or `"jpg"`), `"png"`, `"webp"` or `"avif"`, mapping
+    /// errors to `None`.
+    fn parse(s: impl AsRef<str>) -> Option<Self> {
+        match s.as_ref().trim() {
+            "jpeg" | "jpg" => Some(Self::Jpeg),
+            "png" => Some(Self::Png),
+            "webp" => Some(Self::WebP),
+            "avif" => Some(Self::Avif),
+            _ => None,
+        }
+    }
+
+    /// Encode `image` in this format, at `quality` if `self` is lossy.
+    /// Returns the format actually used, which differs only if `self` does
+    /// not support the colour type of `image`.
+    fn encode(self, image: &image::DynamicImage, quality: u8) -> Result<(Self, Vec<u8>), HttpError> {
+        let mut ret = Vec::<u8>::new();
+        match self {
+            Self::Jpeg => {
+                let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut ret, quality);
+                encoder.encode_image(image).map_err(HttpError::new)?;
+            },
+            Self::Png => {
+                image.write_with_encoder(image::codecs::png::PngEncoder::new(&mut ret)).map_err(HttpError::new)?;
+            },
+            Self::WebP => {
+                match image.write_with_encoder(image::codecs::webp::WebPEncoder::new_lossless(&mut ret)) {
+                    Ok(()) => {},
+                    Err(image::ImageError::Unsupported(e)) => {
+                        println!("Warning: Sending JPEG instead of WebP: {}", e);
+                        return Self::Jpeg.encode(image, quality);
+                    },
+                    Err(e) => return Err(HttpError::new(e)),
+                }
+            },
+            #[cfg(feature = "avif")]
+            Self::Avif => {
+                let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(&mut ret, AVIF_SPEED, quality);
+                match image.write_with_encoder(encoder) {
+                    Ok(()) => {},
+                    Err(image::ImageError::Unsupported(e)) => {
+                        println!("Warning: Sending JPEG instead of AVIF: {}", e);
+                        return Self::Jpeg.encode(image, quality);
+                    },
+                    Err(e) => return Err(HttpError::new(e)),
+                }
+            },
+            #[cfg(not(feature = "avif"))]
+            Self::Avif => {
+                println!("Refused: AVIF needs the avif feature");
+                return Err(HttpError::Invalid);
+            },
+        }
+        Ok((self, ret))
+    }
+
+    /// Returns a response containing `data`, which is in this format.
+    fn response(self, data: Vec<u8>) -> HttpOkay {
+        match self {
+            Self::Jpeg => HttpOkay::Jpeg(data),
+            Self::Png => HttpOkay::Png(data),
+            Self::WebP => HttpOkay::WebP(data),
+            Self::Avif => HttpOkay::Avif(data),
+        }
+    }
+
+    /// Returns the `Format` that is `format`, if any.
+    fn from_image_format(format: image::ImageFormat) -> Option<Self> {
+        match format {
+            image::ImageFormat::Jpeg => Some(Self::Jpeg),
+            image::ImageFormat::Png => Some(Self::Png),
+            image::ImageFormat::WebP => Some(Self::WebP),
+            image::ImageFormat::Avif => Some(Self::Avif),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self { Self::Jpeg => "jpeg", Self::Png => "png", Self::WebP => "webp", Self::Avif => "avif" })
+    }
+}
+
+/// The order of the photos in an index or a slideshow, i.e. the `previous`
+/// and `next` links between frames, and the frames of `preview.gif`.
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq)]
+enum Order {
+    /// By filename.
+    Name,
+
+    /// Oldest first, by modification time.
+    Date,
+
+    /// Smallest first, by file size.
+    Size,
+
+    /// Shuffled, but the same every time.
+    Random,
+}
+
+impl Order {
+    /// Parse `"name"`, `"date"`, `"size"` or `"random"`, mapping errors to
+    /// `None`.
+    fn parse(s: impl AsRef<str>) -> Option<Self> {
+        match s.as_ref().trim() {
+            "name" => Some(Self::Name),
+            "date" => Some(Self::Date),
+            "size" => Some(Self::Size),
+            "random" => Some(Self::Random),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Order {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "?w={}&h={}", self.w, self.h)
+        f.write_str(match self {
+            Self::Name => "name",
+            Self::Date => "date",
+            Self::Size => "size",
+            Self::Random => "random",
+        })
     }
 }
 
+/// Parse a colour written as `RRGGBB` in hexadecimal, mapping errors to
+/// `None`.
+fn parse_colour(s: impl AsRef<str>) -> Option<[u8; 3]> {
+    let s = s.as_ref().trim();
+    let s = s.strip_prefix('#').unwrap_or(s);
+    if s.len() != 6 || !s.is_ascii() { return None; }
+    let component = |i: usize| u8::from_str_radix(&s[i..i + 2], 16).ok();
+    Some([component(0)?, component(2)?, component(4)?])
+}
+
 // ----------------------------------------------------------------------------
 
 /// Information about a request.
@@ -34,27 +355,228 @@ struct Params {
 
     /// The user-requested height, if any.
     pub h: Option<u32>,
+
+    /// The secret for a token-protected album, if any.
+    pub token: Option<String>,
+
+    /// The number of directory levels to include in `/manifest.json`.
+    pub depth: Option<u32>,
+
+    /// The version of the original photo. Ignored except for caching.
+    pub v: Option<String>,
+
+    /// The user-requested mirroring, if any.
+    pub flip: Option<Flip>,
+
+    /// How to fit the photo into the requested size, if specified.
+    pub mode: Option<Mode>,
+
+    /// The background colour for `Mode::Pad`, if specified.
+    pub bg: Option<[u8; 3]>,
+
+    /// The user-requested file format, if any. The default is JPEG.
+    pub fmt: Option<Format>,
+
+    /// The user-requested JPEG quality of a resized photo, if any.
+    pub q: Option<u32>,
+
+    /// Whether to ask the browser to save a static file rather than show it,
+    /// from `download` or `dl`.
+    pub download: bool,
+
+    /// Whether to download an album as a ZIP archive.
+    pub zip: bool,
+
+    /// Whether to describe an album in JSON rather than HTML.
+    pub json: bool,
+
+    /// Whether to serve an original photo even to a small screen.
+    pub original: bool,
+
+    /// Whether to list all files in the index, however many there are.
+    pub all: bool,
+
+    /// An administrative request about the cache, e.g. `"list"`.
+    pub cache: Option<String>,
+
+    /// The number of tiny previews to inline in the index, if any.
+    pub inline_previews: Option<u32>,
+
+    /// The time for which a slideshow shows each photo, in milliseconds.
+    pub delay: Option<u32>,
+
+    /// The page of `/sitemap.xml` or of an index, counting from 1, if any.
+    pub page: Option<u32>,
+
+    /// The number of photos on each page of an index, if not all of them.
+    pub per_page: Option<u32>,
+
+    /// The album to which an administrative request applies, if not all.
+    pub album: Option<String>,
+
+    /// The user-requested slideshow order, if any.
+    pub order: Option<Order>,
+
+    /// The user-requested index order, if any.
+    pub sort: Option<Order>,
+
+    /// Whether to reverse the index and slideshow orders.
+    pub reversed: bool,
+
+    /// If set, show only photos with at least this EXIF rating.
+    pub min_rating: Option<u32>,
+
+    /// If set, each frame moves on to the next after this many seconds.
+    pub slideshow: Option<u32>,
+
+    /// If set, a ZIP archive contains only photos taken on or after this
+    /// date, e.g. `"2024-12-31"`. Not validated until used.
+    pub since: Option<String>,
+
+    /// If set, a ZIP archive contains only photos taken on or before this
+    /// date, likewise.
+    pub until: Option<String>,
+
+    /// The server's maximum width and height, if not `MAX_DIMENSION`. Not a
+    /// URL parameter.
+    pub max_dimension: Option<u32>,
+
+    /// The album's or the server's default width, if any. Not a URL
+    /// parameter.
+    pub default_w: Option<u32>,
+
+    /// The album's or the server's default height, if any. Not a URL
+    /// parameter.
+    pub default_h: Option<u32>,
+
+    /// The album's default slideshow order, if any. Not a URL parameter.
+    pub default_order: Option<Order>,
 }
 
 impl Params {
     /// Fill in missing parameters with default values, and apply maxima.
+    /// Both dimensions are at least 1.
+    ///
+    /// The defaults are `default_w` and `default_h`, if set, otherwise
+    /// `DEFAULT_DIMENSIONS`.
     pub fn get_dimensions(&self) -> Dimensions {
         Dimensions {
-            w: 2048 .min(if let Some(w) = self.w { w } else { 800 }),
-            h: 2048 .min(if let Some(h) = self.h { h } else { 600 }),
+            w: self.get_max_dimension().min(self.w.or(self.default_w).unwrap_or(DEFAULT_DIMENSIONS.w)).max(1),
+            h: self.get_max_dimension().min(self.h.or(self.default_h).unwrap_or(DEFAULT_DIMENSIONS.h)).max(1),
         }
     }
+
+    /// Returns the maximum width and height of a resized photo.
+    pub fn get_max_dimension(&self) -> u32 { self.max_dimension.unwrap_or(MAX_DIMENSION) }
+
+    /// Returns the slideshow order. The default is the index order, if the
+    /// request has one, then the album's, if it has one, otherwise
+    /// `Order::Name`.
+    pub fn get_order(&self) -> Order {
+        self.order.or(self.sort).or(self.default_order).unwrap_or(Order::Name)
+    }
+
+    /// Returns the JPEG quality, from 1 to 100, or `default` if the request
+    /// does not specify one.
+    pub fn get_quality(&self, default: u8) -> u8 {
+        self.q.map_or(default, |q| q.clamp(1, 100) as u8)
+    }
+
+    /// Returns how to fit the photo to the requested size. The default is
+    /// `Mode::Fit`.
+    pub fn get_mode(&self) -> Mode { self.mode.unwrap_or(Mode::Fit) }
+
+    /// Returns the background colour for `Mode::Pad`. The default is black.
+    pub fn get_background(&self) -> [u8; 3] { self.bg.unwrap_or(BLACK) }
+
+    /// Returns a query string for a link to another page, which preserves
+    /// the parameters that carry from page to page, except that `key` is set
+    /// to `value`, or omitted if `value` is `None`.
+    pub fn query_with(&self, key: &str, value: Option<&str>) -> String {
+        let dimensions = self.get_dimensions();
+        let mut pairs = vec![("w", dimensions.w.to_string()), ("h", dimensions.h.to_string())];
+        if let Some(flip) = self.flip { pairs.push(("flip", flip.to_string())); }
+        if let Some(mode) = self.mode { pairs.push(("mode", mode.to_string())); }
+        if let Some([r, g, b]) = self.bg { pairs.push(("bg", format!("{:02x}{:02x}{:02x}", r, g, b))); }
+        if let Some(fmt) = self.fmt { pairs.push(("fmt", fmt.to_string())); }
+        if let Some(q) = self.q { pairs.push(("q", q.to_string())); }
+        if let Some(order) = self.order { pairs.push(("order", order.to_string())); }
+        if let Some(sort) = self.sort { pairs.push(("sort", sort.to_string())); }
+        if self.reversed { pairs.push(("order", "desc".into())); }
+        if let Some(min_rating) = self.min_rating { pairs.push(("min_rating", min_rating.to_string())); }
+        if let Some(slideshow) = self.slideshow { pairs.push(("slideshow", slideshow.to_string())); }
+        if let Some(per_page) = self.per_page { pairs.push(("per_page", per_page.to_string())); }
+        pairs.retain(|&(k, _)| k != key);
+        if let Some(value) = value { pairs.push((key, value.into())); }
+        let mut query = url::form_urlencoded::Serializer::for_suffix(String::from("?"), 1);
+        query.extend_pairs(pairs);
+        query.finish()
+    }
+
+    /// Returns a query string that preserves the parameters that carry from
+    /// page to page.
+    pub fn query(&self) -> String { self.query_with("", None) }
 }
 
 /// Parse a u32, ignoring white-space, and mapping errors to `None`.
 fn parse_u32(s: impl AsRef<str>) -> Option<u32> { s.as_ref().trim().parse::<u32>().ok() }
 
+/// Returns `true` if `s` is "1", "true" or "yes", ignoring white-space.
+fn parse_flag(s: impl AsRef<str>) -> bool { matches!(s.as_ref().trim(), "1" | "true" | "yes") }
+
+/// Returns `s` if it is a date like `"2024-12-31"`, ignoring white-space.
+/// Such dates sort in date order.
+fn parse_day(s: &str) -> Option<&str> {
+    let s = s.trim();
+    let [year, month, day]: [&str; 3] = s.split('-').collect::<Vec<_>>().try_into().ok()?;
+    let digits = |part: &str, len| part.len() == len && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(year, 4) || !digits(month, 2) || !digits(day, 2) { return None; }
+    let (month, day): (u32, u32) = (month.parse().ok()?, day.parse().ok()?);
+    ((1..=12).contains(&month) && (1..=31).contains(&day)).then_some(s)
+}
+
 impl FromIterator<(String, String)> for Params {
     fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
         let mut ret = Self::default();
         for (key, value) in iter.into_iter() {
             if "w" == key { ret.w = parse_u32(value); }
             else if "h" == key { ret.h = parse_u32(value); }
+            else if "token" == key { ret.token = Some(value); }
+            else if "depth" == key { ret.depth = parse_u32(value); }
+            else if "v" == key { ret.v = Some(value); }
+            else if "flip" == key { ret.flip = Flip::parse(value); }
+            else if "mode" == key { ret.mode = Mode::parse(value); }
+            else if "fit" == key { ret.mode = Mode::parse_fit(value); }
+            else if "bg" == key { ret.bg = parse_colour(value); }
+            else if "fmt" == key { ret.fmt = Format::parse(value); }
+            else if "q" == key { ret.q = parse_u32(value); }
+            else if "download" == key {
+                ret.zip = value.trim() == "zip";
+                ret.download = parse_flag(value);
+            }
+            else if "dl" == key { ret.download = parse_flag(value); }
+            else if "original" == key { ret.original = parse_flag(value); }
+            else if "all" == key { ret.all = parse_flag(value); }
+            else if "format" == key { ret.json = value.trim() == "json"; }
+            else if "cache" == key { ret.cache = Some(value); }
+            else if "inline_previews" == key { ret.inline_previews = parse_u32(value); }
+            else if "page" == key { ret.page = parse_u32(value); }
+            else if "per_page" == key { ret.per_page = parse_u32(value).filter(|&n| n > 0); }
+            else if "delay" == key { ret.delay = parse_u32(value); }
+            else if "album" == key { ret.album = Some(value); }
+            else if "order" == key {
+                // `desc` and `asc` combine with the other orders.
+                match value.trim() {
+                    "desc" => { ret.reversed = true; },
+                    "asc" => { ret.reversed = false; },
+                    _ => { ret.order = Order::parse(value); },
+                }
+            }
+            else if "sort" == key { ret.sort = Order::parse(value); }
+            else if "min_rating" == key { ret.min_rating = parse_u32(value); }
+            else if "slideshow" == key { ret.slideshow = parse_u32(value).filter(|&seconds| seconds > 0); }
+            else if "since" == key { ret.since = Some(value); }
+            else if "until" == key { ret.until = Some(value); }
         }
         ret
     }
@@ -62,6 +584,103 @@ impl FromIterator<(String, String)> for Params {
 
 // ----------------------------------------------------------------------------
 
+/// The kinds of request, and their arguments. See `PhotoServer::dispatch()`.
+///
+/// Each `dir` is the path of an album relative to the document root, e.g.
+/// `"2023/summer"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Route {
+    Manifest,
+    Sitemap,
+    Favicon,
+    Robots,
+
+    /// The photo of the day from the album, or from all albums if `None`.
+    PhotoOfTheDay(Option<String>),
+
+    Index(String),
+
+    /// A ZIP archive of the files in the album.
+    Zip(String),
+
+    /// A JSON description of the album.
+    Listing(String),
+    CacheList {dir: String, leaf: String},
+    Rescale {dir: String, leaf: String},
+    Original {dir: String, leaf: String},
+
+    /// The frame around the photo `leaf`.
+    Frame {dir: String, leaf: String},
+
+    /// The thumbnail of the photo `leaf`.
+    Thumb {dir: String, leaf: String},
+
+    Slideshow(String),
+    Static {dir: String, leaf: String},
+}
+
+impl Route {
+    /// Interpret `path`, from which empty segments (e.g. those implied by
+    /// trailing slashes) have been removed. Rejects dubious segments.
+    ///
+    /// A path that names a directory in `document_root` is an album.
+    /// Otherwise, the last segment is a file in the album named by the
+    /// others. The filesystem is not examined further, so `Slideshow` and
+    /// `PhotoOfTheDay(Some(_))` might name a static file.
+    pub fn parse(path: &[String], params: &Params, document_root: &Path) -> Result<Self, HttpError> {
+        for name in path { safe_name(name).map_err(|_| HttpError::Invalid)?; }
+        let album = |dir: String| {
+            if params.zip { Self::Zip(dir) } else if params.json { Self::Listing(dir) } else { Self::Index(dir) }
+        };
+        Ok(match path {
+            [] => return Err(HttpError::Invalid),
+            [name] if name == "manifest.json" => Self::Manifest,
+            [name] if name == "sitemap.xml" => Self::Sitemap,
+            [name] if name == FAVICON_NAME => Self::Favicon,
+            [name] if name == ROBOTS_NAME => Self::Robots,
+            [name] if name == PHOTO_OF_THE_DAY_NAME => Self::PhotoOfTheDay(None),
+            [dir] => album(dir.clone()),
+            _ if document_root.join(path.join("/")).is_dir() => album(path.join("/")),
+            [dir @ .., leaf] => {
+                let (dir, leaf) = (dir.join("/"), leaf.clone());
+                if photo_base_name(&leaf).is_some() {
+                    if params.cache.as_deref() == Some("list") {
+                        Self::CacheList {dir, leaf}
+                    } else if params.w.is_some() || params.h.is_some() || params.flip.is_some()
+                        || params.mode.is_some() || params.fmt.is_some() || params.q.is_some()
+                    {
+                        Self::Rescale {dir, leaf}
+                    } else {
+                        Self::Original {dir, leaf}
+                    }
+                } else if let Some(jpeg_name) = remove_extension(&leaf, "html").filter(|&name| photo_base_name(name).is_some()) {
+                    Self::Frame {leaf: jpeg_name.into(), dir}
+                } else if let Some(jpeg_name) = remove_extension(&leaf, "thumb").filter(|&name| photo_base_name(name).is_some()) {
+                    Self::Thumb {leaf: jpeg_name.into(), dir}
+                } else if leaf == SLIDESHOW_NAME {
+                    Self::Slideshow(dir)
+                } else if leaf == PHOTO_OF_THE_DAY_NAME {
+                    Self::PhotoOfTheDay(Some(dir))
+                } else {
+                    Self::Static {dir, leaf}
+                }
+            },
+        })
+    }
+
+    /// Returns the album to which `self` applies, if any.
+    pub fn dir(&self) -> Option<&str> {
+        match self {
+            Self::Manifest | Self::Sitemap | Self::Favicon | Self::Robots | Self::PhotoOfTheDay(None) => None,
+            Self::PhotoOfTheDay(Some(dir)) | Self::Index(dir) | Self::Zip(dir) | Self::Listing(dir) | Self::Slideshow(dir) => Some(dir),
+            Self::CacheList {dir, ..} | Self::Rescale {dir, ..} | Self::Original {dir, ..} => Some(dir),
+            Self::Frame {dir, ..} | Self::Thumb {dir, ..} | Self::Static {dir, ..} => Some(dir),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
 /// Contents of an album directory.
 #[derive(Default, Debug, Clone)]
 struct Album {
@@ -71,15 +690,35 @@ struct Album {
 }
 
 impl Album {
-    fn new(dir_name: &Path) -> Result<Self, HttpError> {
+    /// List `dir_name`. The first of `readme_names` that exists, if any, is
+    /// its README. Entries with dubious names are skipped.
+    fn new(dir_name: &Path, readme_names: &[String]) -> Result<Self, HttpError> {
         let mut ret = Self::default();
+        let mut readmes = Vec::new();
         for dir_entry in dir_name.read_dir()? {
-            if let Some(filename) = dir_entry?.path().file_name() {
-                let filename = validate_name(filename)?;
-                if filename == "README.txt" {
-                    ret.readme = Some(filename.into());
+            let dir_entry = match dir_entry {
+                Ok(dir_entry) => dir_entry,
+                // Removed while we were listing the directory.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(filename) = dir_entry.path().file_name() {
+                let filename = match validate_name(filename) {
+                    Ok(filename) => filename,
+                    Err(e) => {
+                        // Listed by `/admin/issues`.
+                        println!("Warning: Skipping {}: {}", dir_name.display(), e);
+                        continue;
+                    },
+                };
+                if readme_names.iter().any(|name| name == filename) {
+                    readmes.push(filename.to_owned());
+                } else if filename == TOKEN_FILENAME || filename == AUTH_FILENAME || filename == ALBUM_CONFIG_FILENAME {
+                    // Never reveal the secrets or the settings.
+                } else if filename.starts_with(TEMPORARY_PREFIX) {
+                    // Being written, or left behind by a crash.
                 } else {
-                    if let Some(_) = remove_extension(filename, "jpg") {
+                    if photo_base_name(filename).is_some() {
                         ret.jpegs.push(filename.into());
                     } else {
                         ret.others.push(filename.into());
@@ -87,129 +726,1672 @@ impl Album {
                 }
             }
         }
+        ret.readme = readme_names.iter().find(|name| readmes.contains(name)).cloned();
+        // Other README candidates are just files.
+        ret.others.extend(readmes.into_iter().filter(|name| Some(name) != ret.readme.as_ref()));
         ret.jpegs.sort();
         ret.others.sort();
         Ok(ret)
     }
+}
 
-    /// Given one of the filenames in `self.jpegs`, returns the previous and
-    /// next such filename.
-    fn previous_next(&self, jpeg_name: &str) -> Option<(&str, &str)> {
-        if let Some(prev) = self.jpegs.last() {
-            let mut prev: &str = &prev;
-            let mut iter = self.jpegs.iter();
-            while let Some(p) = iter.next() {
-                if p == jpeg_name {
-                    let next: &str = iter.next().unwrap_or(self.jpegs.first().unwrap());
-                    return Some((prev, next));
-                }
-                prev = p;
-            }
-        }
-        None
-    }
+/// The photos of an album in some `Order`, cached by
+/// `PhotoServer::sorted_photos()`.
+#[derive(Debug)]
+struct CachedOrder {
+    /// The modification time of the album directory.
+    mtime: SystemTime,
+
+    /// The `Album::jpegs`, which are sorted by name.
+    jpegs: Vec<String>,
+
+    /// The same photos in order.
+    ordered: Vec<String>,
+}
+
+/// Given one of `jpegs`, returns the previous and next of them, wrapping
+/// around at the ends. If `jpegs` has only one element, both are
+/// `jpeg_name` itself.
+///
+/// Returns `None` if `jpeg_name` is not one of `jpegs`.
+fn previous_next<'a>(jpegs: &'a [String], jpeg_name: &str) -> Option<(&'a str, &'a str)> {
+    let i = jpegs.iter().position(|p| p == jpeg_name)?;
+    let previous = &jpegs[(i + jpegs.len() - 1) % jpegs.len()];
+    let next = &jpegs[(i + 1) % jpegs.len()];
+    Some((previous, next))
+}
+
+/// Returns HTML naming the album `dir_name` (e.g. `"2023/summer"`), in which
+/// each enclosing album links to its index.
+fn breadcrumbs(dir_name: &str) -> String {
+    let names: Vec<&str> = dir_name.split('/').collect();
+    let (last, enclosing) = names.split_last().unwrap(); // `split()` yields at least one.
+    let mut ret: Vec<String> = enclosing.iter().enumerate().map(|(i, name)| format!(
+        r#"<a href="{up}">{name}</a>"#,
+        up = "../".repeat(enclosing.len() - i),
+    )).collect();
+    ret.push(last.to_string());
+    ret.join(" / ")
 }
 
 // ----------------------------------------------------------------------------
 
-struct PhotoServer<'a> {
-    /// The directory containing the photos.
-    pub document_root: &'a Path,
+/// Returns the name under which `PhotoServer::export()` writes the photo
+/// `leaf_name` resized according to `params`.
+fn exported_resized_name(leaf_name: &str, params: &Params) -> String {
+    let d = params.get_dimensions();
+    format!("{}@{}x{}.jpg", leaf_name, d.w, d.h)
+}
 
-    /// The thumbnail cache directory.
-    pub thumbnail_root: &'a Path,
+/// Given a URL in a page generated by the server, returns the URL of the
+/// corresponding file written by `PhotoServer::export()`. Static files
+/// cannot depend on the query string, so it is removed.
+fn static_url(url: &str) -> String {
+    // Absolute URLs, e.g. `footer_url`, are unchanged.
+    if url.starts_with('/') || url.starts_with('#') || Url::parse(url).is_ok() { return url.to_owned(); }
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    match path {
+        "" | "." => "index.html".to_owned(),
+        ".." => "../index.html".to_owned(),
+        // E.g. a nested album, or an enclosing one.
+        _ if path.ends_with('/') => format!("{}index.html", path),
+        _ => if let Some(leaf_name) = remove_extension(path, "thumb") {
+            format!("{}.thumb.jpg", leaf_name)
+        } else if photo_base_name(path).is_some() && !query.is_empty() {
+            let params: Params = url::form_urlencoded::parse(query.as_bytes()).into_owned().collect();
+            exported_resized_name(path, &params)
+        } else {
+            path.to_owned()
+        },
+    }
 }
 
-impl<'a> PhotoServer<'a> {
-    fn new(document_root: &'a str, thumbnail_root: &'a str) -> Self {
-        Self {
-            document_root: Path::new(document_root),
-            thumbnail_root: Path::new(thumbnail_root),
+/// Replace every `href` and `src` attribute in `html` using `static_url()`.
+fn static_links(html: &str) -> String {
+    let mut ret = html.to_owned();
+    for attribute in ["href=\"", "src=\""] {
+        let mut out = String::new();
+        let mut rest = ret.as_str();
+        while let Some(start) = rest.find(attribute) {
+            let (before, after) = rest.split_at(start + attribute.len());
+            let Some(end) = after.find('"') else { break; };
+            out.push_str(before);
+            out.push_str(&html_escape_attribute(&static_url(&html_escape::decode_html_entities(&after[..end]))));
+            rest = &after[end..];
         }
+        out.push_str(rest);
+        ret = out;
     }
+    ret
+}
 
-    /// Load `jpeg_name`, resize it, and encode it as a new JPEG file.
-    fn resize_jpeg(jpeg_name: &Path, d: Dimensions) -> Result<Vec<u8>, HttpError> {
-        let image = image::open(jpeg_name).map_err(HttpError::new)?;
-        let image = image.resize(d.w, d.h, image::imageops::FilterType::Lanczos3);
-        let mut ret = Vec::<u8>::new();
-        let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut ret, 85);
-        encoder.encode_image(&image).map_err(HttpError::new)?;
-        Ok(ret)
+/// Write the page `okay`, which must be HTML, to `file_name`, with its links
+/// replaced using `static_links()`.
+fn write_static_page(file_name: &Path, mut okay: HttpOkay) -> Result<(), HttpError> {
+    while let HttpOkay::Header(inner, _, _) = okay { okay = *inner; }
+    let HttpOkay::Html(text) = okay else { return Err(HttpError::new(std::io::Error::other("not an HTML page"))); };
+    std::fs::write(file_name, static_links(&text))?;
+    Ok(())
+}
+
+// ----------------------------------------------------------------------------
+
+/// An album directory containing a file with this name can only be seen by
+/// clients that supply its contents as the `token` parameter or cookie.
+const TOKEN_FILENAME: &str = ".token";
+
+/// Returns the secret of the album directory `dir_name`, if it has one.
+fn album_token(dir_name: &Path) -> Result<Option<String>, HttpError> {
+    let mut secret = String::new();
+    match File::open(dir_name.join(TOKEN_FILENAME)) {
+        Ok(mut file) => { file.read_to_string(&mut secret)?; },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(None); },
+        Err(e) => { return Err(e.into()); },
     }
+    Ok(Some(secret.trim().into()))
+}
 
-    /// Show thumbnails for all photos in a directory.
-    pub fn index(&self, dir_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
-        let dimensions = params.get_dimensions();
-        let album = Album::new(&self.document_root.join(dir_name))?;
-        let readme = if let Some(name) = &album.readme {
-            let mut text = String::new();
-            File::open(self.document_root.join(dir_name).join(name))?.read_to_string(&mut text)?;
-            format!(
-                "<pre>{text}</pre>",
-                text = html_escape(&text),
-            )
-        } else {
-            String::new()
-        };
-        let jpegs: Vec<_> = album.jpegs.iter().map(|name| format!(
-            r#"<a href="{name}.html{dimensions}"><img src="{name}.thumb"/></a>"#,
-            name = name,
-        )).collect();
-        let others: Vec<_> = album.others.iter().map(|name| format!(
-            r#"<a href="{name}">{name}</a>"#,
-            name = name,
-        )).collect();
-        Ok(HttpOkay::Html(format!(
-r#"<html>
- <head>
-  <title>{dir_name}</title>
- </head>
- <body>
-  <h2>{dir_name}</h2>
-  <a href="..">Up</a><br/>
-  {readme}
-  {jpegs}
-  <br/>
-  {others}
- </body>
-</html>"#,
-            dir_name = dir_name,
-            readme = readme,
-            jpegs = jpegs.join("\n  "),
-            others = others.join("\n  "),
-        )))
+/// Returns `true` if one of `tokens` is `secret`.
+fn token_matches(tokens: &[&str], secret: &str) -> bool {
+    tokens.iter().any(|token| !token.is_empty() && constant_time_eq(token.as_bytes(), secret.as_bytes()))
+}
+
+/// Returns `true` if `dir_name` has no secret or if one of `tokens` is it.
+fn check_token(dir_name: &Path, tokens: &[&str]) -> Result<bool, HttpError> {
+    Ok(album_token(dir_name)?.is_none_or(|secret| token_matches(tokens, &secret)))
+}
+
+/// An album directory containing a file with this name can only be seen by
+/// clients that log in, using HTTP basic authentication, as one of the users
+/// it lists. Each line is `user:hash`, where `hash` is a bcrypt hash of the
+/// user's password, e.g. as written by `htpasswd -B`.
+const AUTH_FILENAME: &str = ".auth";
+
+/// Returns the users and password hashes of the album directory `dir_name`,
+/// if it has any. Blank lines and lines starting with `#` are ignored.
+fn album_users(dir_name: &Path) -> Result<Option<Vec<(String, String)>>, HttpError> {
+    let mut text = String::new();
+    match File::open(dir_name.join(AUTH_FILENAME)) {
+        Ok(mut file) => { file.read_to_string(&mut text)?; },
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => { return Ok(None); },
+        Err(e) => { return Err(e.into()); },
     }
+    Ok(Some(text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')).filter_map(
+        |line| line.split_once(':').map(|(user, hash)| (user.into(), hash.trim().into()))
+    ).collect()))
+}
 
-    /// Serve a resized JPEG file.
-    pub fn rescale(&self, dir_name: &str, leaf_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
-        let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
-        Ok(HttpOkay::Jpeg(Self::resize_jpeg(&jpeg_name, params.get_dimensions())?))
+/// Returns `true` if `dir_name` can be listed in pages that don't belong to
+/// it, such as the sitemap: it has no secret, or one of `tokens` is it, and
+/// it needs no password.
+fn is_public(dir_name: &Path, tokens: &[&str]) -> Result<bool, HttpError> {
+    Ok(check_token(dir_name, tokens)? && !dir_name.join(AUTH_FILENAME).exists())
+}
+
+// ----------------------------------------------------------------------------
+
+/// Like `validate_name()`, but also rejects `.` and `..`, which are
+/// directories. The returned name is safe to join to any path.
+fn safe_name(s: &str) -> Result<&str, HttpError> {
+    let s = validate_name(OsStr::new(s))?;
+    if s == "." || s == ".." { return Err(HttpError::Invalid); }
+    Ok(s)
+}
+
+/// What a cached file derived from a photo is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum CacheKind {
+    /// A thumbnail, with this multiple of the usual resolution.
+    Thumbnail(u32),
+
+    /// A photo resized by `rescale()` to these dimensions.
+    Resized(Dimensions),
+}
+
+/// If `name` is the name of a cached file derived from `leaf_name`, says
+/// which. Reverses `PhotoServer::thumbnail_name()` and
+/// `PhotoServer::resized_name()`. `@` cannot occur in `leaf_name`.
+fn cache_kind_of(name: &str, leaf_name: &str) -> Option<CacheKind> {
+    let suffix = name.strip_prefix(leaf_name)?;
+    if suffix.is_empty() { return Some(CacheKind::Thumbnail(1)); }
+    let suffix = suffix.strip_prefix('@')?;
+    if let Some(scale) = suffix.strip_suffix('x') { return scale.parse().ok().map(CacheKind::Thumbnail); }
+    // E.g. `300x200-h-crop-q85.jpeg`.
+    let (size, options) = suffix.split_once('-')?;
+    let (w, h) = size.split_once('x')?;
+    let (options, extension) = options.rsplit_once('.')?;
+    Format::parse(extension)?;
+    options.rsplit('-').next()?.strip_prefix('q')?.parse::<u32>().ok()?;
+    Some(CacheKind::Resized(Dimensions {w: w.parse().ok()?, h: h.parse().ok()?}))
+}
+
+/// Returns `true` if `url` is relative, or uses a scheme that cannot run
+/// code, e.g. not `javascript:`.
+fn is_safe_url(url: &str) -> bool {
+    let Some(colon) = url.find(':') else { return true; };
+    if url[..colon].contains(['/', '?', '#']) { return true; }
+    // Browsers ignore white-space and control characters in the scheme.
+    let scheme: String = url[..colon].chars().filter(|c| !c.is_ascii_whitespace() && !c.is_ascii_control()).collect();
+    ["http", "https", "mailto"].iter().any(|safe| scheme.eq_ignore_ascii_case(safe))
+}
+
+/// Render Markdown `text` as HTML. Any HTML in `text` is escaped, and links
+/// and images with other than `is_safe_url()`s go nowhere.
+fn markdown_to_html(text: &str) -> String {
+    use pulldown_cmark::{Event, Parser, Tag};
+    let events = Parser::new(text).map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link {link_type, dest_url, title, id}) if !is_safe_url(&dest_url) => {
+            Event::Start(Tag::Link {link_type, dest_url: "#".into(), title, id})
+        },
+        Event::Start(Tag::Image {link_type, dest_url, title, id}) if !is_safe_url(&dest_url) => {
+            Event::Start(Tag::Image {link_type, dest_url: "#".into(), title, id})
+        },
+        event => event,
+    });
+    let mut ret = String::new();
+    pulldown_cmark::html::push_html(&mut ret, events);
+    ret
+}
+
+/// If `file_name` is a photo, returns it without its extension.
+fn photo_base_name(file_name: &str) -> Option<&str> {
+    #[cfg(feature = "raw")]
+    if let Some(ret) = raw::RAW_EXTENSIONS.iter().find_map(|extension| remove_extension(file_name, extension)) {
+        return Some(ret);
     }
+    PHOTO_EXTENSIONS.iter().find_map(|extension| remove_extension(file_name, extension))
+}
 
-    /// Show an HTML frame around a single photo.
-    pub fn frame(&self, dir_name: &str, leaf_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
-        let dimensions = params.get_dimensions();
-        // Enumerate the JPEG files in `dir_name` and compute
-        // `previous` and `next` links.
-        let album = Album::new(&self.document_root.join(dir_name))?;
-        let (previous, next) = album.previous_next(leaf_name).ok_or(HttpError::NotFound)?;
-        // This substring contains a lot of `{` and `}` characters.
-        let stylesheet =
-r#"body {background-color: #000000; color: #FFFFFF}
-a:link {color: #8080FF}
-a:visited {color: #C080FF}
-input[type="text"] {
-background-color: #404040; color: #FFFFFF;
-border: thin solid #808080
-}"#;
+/// Load the photo `file_name`, turned the right way up according to its
+/// EXIF orientation, if any.
+fn open_photo(file_name: &Path) -> image::ImageResult<image::DynamicImage> {
+    #[cfg(feature = "raw")]
+    if raw::is_raw(file_name) { return raw::open(file_name); }
+    let mut decoder = image::ImageReader::open(file_name)?.into_decoder()?;
+    let orientation = decoder.orientation()?;
+    let mut ret = image::DynamicImage::from_decoder(decoder)?;
+    ret.apply_orientation(orientation);
+    Ok(ret)
+}
+
+/// Returns the width and height of the photo `file_name` once it is the
+/// right way up, reading as little of it as possible.
+fn photo_dimensions(file_name: &Path) -> image::ImageResult<(u32, u32)> {
+    #[cfg(feature = "raw")]
+    if raw::is_raw(file_name) { return raw::dimensions(file_name); }
+    let mut decoder = image::ImageReader::open(file_name)?.into_decoder()?;
+    let (w, h) = decoder.dimensions();
+    Ok(match decoder.orientation()? {
+        Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH => (h, w),
+        _ => (w, h),
+    })
+}
+
+/// The EXIF tag of a photo's star rating, usually from 0 to 5.
+const EXIF_RATING: u16 = 0x4746;
+
+/// Returns the `EXIF_RATING` in the EXIF metadata `exif`, if any.
+fn exif_rating(exif: &[u8]) -> Option<u32> {
+    let tiff = Tiff::new(exif.strip_prefix(b"Exif\0\0").unwrap_or(exif))?;
+    let entries = tiff.entries(tiff.first_ifd()?)?;
+    entries.into_iter().find(|&(tag, _)| tag == EXIF_RATING)?.1.first().copied()
+}
+
+/// Returns the EXIF star rating of the photo `file_name`, if it has one.
+fn photo_rating(file_name: &Path) -> image::ImageResult<Option<u32>> {
+    // RAW files are TIFF files, and their first IFD is like EXIF's.
+    #[cfg(feature = "raw")]
+    if raw::is_raw(file_name) { return Ok(exif_rating(&std::fs::read(file_name)?)); }
+    let mut decoder = image::ImageReader::open(file_name)?.into_decoder()?;
+    Ok(decoder.exif_metadata()?.and_then(|exif| exif_rating(&exif)))
+}
+
+/// EXIF tags read by `exif_details()`.
+const EXIF_MAKE: u16 = 0x010f;
+const EXIF_MODEL: u16 = 0x0110;
+const EXIF_IFD: u16 = 0x8769;
+const EXIF_EXPOSURE_TIME: u16 = 0x829a;
+const EXIF_F_NUMBER: u16 = 0x829d;
+const EXIF_ISO: u16 = 0x8827;
+const EXIF_DATE_TIME_ORIGINAL: u16 = 0x9003;
+const EXIF_FOCAL_LENGTH: u16 = 0x920a;
+const EXIF_LENS_MODEL: u16 = 0xa434;
+const EXIF_GPS_IFD: u16 = 0x8825;
+const GPS_LATITUDE_REF: u16 = 0x0001;
+const GPS_LATITUDE: u16 = 0x0002;
+const GPS_LONGITUDE_REF: u16 = 0x0003;
+const GPS_LONGITUDE: u16 = 0x0004;
+
+/// Format a fraction as a decimal with at most one decimal place.
+fn format_fraction((numerator, denominator): (u32, u32)) -> Option<String> {
+    if denominator == 0 { return None; }
+    let tenths = (f64::from(numerator) * 10.0 / f64::from(denominator)).round() as u64;
+    let (units, tenths) = (tenths / 10, tenths % 10);
+    Some(if tenths == 0 { format!("{}", units) } else { format!("{}.{}", units, tenths) })
+}
+
+/// Converts degrees, minutes and seconds to degrees. `reference` is `"N"`,
+/// `"S"`, `"E"` or `"W"`, and south and west are negative.
+fn dms_to_degrees(dms: &[(u32, u32)], reference: &str) -> Option<f64> {
+    let [degrees, minutes, seconds] = dms else { return None; };
+    let mut ret = 0.0;
+    for (&(numerator, denominator), unit) in [degrees, minutes, seconds].into_iter().zip([1.0, 60.0, 3600.0]) {
+        if denominator == 0 { return None; }
+        ret += f64::from(numerator) / f64::from(denominator) / unit;
+    }
+    match reference.trim() {
+        "N" | "E" => Some(ret),
+        "S" | "W" => Some(-ret),
+        _ => None,
+    }
+}
+
+/// Returns the latitude and longitude in the GPS IFD at `ifd` of `tiff`, in
+/// degrees north and east.
+fn gps_location(tiff: &Tiff, ifd: usize) -> Option<(f64, f64)> {
+    let latitude = dms_to_degrees(&tiff.rationals(ifd, GPS_LATITUDE)?, tiff.ascii(ifd, GPS_LATITUDE_REF)?)?;
+    let longitude = dms_to_degrees(&tiff.rationals(ifd, GPS_LONGITUDE)?, tiff.ascii(ifd, GPS_LONGITUDE_REF)?)?;
+    if latitude.abs() > 90.0 || longitude.abs() > 180.0 { return None; }
+    Some((latitude, longitude))
+}
+
+/// Information from the EXIF metadata of a photo, for `frame()`.
+#[derive(Debug, Default)]
+struct ExifDetails {
+    /// The names and values of the capture settings that are present.
+    settings: Vec<(&'static str, String)>,
+
+    /// Where the photo was taken, in degrees north and east, if known.
+    location: Option<(f64, f64)>,
+
+    /// When the photo was taken, e.g. `"2024-12-31 23:59:59"`, if known.
+    taken: Option<String>,
+}
+
+/// Returns the value of the IFD pointer `tag` in the IFD at `ifd` of `tiff`.
+fn sub_ifd(tiff: &Tiff, ifd: usize, tag: u16) -> Option<usize> {
+    let entries = tiff.entries(ifd)?;
+    Some(*entries.into_iter().find(|&(t, _)| t == tag)?.1.first()? as usize)
+}
+
+/// Returns the capture date in the EXIF IFD at `ifd` of `tiff`, e.g.
+/// `"2024-12-31 23:59:59"`.
+fn date_taken(tiff: &Tiff, ifd: usize) -> Option<String> {
+    // E.g. `"2024:12:31 23:59:59"`.
+    let (day, time) = tiff.ascii(ifd, EXIF_DATE_TIME_ORIGINAL)?.trim().split_once(' ')?;
+    Some(format!("{} {}", day.replace(':', "-"), time))
+}
+
+/// Returns the capture settings in IFD0 `ifd0` of `tiff` and in its EXIF
+/// IFD, as names and values. Those that are missing are omitted.
+fn exif_settings(tiff: &Tiff, ifd0: usize) -> Vec<(&'static str, String)> {
+    let mut ret = Vec::new();
+    if let Some(model) = tiff.ascii(ifd0, EXIF_MODEL).map(str::trim).filter(|s| !s.is_empty()) {
+        // The model often includes the make.
+        let camera = match tiff.ascii(ifd0, EXIF_MAKE).map(str::trim) {
+            Some(make) if !make.is_empty() && !model.starts_with(make) => format!("{} {}", make, model),
+            _ => model.into(),
+        };
+        ret.push(("Camera", camera));
+    }
+    let Some(ifd) = sub_ifd(tiff, ifd0, EXIF_IFD) else { return ret; };
+    if let Some(lens) = tiff.ascii(ifd, EXIF_LENS_MODEL).map(str::trim).filter(|s| !s.is_empty()) {
+        ret.push(("Lens", lens.into()));
+    }
+    if let Some(focal_length) = tiff.rational(ifd, EXIF_FOCAL_LENGTH).and_then(format_fraction) {
+        ret.push(("Focal length", format!("{} mm", focal_length)));
+    }
+    if let Some(f_number) = tiff.rational(ifd, EXIF_F_NUMBER).and_then(format_fraction) {
+        ret.push(("Aperture", format!("f/{}", f_number)));
+    }
+    match tiff.rational(ifd, EXIF_EXPOSURE_TIME) {
+        Some((n, d)) if n > 0 && n < d => {
+            ret.push(("Shutter", format!("1/{} s", (f64::from(d) / f64::from(n)).round())));
+        },
+        Some(time) => if let Some(time) = format_fraction(time) { ret.push(("Shutter", format!("{} s", time))); },
+        None => {},
+    }
+    let entries = tiff.entries(ifd).unwrap_or_default();
+    if let Some(&iso) = entries.iter().find(|&&(tag, _)| tag == EXIF_ISO).and_then(|(_, values)| values.first()) {
+        ret.push(("ISO", iso.to_string()));
+    }
+    if let Some(taken) = date_taken(tiff, ifd) { ret.push(("Taken", taken)); }
+    ret
+}
+
+/// Returns the `ExifDetails` in the EXIF metadata `exif`.
+fn exif_details(exif: &[u8]) -> ExifDetails {
+    let Some(tiff) = Tiff::new(exif.strip_prefix(b"Exif\0\0").unwrap_or(exif)) else { return ExifDetails::default(); };
+    let Some(ifd0) = tiff.first_ifd() else { return ExifDetails::default(); };
+    ExifDetails {
+        settings: exif_settings(&tiff, ifd0),
+        location: sub_ifd(&tiff, ifd0, EXIF_GPS_IFD).and_then(|ifd| gps_location(&tiff, ifd)),
+        taken: sub_ifd(&tiff, ifd0, EXIF_IFD).and_then(|ifd| date_taken(&tiff, ifd)),
+    }
+}
+
+/// Returns the `exif_details()` of the photo `file_name`.
+fn photo_details(file_name: &Path) -> image::ImageResult<ExifDetails> {
+    // RAW files are TIFF files, and their first IFD is like EXIF's.
+    #[cfg(feature = "raw")]
+    if raw::is_raw(file_name) { return Ok(exif_details(&std::fs::read(file_name)?)); }
+    let mut decoder = image::ImageReader::open(file_name)?.into_decoder()?;
+    Ok(decoder.exif_metadata()?.map_or_else(ExifDetails::default, |exif| exif_details(&exif)))
+}
+
+/// Returns the photo of the day on `date` (e.g. `"2024-12-31"`), from
+/// `photos`, which should be sorted.
+///
+/// The choice is the 64-bit FNV-1a hash of `date`, modulo the number of
+/// `photos`. Unlike `hash()`, it does not depend on the version of Rust, so
+/// it stays the same if the server is upgraded.
+fn photo_of_the_day<'a>(photos: &'a [String], date: &str) -> Option<&'a str> {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for b in date.bytes() { hash = (hash ^ u64::from(b)).wrapping_mul(0x100000001b3); }
+    let index = usize::try_from(hash.checked_rem(u64::try_from(photos.len()).ok()?)?).ok()?;
+    photos.get(index).map(String::as_str)
+}
+
+/// Guess the MIME type of `file_name` from its extension.
+fn content_type(file_name: &str) -> &'static str {
+    let extension = file_name.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        "cr2" => "image/x-canon-cr2",
+        "nef" => "image/x-nikon-nef",
+        "arw" => "image/x-sony-arw",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "svg" => "image/svg+xml",
+        "ico" => "image/x-icon",
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "txt" => "text/plain",
+        "md" => "text/markdown",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        _ => "application/octet-stream",
+    }
+}
+
+/// `is_utf8()` reads at most this many bytes.
+const UTF8_SNIFF_BYTES: u64 = 64 * 1024;
+
+/// Returns `true` if the start of `file`, up to `UTF8_SNIFF_BYTES`, is valid
+/// UTF-8, except perhaps for a character cut off by that limit.
+fn is_utf8(file: &File) -> std::io::Result<bool> {
+    let mut data = Vec::new();
+    file.take(UTF8_SNIFF_BYTES).read_to_end(&mut data)?;
+    let is_truncated = data.len() as u64 == UTF8_SNIFF_BYTES;
+    Ok(std::str::from_utf8(&data).map_or_else(|e| is_truncated && e.error_len().is_none(), |_| true))
+}
+
+/// Returns the metadata of `file_name`, or `None` if it does not exist, e.g.
+/// because it was removed after `Album::new()` listed it.
+fn metadata_if_exists(file_name: &Path) -> std::io::Result<Option<std::fs::Metadata>> {
+    match std::fs::metadata(file_name) {
+        Ok(metadata) => Ok(Some(metadata)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Returns a string that changes whenever `file_name` is modified.
+fn source_version(file_name: &Path) -> Result<String, HttpError> {
+    Ok(validators(file_name)?.0)
+}
+
+/// Returns an entity tag and a last-modified date for `file_name`.
+///
+/// The entity tag changes whenever the file is modified or replaced.
+fn validators(file_name: &Path) -> Result<(String, String), HttpError> {
+    let metadata = std::fs::metadata(file_name)?;
+    let mtime = metadata.modified()?;
+    #[cfg(unix)]
+    let inode = std::os::unix::fs::MetadataExt::ino(&metadata);
+    #[cfg(not(unix))]
+    let inode = 0;
+    Ok((format!("{:016x}", hash((mtime, metadata.len(), inode))), http_date(mtime)))
+}
+
+/// Returns `true` if `cached_name` exists and is no older than
+/// `original_name`, from which it was made.
+fn is_up_to_date(cached_name: &Path, original_name: &Path) -> std::io::Result<bool> {
+    let mtime = std::fs::metadata(original_name)?.modified()?;
+    Ok(std::fs::metadata(cached_name).and_then(|m| m.modified()).is_ok_and(|cached| cached >= mtime))
+}
+
+/// The names of the temporary files written by `write_atomically()` start
+/// with this. Albums never list or serve them, e.g. if one is left behind by
+/// a crash.
+const TEMPORARY_PREFIX: &str = ".tmp-";
+
+/// Write `data` to a temporary file and then rename it to `file_name`, so
+/// that neither a failure nor a concurrent reader ever sees a partial file.
+/// On error, the temporary file is deleted.
+///
+/// The temporary file is in the same directory, and its name is unique, so
+/// that concurrent writers of `file_name` do not interfere; the last rename
+/// wins.
+fn write_atomically(file_name: &Path, data: &[u8]) -> std::io::Result<()> {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let unique = format!("{}{}-{}-", TEMPORARY_PREFIX, std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+    let mut temporary_name = std::ffi::OsString::from(unique);
+    temporary_name.push(file_name.file_name().ok_or(std::io::ErrorKind::InvalidInput)?);
+    let temporary_name = file_name.with_file_name(temporary_name);
+    let result = File::create_new(&temporary_name)
+        .and_then(|mut file| file.write_all(data))
+        .and_then(|()| std::fs::rename(&temporary_name, file_name));
+    if result.is_err() { let _ = std::fs::remove_file(&temporary_name); }
+    result
+}
+
+// ----------------------------------------------------------------------------
+
+/// A photo in `/manifest.json`.
+#[derive(Debug, Serialize)]
+struct ManifestImage {
+    name: String,
+    url: String,
+    frame: String,
+    thumb: String,
+    width: Option<u32>,
+    height: Option<u32>,
+}
+
+/// An album in `/manifest.json`.
+#[derive(Debug, Serialize)]
+struct ManifestAlbum {
+    name: String,
+    url: String,
+    images: Vec<ManifestImage>,
+    albums: Vec<ManifestAlbum>,
+}
+
+/// The contents of `/manifest.json`.
+#[derive(Debug, Serialize)]
+struct Manifest {
+    url: String,
+    albums: Vec<ManifestAlbum>,
+
+    /// `true` if `MAX_MANIFEST_ENTRIES` was reached.
+    truncated: bool,
+}
+
+/// A photo in the response to `?format=json`.
+#[derive(Debug, Serialize)]
+struct ListingImage {
+    name: String,
+    width: Option<u32>,
+    height: Option<u32>,
+    bytes: u64,
+
+    /// Modification time in seconds since 1970.
+    mtime: u64,
+}
+
+/// The response to `?format=json`, describing an album.
+#[derive(Debug, Serialize)]
+struct Listing {
+    name: String,
+
+    /// The text of the README, if any, truncated like in the index.
+    readme: Option<String>,
+
+    images: Vec<ListingImage>,
+
+    /// Files other than photos, and nested albums, whose names end with
+    /// `"/"`.
+    others: Vec<String>,
+}
+
+/// A cached file in the response to `?cache=list`.
+#[derive(Debug, Serialize)]
+struct CachedVariant {
+    name: String,
+
+    /// `"thumbnail"` or `"resized"`.
+    kind: &'static str,
+
+    /// For thumbnails, the resolution as a multiple of the usual one.
+    scale: Option<u32>,
+
+    /// The requested size, for resized photos. The actual size is `width`
+    /// by `height`.
+    requested: Option<Dimensions>,
+
+    /// As found by sniffing the file, e.g. `"jpeg"`.
+    format: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    bytes: u64,
+
+    /// Modification time in seconds since 1970.
+    mtime: u64,
+}
+
+/// The maximum `depth` parameter of `/manifest.json`.
+const MAX_MANIFEST_DEPTH: u32 = 16;
+
+/// The maximum total number of albums and photos in `/manifest.json`.
+const MAX_MANIFEST_ENTRIES: usize = 10000;
+
+/// The maximum number of URLs in one page of `/sitemap.xml`, according to
+/// the sitemap protocol.
+const MAX_SITEMAP_URLS: usize = 50000;
+
+/// The maximum total number of URLs in all pages of `/sitemap.xml`.
+const MAX_SITEMAP_ENTRIES: usize = 10 * MAX_SITEMAP_URLS;
+
+/// A file that is skipped or broken, in the response to `/admin/issues`.
+#[derive(Debug, Serialize)]
+struct Issue {
+    /// Relative to the document root.
+    path: String,
+    reason: String,
+}
+
+/// The contents of `/admin/issues`.
+#[derive(Debug, Serialize)]
+struct Issues {
+    issues: Vec<Issue>,
+
+    /// `true` if `MAX_ISSUES` was reached.
+    truncated: bool,
+}
+
+/// The maximum number of entries in `/admin/issues`.
+const MAX_ISSUES: usize = 1000;
+
+// ----------------------------------------------------------------------------
+
+/// The file in the thumbnail cache directory where `precache()` records when
+/// it last started.
+const PRECACHE_STATE: &str = ".precache";
+
+/// The cached files to regenerate, requested by `POST /admin/rebuild-cache`.
+#[derive(Debug, Clone, PartialEq)]
+enum Rebuild {
+    All,
+    Album(String),
+}
+
+/// The stylesheet of single-photo pages and error pages.
+///
+/// This string contains a lot of `{` and `}` characters.
+const STYLESHEET: &str =
+r#"body {background-color: #000000; color: #FFFFFF}
+a:link {color: #8080FF}
+a:visited {color: #C080FF}
+input[type="text"] {
+background-color: #404040; color: #FFFFFF;
+border: thin solid #808080
+}"#;
+
+// ----------------------------------------------------------------------------
+
+struct PhotoServer<'a> {
+    /// The directory containing the photos.
+    pub document_root: &'a Path,
+
+    /// The thumbnail cache directory.
+    pub thumbnail_root: &'a Path,
+
+    /// Photos modified more recently than this are badged as "new" in the
+    /// index. `None` disables the badge.
+    pub new_window: Option<Duration>,
+
+    /// Whether to make resized image URLs content-addressed.
+    ///
+    /// If `true`, `frame()` adds `&v=<version>` to the URL of the resized
+    /// photo, where `<version>` changes whenever the original file changes.
+    /// The `v` parameter does not affect the image, but `rescale()` treats
+    /// a URL with the current `v` as immutable, so that browsers and CDNs can
+    /// cache it for ever. When the original changes, the URL changes too.
+    /// All `rescale()` responses also get an `ETag` computed from their
+    /// contents.
+    pub versioned_urls: bool,
+
+    /// Whether to use the `Sec-CH-DPR` client hint to choose the resolution
+    /// of thumbnails.
+    ///
+    /// If `true`, `index()` sends `Accept-CH: Sec-CH-DPR`, asking the browser
+    /// to send its device pixel ratio with subsequent requests. `thumb()`
+    /// then serves thumbnails with 1, 2 or 3 times as many pixels, which the
+    /// index displays at the usual size. Responses that depend on the hint
+    /// carry `Vary: Sec-CH-DPR` so that caches keep the variants apart.
+    /// Without the hint, thumbnails are served at the usual resolution.
+    pub client_hints: bool,
+
+    /// Whether to serve smaller photos in place of originals to small
+    /// screens.
+    ///
+    /// If `true`, `index()` asks for the `Sec-CH-Viewport-Width` and
+    /// `Sec-CH-DPR` client hints. `original()` then serves a photo resized to
+    /// the width of the screen in device pixels, if that is smaller than the
+    /// photo. All responses from `original()` carry a `Vary` header naming
+    /// both hints, so a cache stores one copy per screen size, and serves the
+    /// original only to clients that asked for it.
+    pub cap_originals: bool,
+
+    /// Whether `frame()` sends a `Link: <...>; rel=preload` header naming
+    /// its photo, so that the browser starts fetching it before it has read
+    /// the page.
+    pub preload_images: bool,
+
+    /// Whether to serve resized photos as AVIF to clients whose `Accept`
+    /// header names `image/avif`, unless the request has `fmt`.
+    ///
+    /// Only has an effect with the `avif` feature. The responses carry
+    /// `Vary: Accept`. WebP is not chosen this way, since our WebP encoder is
+    /// lossless, and so makes bigger files than JPEG.
+    pub negotiate_format: bool,
+
+    /// `negotiate_format` only chooses AVIF for resized photos whose
+    /// requested width or height is larger than this. Smaller ones stay
+    /// JPEG, since AVIF is slow to encode and saves little for them.
+    pub avif_min_dimension: u32,
+
+    /// A line of text, e.g. a copyright notice, shown at the bottom of every
+    /// page. `None` omits the footer.
+    pub footer_text: Option<String>,
+
+    /// If set, `footer_text` links to this URL.
+    pub footer_url: Option<String>,
+
+    /// The contents of `/robots.txt`, unless the document root has one.
+    pub robots_txt: String,
+
+    /// Included in the names of all cached files, so that changing it
+    /// invalidates the whole cache. Bump it after changing any setting that
+    /// affects the appearance of cached images. Files are then regenerated
+    /// when next requested, or by the next `precache()`. Old files are not
+    /// deleted.
+    pub cache_version: u32,
+
+    /// The JPEG quality of resized photos, from 1 to 100.
+    pub jpeg_quality: u8,
+
+    /// The JPEG quality of thumbnails and previews, from 1 to 100. It is
+    /// included in the names of cached files, like `cache_version`.
+    pub thumbnail_quality: u8,
+
+    /// The size of a thumbnail at the usual resolution. Photos are cropped
+    /// to fill it. It is included in the names of cached files.
+    pub thumbnail_dimensions: Dimensions,
+
+    /// The maximum width and height of a resized photo.
+    pub max_dimension: u32,
+
+    /// The size of photos when neither the request nor the album gives one.
+    pub default_dimensions: Dimensions,
+
+    /// The secret that grants access to administrative requests, which must
+    /// send it as `Authorization: Bearer <secret>`. `None` disables them.
+    pub admin_token: Option<String>,
+
+    /// The filenames that can be an album's README, in order of preference.
+    /// A README is shown in the index, rendered as Markdown if it ends with
+    /// `.md`, and otherwise as plain text.
+    pub readme_names: Vec<String>,
+
+    /// The number of bytes of a README that `index()` shows. Any more are
+    /// omitted, with a note saying so.
+    pub max_readme_bytes: u64,
+
+    /// The maximum number of files other than photos that `index()` lists,
+    /// unless asked for all of them.
+    pub max_others: usize,
+
+    /// The maximum size of an archive that `zip()` serves.
+    pub max_zip_bytes: u64,
+
+    /// Whether `static_file()` refuses requests whose `Referer` is not on
+    /// the same origin as `base_url`, to discourage hotlinking.
+    pub referer_check: bool,
+
+    /// Whether `referer_check` allows requests without a `Referer`, which
+    /// some browsers and privacy tools omit.
+    pub allow_empty_referer: bool,
+
+    /// Whether `min_rating` shows photos that have no EXIF rating.
+    pub include_unrated: bool,
+
+    /// The publicly visible URL of the server, for constructing absolute URLs.
+    pub base_url: Url,
+
+    /// The width and height of each photo whose size we have read, and its
+    /// modification time when we read it.
+    pub sizes: Mutex<HashMap<PathBuf, (SystemTime, u32, u32)>>,
+
+    /// The EXIF rating of each photo whose rating we have read, and its
+    /// modification time when we read it.
+    pub ratings: Mutex<HashMap<PathBuf, (SystemTime, Option<u32>)>>,
+
+    /// The slideshow order of each album for which we have computed one
+    /// other than `Order::Name`.
+    pub orders: Mutex<HashMap<(String, Order), CachedOrder>>,
+
+    /// A lock for each cached file that is being generated, so that
+    /// concurrent requests for it wait for one copy instead of each making
+    /// their own. See `with_generation_lock()`.
+    pub generating: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+
+    /// A pending request to rebuild the cache, for `maintain()`.
+    pub rebuild: Mutex<Option<Rebuild>>,
+
+    /// Notified when `rebuild` is set.
+    pub rebuild_requested: Condvar,
+}
+
+impl<'a> PhotoServer<'a> {
+    fn new(document_root: &'a str, thumbnail_root: &'a str, base_url: Url) -> Self {
+        Self {
+            document_root: Path::new(document_root),
+            thumbnail_root: Path::new(thumbnail_root),
+            new_window: None,
+            versioned_urls: false,
+            client_hints: false,
+            cap_originals: false,
+            preload_images: false,
+            negotiate_format: false,
+            avif_min_dimension: AVIF_MIN_DIMENSION,
+            footer_text: None,
+            robots_txt: ROBOTS_TXT.into(),
+            footer_url: None,
+            cache_version: 0,
+            jpeg_quality: 85,
+            thumbnail_quality: 75,
+            thumbnail_dimensions: THUMBNAIL_DIMENSIONS,
+            max_dimension: MAX_DIMENSION,
+            default_dimensions: DEFAULT_DIMENSIONS,
+            admin_token: None,
+            readme_names: README_NAMES.iter().map(|&name| name.into()).collect(),
+            max_readme_bytes: 256 * 1024,
+            max_others: 200,
+            max_zip_bytes: 1 << 30,
+            referer_check: false,
+            allow_empty_referer: true,
+            include_unrated: false,
+            base_url,
+            sizes: Mutex::new(HashMap::new()),
+            ratings: Mutex::new(HashMap::new()),
+            orders: Mutex::new(HashMap::new()),
+            generating: Mutex::new(HashMap::new()),
+            rebuild: Mutex::new(None),
+            rebuild_requested: Condvar::new(),
+        }
+    }
+
+    /// Checks the `Referer` of a request, if `self.referer_check` is set.
+    fn check_referer(&self, headers: Headers) -> Result<(), HttpError> {
+        if !self.referer_check { return Ok(()); }
+        let Some(referer) = headers.get("Referer") else {
+            return if self.allow_empty_referer { Ok(()) } else { Err(HttpError::Forbidden) };
+        };
+        match Url::parse(referer) {
+            Ok(url) if url.origin() == self.base_url.origin() => Ok(()),
+            _ => {
+                println!("Forbidden: Referer {:?}", referer);
+                Err(HttpError::Forbidden)
+            },
+        }
+    }
+
+    /// List the album `dir_name`.
+    fn album(&self, dir_name: &str) -> Result<Album, HttpError> {
+        Album::new(&self.document_root.join(dir_name), &self.readme_names)
+    }
+
+    /// Pretend that token-protected albums, and albums inside them, don't
+    /// exist, unless the client knows the secret. Checks the album
+    /// `dir_name` and every album enclosing it against `tokens`.
+    ///
+    /// Returns the secret to remember in a cookie, if `params.token` is one.
+    fn check_album_tokens(&self, dir_name: &str, params: &Params, tokens: &[&str]) -> Result<Option<String>, HttpError> {
+        let mut ret = None;
+        let mut path = self.document_root.to_owned();
+        for name in dir_name.split('/') {
+            path.push(name);
+            if let Some(secret) = album_token(&path)? {
+                if !token_matches(tokens, &secret) { return Err(HttpError::NotFound); }
+                if params.token.as_ref() == Some(&secret) { ret = Some(secret); }
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Checks the album `dir_name` and every album enclosing it for an
+    /// `AUTH_FILENAME`, and if any has one, checks that the request logs in
+    /// as one of its users.
+    fn check_album_users(&self, dir_name: &str, headers: Headers) -> Result<(), HttpError> {
+        let mut path = self.document_root.to_owned();
+        for name in dir_name.split('/') {
+            path.push(name);
+            let Some(users) = album_users(&path)? else { continue; };
+            let Some((user, password)) = headers.basic_auth() else { return Err(HttpError::Unauthorized); };
+            let Some((_, hash)) = users.into_iter().find(|(u, _)| *u == user) else { return Err(HttpError::Unauthorized); };
+            // A malformed hash matches nothing.
+            if !bcrypt::verify(&password, &hash).unwrap_or(false) {
+                println!("Unauthorized: User {:?}", user);
+                return Err(HttpError::Unauthorized);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the HTML for the footer of each page.
+    fn footer(&self) -> String {
+        let Some(text) = &self.footer_text else { return String::new(); };
+        let text = html_escape(text);
+        if let Some(url) = &self.footer_url {
+            format!(r#"<p class="footer"><a href="{}">{}</a></p>"#, html_escape_attribute(url), text)
+        } else {
+            format!(r#"<p class="footer">{}</p>"#, text)
+        }
+    }
+
+    /// Checks that the request is authorised by `self.admin_token`.
+    ///
+    /// Administrative requests pretend not to exist if not authorised.
+    fn check_admin(&self, headers: Headers) -> Result<(), HttpError> {
+        let Some(secret) = &self.admin_token else { return Err(HttpError::NotFound); };
+        let token = headers.get("Authorization").and_then(|value| value.strip_prefix("Bearer "));
+        if token.is_some_and(|token| constant_time_eq(token.trim().as_bytes(), secret.as_bytes())) {
+            Ok(())
+        } else {
+            Err(HttpError::NotFound)
+        }
+    }
+
+    /// Returns the width and height of `jpeg_name`, reading only its header.
+    fn image_size(&self, jpeg_name: &Path) -> Result<(u32, u32), HttpError> {
+        let mtime = std::fs::metadata(jpeg_name)?.modified()?;
+        if let Some(&(m, w, h)) = self.sizes.lock().unwrap().get(jpeg_name) && m == mtime { return Ok((w, h)); }
+        let (w, h) = photo_dimensions(jpeg_name).map_err(HttpError::new)?;
+        self.sizes.lock().unwrap().insert(jpeg_name.to_owned(), (mtime, w, h));
+        Ok((w, h))
+    }
+
+    /// Returns the EXIF rating of `jpeg_name`, if it has one.
+    fn rating(&self, jpeg_name: &Path) -> Result<Option<u32>, HttpError> {
+        let mtime = std::fs::metadata(jpeg_name)?.modified()?;
+        if let Some(&(m, rating)) = self.ratings.lock().unwrap().get(jpeg_name) && m == mtime { return Ok(rating); }
+        let rating = photo_rating(jpeg_name).map_err(HttpError::new)?;
+        self.ratings.lock().unwrap().insert(jpeg_name.to_owned(), (mtime, rating));
+        Ok(rating)
+    }
+
+    /// Returns those of `jpegs`, in `dir_name`, that have an EXIF rating of
+    /// at least `min_rating`, or have none if `self.include_unrated` is set.
+    fn filter_rating(&self, dir_name: &str, jpegs: &[String], min_rating: u32) -> Vec<String> {
+        jpegs.iter().filter(|name| match self.rating(&self.document_root.join(dir_name).join(name)) {
+            Ok(Some(rating)) => rating >= min_rating,
+            Ok(None) => self.include_unrated,
+            Err(e) => {
+                println!("Warning: Cannot read the rating of {}/{}: {}", dir_name, name, e);
+                self.include_unrated
+            },
+        }).cloned().collect()
+    }
+
+    /// Read at most `self.max_readme_bytes` of `file_name`. Returns the text,
+    /// with any invalid UTF-8 replaced, and `true` if it was truncated.
+    fn read_readme(&self, file_name: &Path) -> std::io::Result<(String, bool)> {
+        let mut data = Vec::new();
+        File::open(file_name)?.take(self.max_readme_bytes + 1).read_to_end(&mut data)?;
+        let truncated = data.len() as u64 > self.max_readme_bytes;
+        data.truncate(self.max_readme_bytes as usize);
+        Ok((String::from_utf8_lossy(&data).into_owned(), truncated))
+    }
+
+    /// Returns the photos of `album`, which is `dir_name`, that its index
+    /// shows, in order, on all pages together.
+    fn index_photos(&self, dir_name: &str, album: &Album, params: &Params) -> Result<Vec<String>, HttpError> {
+        let mut ret = self.photo_order(dir_name, album, params.sort.unwrap_or(Order::Name), params.reversed)?;
+        if let Some(min_rating) = params.min_rating { ret = self.filter_rating(dir_name, &ret, min_rating); }
+        Ok(ret)
+    }
+
+    /// Returns the photos of `album`, which is `dir_name`, in the order in
+    /// which an index or a slideshow shows them: `order`, reversed if
+    /// `reversed`. The index and frames both use this, so that they agree.
+    fn photo_order(&self, dir_name: &str, album: &Album, order: Order, reversed: bool) -> Result<Vec<String>, HttpError> {
+        let mut ret = self.sorted_photos(dir_name, album, order)?;
+        if reversed { ret.reverse(); }
+        Ok(ret)
+    }
+
+    /// Returns the photos of `album`, which is `dir_name`, in `order`.
+    fn sorted_photos(&self, dir_name: &str, album: &Album, order: Order) -> Result<Vec<String>, HttpError> {
+        if order == Order::Name { return Ok(album.jpegs.clone()); }
+        let dir_path = self.document_root.join(dir_name);
+        let mtime = std::fs::metadata(&dir_path)?.modified()?;
+        let key = (dir_name.to_owned(), order);
+        if let Some(cached) = self.orders.lock().unwrap().get(&key)
+            && cached.mtime == mtime && cached.jpegs == album.jpegs
+        {
+            return Ok(cached.ordered.clone());
+        }
+        let mut ret = album.jpegs.clone();
+        match order {
+            Order::Name => {},
+            Order::Date => {
+                let mut dated = Vec::new();
+                for name in ret {
+                    let Some(metadata) = metadata_if_exists(&dir_path.join(&name))? else { continue; };
+                    dated.push((metadata.modified()?, name));
+                }
+                dated.sort();
+                ret = dated.into_iter().map(|(_, name)| name).collect();
+            },
+            Order::Size => {
+                let mut sized = Vec::new();
+                for name in ret {
+                    let Some(metadata) = metadata_if_exists(&dir_path.join(&name))? else { continue; };
+                    sized.push((metadata.len(), name));
+                }
+                sized.sort();
+                ret = sized.into_iter().map(|(_, name)| name).collect();
+            },
+            Order::Random => { ret.sort_by_cached_key(|name| hash((dir_name, name))); },
+        }
+        self.orders.lock().unwrap().insert(key, CachedOrder {mtime, jpegs: album.jpegs.clone(), ordered: ret.clone()});
+        Ok(ret)
+    }
+
+    /// Returns `true` if `file_name` was modified within `self.new_window`.
+    /// Errors reading the modification time are treated as "not new".
+    fn is_new(&self, file_name: &Path, now: SystemTime) -> bool {
+        let Some(window) = self.new_window else { return false; };
+        let Ok(mtime) = std::fs::metadata(file_name).and_then(|m| m.modified()) else { return false; };
+        // A modification time in the future counts as new.
+        now.duration_since(mtime).map_or(true, |age| age < window)
+    }
+
+    /// Load `jpeg_name`, resize it to `d` according to `mode`, and
+    /// optionally `flip` it. `background` is the colour for `Mode::Pad`.
+    fn resize_image(
+        jpeg_name: &Path,
+        d: Dimensions,
+        flip: Option<Flip>,
+        mode: Mode,
+        background: [u8; 3],
+    ) -> Result<image::DynamicImage, HttpError> {
+        let image = open_photo(jpeg_name).map_err(|e| match e {
+            image::ImageError::IoError(e) => e.into(),
+            image::ImageError::Decoding(_) | image::ImageError::Unsupported(_) => {
+                println!("Warning: Cannot decode {}: {}", jpeg_name.display(), e);
+                HttpError::UnsupportedMedia
+            },
+            e => HttpError::new(e),
+        })?;
+        let filter = image::imageops::FilterType::Lanczos3;
+        let mut image = match mode {
+            Mode::Fit | Mode::Pad => image.resize(d.w, d.h, filter),
+            Mode::Crop => image.resize_to_fill(d.w, d.h, filter),
+        };
+        if let Some(flip) = flip { image = flip.apply(image); }
+        if mode == Mode::Pad {
+            let mut canvas = image::RgbImage::from_pixel(d.w, d.h, image::Rgb(background));
+            let x = d.w.saturating_sub(image.width()) / 2;
+            let y = d.h.saturating_sub(image.height()) / 2;
+            image::imageops::overlay(&mut canvas, &image.to_rgb8(), x.into(), y.into());
+            image = canvas.into();
+        }
+        Ok(image)
+    }
+
+    /// Like `resize_image()`, but returns a JPEG file with `quality`.
+    fn resize_jpeg(
+        jpeg_name: &Path,
+        d: Dimensions,
+        flip: Option<Flip>,
+        mode: Mode,
+        background: [u8; 3],
+        quality: u8,
+    ) -> Result<Vec<u8>, HttpError> {
+        let image = Self::resize_image(jpeg_name, d, flip, mode, background)?;
+        let mut ret = Vec::<u8>::new();
+        let mut encoder = image::codecs::jpeg::JpegEncoder::new_with_quality(&mut ret, quality);
+        encoder.encode_image(&image).map_err(HttpError::new)?;
+        Ok(ret)
+    }
+
+    /// Show thumbnails for all photos in a directory.
+    ///
+    /// Subdirectories are omitted unless `is_visible` returns `true` for
+    /// them, e.g. because they are not protected by a secret that the client
+    /// lacks.
+    pub fn index(
+        &self,
+        dir_name: &str,
+        params: &Params,
+        is_visible: impl Fn(&Path) -> Result<bool, HttpError>,
+    ) -> Result<HttpOkay, HttpError> {
+        let mut query = html_escape_attribute(&params.query()).into_owned();
+        let mut album = self.album(dir_name)?;
+        let mut visible = Vec::new();
+        for name in album.others {
+            let path = self.document_root.join(dir_name).join(&name);
+            if !path.is_dir() || is_visible(&path)? { visible.push(name); }
+        }
+        album.others = visible;
+        album.jpegs = self.index_photos(dir_name, &album, params)?;
+        let mut pages = String::new();
+        if let Some(per_page) = params.per_page.or(params.page.map(|_| DEFAULT_PER_PAGE)) {
+            let count = album.jpegs.len().div_ceil(per_page as usize).max(1);
+            let page = params.page.unwrap_or(1) as usize;
+            if !(1..=count).contains(&page) { return Err(HttpError::NotFound); }
+            album.jpegs = album.jpegs.chunks(per_page as usize).nth(page - 1).unwrap_or_default().to_vec();
+            // So that frames link back to the right page.
+            query = html_escape_attribute(&params.query_with("per_page", Some(&per_page.to_string()))).into_owned();
+            let link = |page: usize, text: &str| format!(
+                r#"<a href=".{query}">{text}</a>"#,
+                query = html_escape_attribute(&params.query_with("page", Some(&page.to_string()))),
+            );
+            pages = format!(
+                "<p>{previous} page {page} of {count} {next}</p>",
+                previous = if page > 1 { link(page - 1, "previous page") } else { String::new() },
+                next = if page < count { link(page + 1, "next page") } else { String::new() },
+            );
+        }
+        let readme = if let Some(name) = &album.readme {
+            match self.read_readme(&self.document_root.join(dir_name).join(name)) {
+                Ok((text, truncated)) => {
+                    let mut html = if remove_extension(name, "md").is_some() {
+                        markdown_to_html(&text)
+                    } else {
+                        format!("<pre>{text}</pre>", text = html_escape(&text))
+                    };
+                    if truncated {
+                        html.push_str(&format!("<p>(The rest of {} is too long to show.)</p>", name));
+                    }
+                    html
+                },
+                // Removed since `Album::new()`.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+                Err(e) => {
+                    // Show the album anyway.
+                    println!("Warning: Cannot read {}/{}: {}", dir_name, name, e);
+                    String::new()
+                },
+            }
+        } else {
+            String::new()
+        };
+        let now = SystemTime::now();
+        let inline_previews = MAX_INLINE_PREVIEWS.min(params.inline_previews.unwrap_or(0)) as usize;
+        let mut jpegs = Vec::new();
+        for (i, name) in album.jpegs.iter().enumerate() {
+            let jpeg_name = self.document_root.join(dir_name).join(name);
+            // Tell the browser the size of the thumbnail, so that the layout
+            // does not change as thumbnails load.
+            let mut attributes = format!(
+                r#" width="{}" height="{}""#, self.thumbnail_dimensions.w, self.thumbnail_dimensions.h,
+            );
+            if inline_previews > 0 { attributes += r#" loading="lazy""#; }
+            if i < inline_previews {
+                match self.preview(dir_name, name) {
+                    Ok(data) => {
+                        attributes += &format!(
+                            r#" style="background-image: url(data:image/jpeg;base64,{}); background-size: cover""#,
+                            BASE64_STANDARD.encode(data),
+                        );
+                    },
+                    Err(e) => { println!("Warning: No preview for {}/{}: {}", dir_name, name, e); },
+                }
+            }
+            jpegs.push(format!(
+                r#"<a class="thumb" href="{name}.html{query}"><img src="{name}.thumb"{attributes}/>{badge}</a>"#,
+                name = name,
+                badge = if self.is_new(&jpeg_name, now) { r#"<span class="new">NEW</span>"# } else { "" },
+            ));
+        }
+        let shown = if params.all { album.others.len() } else { album.others.len().min(self.max_others) };
+        let mut others: Vec<_> = album.others[..shown].iter().map(|name| format!(
+            r#"<a href="{name}{slash}">{name}</a>"#,
+            name = name,
+            // So that relative links in the nested album's index work.
+            slash = if self.document_root.join(dir_name).join(name).is_dir() { "/" } else { "" },
+        )).collect();
+        if shown < album.others.len() {
+            others.push(format!(
+                r#"<a href=".{query}">show all {count} files</a>"#,
+                query = html_escape_attribute(&params.query_with("all", Some("1"))),
+                count = album.others.len(),
+            ));
+        }
+        let ret = HttpOkay::Html(format!(
+r#"<html>
+ <head>
+  <title>{dir_name}</title>
+  <style type="text/css">
+div.grid {{display: flex; flex-wrap: wrap; align-items: center; gap: 4px}}
+a.thumb {{position: relative; display: inline-block}}
+a.thumb img {{max-width: {w}px; max-height: {h}px}}
+span.new {{position: absolute; top: 2px; left: 2px; padding: 0px 3px;
+background-color: #C00000; color: #FFFFFF; font-size: x-small; font-weight: bold}}
+  </style>
+ </head>
+ <body>
+  <h2>{breadcrumbs}{rating}</h2>
+  <a href="..">Up</a><br/>
+  {readme}
+  <div class="grid">
+  {jpegs}
+  </div>
+  {pages}
+  {others}
+  {footer}
+ </body>
+</html>"#,
+            dir_name = dir_name,
+            breadcrumbs = breadcrumbs(dir_name),
+            rating = params.min_rating.map_or(String::new(), |min_rating| format!(" (rated {} or more)", min_rating)),
+            w = self.thumbnail_dimensions.w,
+            h = self.thumbnail_dimensions.h,
+            readme = readme,
+            jpegs = jpegs.join("\n  "),
+            pages = pages,
+            others = others.join("\n  "),
+            footer = self.footer(),
+        ));
+        let mut hints = Vec::new();
+        if self.client_hints || self.cap_originals { hints.push("Sec-CH-DPR"); }
+        if self.cap_originals { hints.push("Sec-CH-Viewport-Width"); }
+        Ok(if hints.is_empty() { ret } else { ret.with_header("Accept-CH", hints.join(", ")) })
+    }
+
+    /// Serve a JSON description of the album `dir_name`, listing the same
+    /// photos and files as `index()`, but all of them.
+    pub fn listing(&self, dir_name: &str, params: &Params, tokens: &[&str]) -> Result<HttpOkay, HttpError> {
+        let album = self.album(dir_name)?;
+        let readme = album.readme.as_ref().and_then(|name| {
+            match self.read_readme(&self.document_root.join(dir_name).join(name)) {
+                Ok((text, _)) => Some(text),
+                // Removed since `Album::new()`.
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    println!("Warning: Cannot read {}/{}: {}", dir_name, name, e);
+                    None
+                },
+            }
+        });
+        let mut images = Vec::new();
+        for name in self.index_photos(dir_name, &album, params)? {
+            let jpeg_name = self.document_root.join(dir_name).join(&name);
+            let Some(metadata) = metadata_if_exists(&jpeg_name)? else { continue; };
+            let size = self.image_size(&jpeg_name).ok();
+            images.push(ListingImage {
+                name,
+                width: size.map(|(w, _)| w),
+                height: size.map(|(_, h)| h),
+                bytes: metadata.len(),
+                mtime: metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+            });
+        }
+        let mut others = Vec::new();
+        for name in album.others {
+            let path = self.document_root.join(dir_name).join(&name);
+            if !path.is_dir() {
+                others.push(name);
+            } else if check_token(&path, tokens)? {
+                others.push(format!("{}/", name));
+            }
+        }
+        let listing = Listing {name: dir_name.into(), readme, images, others};
+        Ok(HttpOkay::Json(serde_json::to_string(&listing).map_err(HttpError::new)?))
+    }
+
+    /// Describe the album `dir_name` (e.g. `"album/sub"`), and its
+    /// subdirectories down to `depth` further levels.
+    ///
+    /// Stops adding entries when `budget` reaches zero, and sets `truncated`.
+    fn manifest_album(
+        &self,
+        dir_name: &str,
+        depth: u32,
+        tokens: &[&str],
+        budget: &mut usize,
+        truncated: &mut bool,
+    ) -> Result<ManifestAlbum, HttpError> {
+        let url = self.base_url.join(&format!("{}/", dir_name))?;
+        let album = self.album(dir_name)?;
+        let mut images = Vec::new();
+        for name in &album.jpegs {
+            if *budget == 0 { *truncated = true; break; }
+            *budget -= 1;
+            let size = self.image_size(&self.document_root.join(dir_name).join(name)).ok();
+            images.push(ManifestImage {
+                name: name.clone(),
+                url: url.join(name)?.into(),
+                frame: url.join(&format!("{}.html", name))?.into(),
+                thumb: url.join(&format!("{}.thumb", name))?.into(),
+                width: size.map(|(w, _)| w),
+                height: size.map(|(_, h)| h),
+            });
+        }
+        let mut albums = Vec::new();
+        if depth > 0 {
+            albums = self.manifest_albums(Some(dir_name), &album.others, depth - 1, tokens, budget, truncated)?;
+        }
+        Ok(ManifestAlbum {name: dir_name.rsplit('/').next().unwrap().into(), url: url.into(), images, albums})
+    }
+
+    /// Calls `manifest_album()` for each of `names` within `dir_name` that
+    /// is a visible directory.
+    fn manifest_albums(
+        &self,
+        dir_name: Option<&str>,
+        names: &[String],
+        depth: u32,
+        tokens: &[&str],
+        budget: &mut usize,
+        truncated: &mut bool,
+    ) -> Result<Vec<ManifestAlbum>, HttpError> {
+        let mut ret = Vec::new();
+        for name in names {
+            let sub_name = if let Some(dir_name) = dir_name { format!("{}/{}", dir_name, name) } else { name.clone() };
+            let path = self.document_root.join(&sub_name);
+            if !path.is_dir() || !is_public(&path, tokens)? { continue; }
+            if *budget == 0 { *truncated = true; break; }
+            *budget -= 1;
+            match self.manifest_album(&sub_name, depth, tokens, budget, truncated) {
+                Ok(album) => { ret.push(album); },
+                Err(e) => { println!("Manifest: Skipping {}: {}", sub_name, e); },
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Serve a JSON description of all albums, down to `params.depth` levels.
+    pub fn manifest(&self, params: &Params, tokens: &[&str]) -> Result<HttpOkay, HttpError> {
+        let depth = MAX_MANIFEST_DEPTH.min(params.depth.unwrap_or(3));
+        let mut names = Vec::new();
+        for dir_entry in self.document_root.read_dir()? {
+            if let Ok(name) = validate_name(&dir_entry?.file_name()) { names.push(name.to_owned()); }
+        }
+        names.sort();
+        let mut budget = MAX_MANIFEST_ENTRIES;
+        let mut truncated = false;
+        let albums = if depth > 0 {
+            self.manifest_albums(None, &names, depth - 1, tokens, &mut budget, &mut truncated)?
+        } else {
+            Vec::new()
+        };
+        let manifest = Manifest {url: self.base_url.as_str().into(), albums, truncated};
+        Ok(HttpOkay::Json(serde_json::to_string(&manifest).map_err(HttpError::new)?))
+    }
+
+    /// Append to `urls` the URL and modification time of the album
+    /// `dir_name`, its frames, and those of its subdirectories down to
+    /// `depth` further levels. Token-protected albums are omitted.
+    ///
+    /// Stops when `urls` reaches `MAX_SITEMAP_ENTRIES`.
+    fn sitemap_album(&self, dir_name: &str, depth: u32, urls: &mut Vec<(Url, SystemTime)>) -> Result<(), HttpError> {
+        let path = self.document_root.join(dir_name);
+        let url = self.base_url.join(&format!("{}/", dir_name))?;
+        let album = self.album(dir_name)?;
+        urls.push((url.clone(), std::fs::metadata(&path)?.modified()?));
+        for name in &album.jpegs {
+            if urls.len() >= MAX_SITEMAP_ENTRIES { return Ok(()); }
+            let Some(metadata) = metadata_if_exists(&path.join(name))? else { continue; };
+            urls.push((url.join(&format!("{}.html", name))?, metadata.modified()?));
+        }
+        if depth > 0 {
+            self.sitemap_albums(Some(dir_name), &album.others, depth - 1, urls)?;
+        }
+        Ok(())
+    }
+
+    /// Calls `sitemap_album()` for each of `names` within `dir_name` that is
+    /// a directory without a secret.
+    fn sitemap_albums(
+        &self,
+        dir_name: Option<&str>,
+        names: &[String],
+        depth: u32,
+        urls: &mut Vec<(Url, SystemTime)>,
+    ) -> Result<(), HttpError> {
+        for name in names {
+            let sub_name = if let Some(dir_name) = dir_name { format!("{}/{}", dir_name, name) } else { name.clone() };
+            let path = self.document_root.join(&sub_name);
+            if !path.is_dir() || !is_public(&path, &[])? { continue; }
+            if urls.len() >= MAX_SITEMAP_ENTRIES { break; }
+            if let Err(e) = self.sitemap_album(&sub_name, depth, urls) {
+                println!("Sitemap: Skipping {}: {}", sub_name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve a sitemap of all publicly visible albums and frames.
+    ///
+    /// If there are more than `MAX_SITEMAP_URLS`, serves a sitemap index
+    /// instead, which refers to pages selected by `params.page`.
+    pub fn sitemap(&self, params: &Params) -> Result<HttpOkay, HttpError> {
+        let mut names = Vec::new();
+        for dir_entry in self.document_root.read_dir()? {
+            if let Ok(name) = validate_name(&dir_entry?.file_name()) { names.push(name.to_owned()); }
+        }
+        names.sort();
+        let mut urls = Vec::new();
+        self.sitemap_albums(None, &names, MAX_MANIFEST_DEPTH - 1, &mut urls)?;
+        let pages: Vec<_> = urls.chunks(MAX_SITEMAP_URLS).collect();
+        let urls = match params.page {
+            None if pages.len() > 1 => {
+                let sitemaps: String = (1..=pages.len()).map(|page| format!(
+                    "<sitemap><loc>{}sitemap.xml?page={}</loc></sitemap>\n",
+                    html_escape(self.base_url.as_str()), page,
+                )).collect();
+                return Ok(HttpOkay::Xml(format!(
+r#"<?xml version="1.0" encoding="UTF-8"?>
+<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+{sitemaps}</sitemapindex>
+"#,
+                )));
+            },
+            None => pages.first().copied().unwrap_or_default(),
+            Some(page) => pages.get((page as usize).wrapping_sub(1)).ok_or(HttpError::NotFound)?,
+        };
+        let urls: String = urls.iter().map(|(url, mtime)| format!(
+            "<url><loc>{}</loc><lastmod>{}</lastmod></url>\n",
+            html_escape(url.as_str()), iso_date(*mtime),
+        )).collect();
+        Ok(HttpOkay::Xml(format!(
+r#"<?xml version="1.0" encoding="UTF-8"?>
+<urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+{urls}</urlset>
+"#,
+        )))
+    }
+
+    /// Append to `dir_names` the albums inside `dir_name` (relative to the
+    /// document root, or `""` for the root), and those inside them down to
+    /// `depth` further levels, each before the albums inside it. If `tokens`
+    /// is given, albums that are not `is_public()` are left out, with
+    /// everything inside them.
+    fn sub_albums(&self, dir_name: &str, depth: u32, tokens: Option<&[&str]>, dir_names: &mut Vec<String>) -> Result<(), HttpError> {
+        let mut names = Vec::new();
+        for dir_entry in self.document_root.join(dir_name).read_dir()? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            let Ok(name) = validate_name(&file_name) else { continue; };
+            if !dir_entry.path().is_dir() { continue; }
+            if let Some(tokens) = tokens && !is_public(&dir_entry.path(), tokens)? { continue; }
+            names.push(if dir_name.is_empty() { name.to_owned() } else { format!("{}/{}", dir_name, name) });
+        }
+        names.sort();
+        for sub_name in names {
+            dir_names.push(sub_name.clone());
+            if depth > 0 && let Err(e) = self.sub_albums(&sub_name, depth - 1, tokens, dir_names) {
+                println!("Warning: Skipping the albums inside {}: {}", sub_name, e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Redirect to the frame of the photo of the day, from the album
+    /// `dir_name`, or from all albums in `tokens` if `None`.
+    ///
+    /// It changes at midnight UTC, and can be cached until then. See the
+    /// free function `photo_of_the_day()` for how it is chosen.
+    pub fn photo_of_the_day(&self, dir_name: Option<&str>, params: &Params, tokens: &[&str]) -> Result<HttpOkay, HttpError> {
+        let mut dir_names = Vec::new();
+        if let Some(dir_name) = dir_name {
+            dir_names.push(dir_name.to_owned());
+        } else {
+            self.sub_albums("", MAX_MANIFEST_DEPTH - 1, Some(tokens), &mut dir_names)?;
+        }
+        let mut photos = Vec::new();
+        for name in dir_names {
+            match self.album(&name) {
+                Ok(album) => { photos.extend(album.jpegs.iter().map(|leaf_name| format!("{}/{}", name, leaf_name))); },
+                Err(e) if dir_name.is_none() => { println!("Photo of the day: Skipping {}: {}", name, e); },
+                Err(e) => { return Err(e); },
+            }
+        }
+        photos.sort();
+        let now = SystemTime::now();
+        let photo = photo_of_the_day(&photos, &iso_date(now)).ok_or(HttpError::NotFound)?;
+        let url = self.base_url.join(&format!("{}.html{}", photo, params.query()))?;
+        let seconds = now.duration_since(SystemTime::UNIX_EPOCH).map_err(HttpError::new)?.as_secs();
+        let max_age = SECONDS_PER_DAY - seconds % SECONDS_PER_DAY;
+        Ok(HttpOkay::Redirect {location: url.into(), permanent: false}.with_header("Cache-Control", format!("public, max-age={}", max_age)))
+    }
+
+    /// Returns the filename under which `rescale()` caches `leaf_name`
+    /// resized according to `params`, creating its directory if necessary.
+    fn resized_name(&self, dir_name: &str, leaf_name: &str, params: &Params) -> Result<PathBuf, HttpError> {
+        let d = params.get_dimensions();
+        let mut name = format!("{}@{}x{}", leaf_name, d.w, d.h);
+        if let Some(flip) = params.flip { name.push_str(&format!("-{}", flip)); }
+        match params.get_mode() {
+            Mode::Fit => {},
+            Mode::Pad => {
+                let [r, g, b] = params.get_background();
+                name.push_str(&format!("-pad{:02x}{:02x}{:02x}", r, g, b));
+            },
+            Mode::Crop => { name.push_str("-crop"); },
+        }
+        name.push_str(&format!("-q{}.{}", params.get_quality(self.jpeg_quality), params.fmt.unwrap_or(Format::Jpeg)));
+        let resized_dir = self.cache_root().join(dir_name);
+        std::fs::create_dir_all(&resized_dir)?;
+        Ok(resized_dir.join(name))
+    }
+
+    /// Like `rescale()`, but if the request has no `fmt`, chooses the format
+    /// of the resized photo according to `self.negotiate_format` and the
+    /// `Accept` header in `headers`.
+    pub fn negotiate_rescale(&self, dir_name: &str, leaf_name: &str, params: &Params, headers: Headers) -> Result<HttpOkay, HttpError> {
+        let Dimensions {w, h} = params.get_dimensions();
+        if !self.negotiate_format || !cfg!(feature = "avif") || params.fmt.is_some()
+            || w.max(h) <= self.avif_min_dimension
+        {
+            return self.rescale(dir_name, leaf_name, params);
+        }
+        let ret = if headers.accepts("image/avif") {
+            self.rescale(dir_name, leaf_name, &Params {fmt: Some(Format::Avif), ..params.clone()})?
+        } else {
+            self.rescale(dir_name, leaf_name, params)?
+        };
+        Ok(ret.with_header("Vary", "Accept"))
+    }
+
+    /// Serve a resized photo, in `params.fmt`.
+    ///
+    /// It is cached, and regenerated if the original is modified.
+    pub fn rescale(&self, dir_name: &str, leaf_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
+        let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
+        let resized_name = self.resized_name(dir_name, leaf_name, params)?;
+        let data = self.with_generation_lock(&resized_name, || {
+            if is_up_to_date(&resized_name, &jpeg_name)? { return Ok(std::fs::read(&resized_name)?); }
+            let image = Self::resize_image(
+                &jpeg_name, params.get_dimensions(), params.flip, params.get_mode(), params.get_background(),
+            )?;
+            let (_, data) = params.fmt.unwrap_or(Format::Jpeg).encode(&image, params.get_quality(self.jpeg_quality))?;
+            write_atomically(&resized_name, &data)?;
+            Ok::<_, HttpError>(data)
+        })?;
+        // `Format::encode()` might have fallen back to another format.
+        let format = image::guess_format(&data).ok().and_then(Format::from_image_format).unwrap_or(Format::Jpeg);
+        // The server gives it an entity tag.
+        let ret = format.response(data);
+        if self.versioned_urls && params.v.is_some() && params.v == Some(source_version(&jpeg_name)?) {
+            return Ok(ret.with_header("Cache-Control", "public, max-age=31536000, immutable"));
+        }
+        Ok(ret)
+    }
+
+    /// Returns the width of the client's screen in device pixels, according
+    /// to the `Sec-CH-Viewport-Width` and `Sec-CH-DPR` client hints, if known.
+    fn screen_width(headers: Headers) -> Option<u32> {
+        let width = headers.get("Sec-CH-Viewport-Width")?.trim().parse::<f32>().ok()?;
+        let dpr = headers.get("Sec-CH-DPR").and_then(|s| s.trim().parse::<f32>().ok()).unwrap_or(1.0);
+        let ret = (width * dpr).ceil();
+        if ret.is_finite() && ret >= 1.0 { Some(ret as u32) } else { None }
+    }
+
+    /// Serve the photo `leaf_name`, which would be a static file but for
+    /// `self.cap_originals`.
+    pub fn original(
+        &self,
+        dir_name: &str,
+        leaf_name: &str,
+        params: &Params,
+        headers: Headers,
+    ) -> Result<HttpOkay, HttpError> {
+        if !self.cap_originals || params.original || params.download {
+            return self.static_file(dir_name, leaf_name, params, headers);
+        }
+        let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
+        let ret = match Self::screen_width(headers) {
+            Some(width) if self.image_size(&jpeg_name).is_ok_and(|(w, _)| width < w) => {
+                self.check_referer(headers)?;
+                let d = Dimensions {w: width.min(self.max_dimension), h: self.max_dimension};
+                HttpOkay::Jpeg(Self::resize_jpeg(&jpeg_name, d, None, Mode::Fit, BLACK, self.jpeg_quality)?)
+            },
+            _ => self.static_file(dir_name, leaf_name, params, headers)?,
+        };
+        Ok(ret.with_header("Vary", "Sec-CH-Viewport-Width, Sec-CH-DPR"))
+    }
+
+    /// Show an HTML frame around a single photo.
+    pub fn frame(&self, dir_name: &str, leaf_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
+        let dimensions = params.get_dimensions();
+        if !self.document_root.join(dir_name).join(leaf_name).is_file() {
+            println!("Not found: {}/{} does not exist", dir_name, leaf_name);
+            return Err(HttpError::NotFound);
+        }
+        // Enumerate the JPEG files in `dir_name` and compute
+        // `previous` and `next` links.
+        let album = self.album(dir_name)?;
+        let mut jpegs = self.photo_order(dir_name, &album, params.get_order(), params.reversed)?;
+        if let Some(min_rating) = params.min_rating {
+            let rated = self.filter_rating(dir_name, &jpegs, min_rating);
+            // Unless this photo is filtered out itself.
+            if rated.iter().any(|name| name == leaf_name) { jpegs = rated; }
+        }
+        let (previous, next) = previous_next(&jpegs, leaf_name).ok_or(HttpError::NotFound)?;
+        let mut up_params = Params {flip: None, ..params.clone()};
+        if let Some(per_page) = params.per_page {
+            // Link to the page of the index that shows this photo.
+            let position = self.index_photos(dir_name, &album, params)?.iter().position(|name| name == leaf_name);
+            up_params.page = position.map(|i| (i / per_page as usize) as u32 + 1);
+        }
+        let up_query = match up_params.page {
+            Some(page) => up_params.query_with("page", Some(&page.to_string())),
+            None => up_params.query(),
+        };
+        let img_query = if self.versioned_urls {
+            params.query_with("v", Some(&source_version(&self.document_root.join(dir_name).join(leaf_name))?))
+        } else {
+            params.query()
+        };
+        let details = match photo_details(&self.document_root.join(dir_name).join(leaf_name)) {
+            Ok(details) => details,
+            // E.g. HEIC photos.
+            Err(image::ImageError::Unsupported(_)) => ExifDetails::default(),
+            Err(e) => {
+                println!("Warning: Cannot read the EXIF metadata of {}/{}: {}", dir_name, leaf_name, e);
+                ExifDetails::default()
+            },
+        };
+        let mut rows: Vec<_> = details.settings.iter().map(|(name, value)| format!(
+            "<tr><th align=\"left\">{}</th><td>{}</td></tr>\n", name, html_escape(value),
+        )).collect();
+        if let Some((latitude, longitude)) = details.location {
+            rows.push(format!(
+                "<tr><th align=\"left\">Location</th><td><a href=\"{}\">{:.5}, {:.5}</a></td></tr>\n",
+                html_escape_attribute(&format!(
+                    "https://www.openstreetmap.org/?mlat={lat:.5}&mlon={lon:.5}#map=15/{lat:.5}/{lon:.5}",
+                    lat = latitude, lon = longitude,
+                )),
+                latitude, longitude,
+            ));
+        }
+        let details = if rows.is_empty() { String::new() } else {
+            format!("<table align=\"center\" class=\"exif\">\n{}</table>\n", rows.concat())
+        };
         // Generate HTML.
-        Ok(HttpOkay::Html(format!(
+        let ret = HttpOkay::Html(format!(
 r#"<html>
 <head>
 <title>{dir_name}/{base_name}</title>
-<style type="text/css">
-{stylesheet}
+{refresh}<style type="text/css">
+{STYLESHEET}
 </style>
 </head>
 <body>
@@ -218,48 +2400,624 @@ r#"<html>
 <table align="center" valign="center">
 <tr>
 <td colspan="3" align="center">
-<a href="{previous}.html{dimensions}">previous</a>
-<a href="{next}.html{dimensions}">next</a>
-<a href=".{dimensions}">up</a>
+<a href="{previous}.html{query}">previous</a>
+<a href="{next}.html{query}">next</a>
+<a href=".{up_query}">up</a>
 <a href="{leaf_name}">original</a>
-</td>
+<a href="{leaf_name}?dl=1">download</a>
+{stop}</td>
 </tr>
 <tr>
 <td colspan="3" align="center">
-<img src="{leaf_name}{dimensions}"/>
+<img src="{leaf_name}{img_query}"/>
 </td>
 </tr>
 <tr>
 <td>Width <input type="text" name="w" value="{w}"/></td>
 <td>Height <input type="text" name="h" value="{h}"/></td>
-<td><input type="submit" value="Change size"/></td>
+<td><input type="submit" value="Change size"/>{flip_input}{slideshow_input}</td>
 </tr>
 </table>
 </form>
+{details}{footer}
 </body>
 </html>"#,
-            dir_name = dir_name,
-            base_name = remove_extension(leaf_name, "jpg").unwrap(), // Checked by caller.
-            leaf_name = leaf_name,
-            previous = previous,
-            next = next,
-            dimensions = dimensions,
-            w = dimensions.w,
-            h = dimensions.h,
-        )))
+            dir_name = dir_name,
+            base_name = photo_base_name(leaf_name).unwrap(), // Checked by caller.
+            leaf_name = leaf_name,
+            previous = previous,
+            next = next,
+            query = html_escape_attribute(&params.query()),
+            up_query = html_escape_attribute(&up_query),
+            flip_input = params.flip.map_or(String::new(), |flip| format!(
+                r#"<input type="hidden" name="flip" value="{}"/>"#, flip,
+            )),
+            refresh = params.slideshow.map_or(String::new(), |seconds| format!(
+                "<meta http-equiv=\"refresh\" content=\"{}; url={}\"/>\n",
+                seconds, html_escape_attribute(&format!("{}.html{}", next, params.query())),
+            )),
+            stop = params.slideshow.map_or(String::new(), |_| format!(
+                "<a href=\"{}.html{}\">stop</a>\n",
+                leaf_name, html_escape_attribute(&params.query_with("slideshow", None)),
+            )),
+            slideshow_input = params.slideshow.map_or(String::new(), |seconds| format!(
+                r#"<input type="hidden" name="slideshow" value="{}"/>"#, seconds,
+            )),
+            img_query = html_escape_attribute(&img_query),
+            w = dimensions.w,
+            h = dimensions.h,
+            details = details,
+            footer = self.footer(),
+        ));
+        if !self.preload_images { return Ok(ret); }
+        // The same URL as the `<img>`, so that the browser fetches it once.
+        // Omit the origin, in case the browser knows the server by another.
+        let img_url = self.base_url.join(&format!("{}/{}{}", dir_name, leaf_name, img_query))?;
+        Ok(ret.with_header("Link", format!("<{}>; rel=preload; as=image", &img_url[url::Position::BeforePath..])))
+    }
+
+    /// Returns the directory containing all cached files for the current
+    /// `cache_version`, `thumbnail_quality` and `thumbnail_dimensions`.
+    fn cache_root(&self) -> PathBuf {
+        // `@` cannot occur in album names.
+        let mut ret = self.thumbnail_root.to_owned();
+        if self.cache_version != 0 { ret.push(format!("@v{}", self.cache_version)); }
+        ret.push(format!("@q{}", self.thumbnail_quality));
+        // Thumbnails used to be the shape of the photo, in directories
+        // without `@t`. Now they are cropped.
+        ret.push(format!("@t{}x{}", self.thumbnail_dimensions.w, self.thumbnail_dimensions.h));
+        ret
+    }
+
+    /// Calls `f`, which generates the cached file `cache_name`, while holding
+    /// a lock specific to `cache_name`. Concurrent callers with the same
+    /// `cache_name` wait, and `f` should then find the file already exists.
+    fn with_generation_lock<T>(&self, cache_name: &Path, f: impl FnOnce() -> T) -> T {
+        let lock = self.generating.lock().unwrap().entry(cache_name.to_owned()).or_default().clone();
+        let ret = {
+            // A panic in another `f` does not affect this one.
+            let _guard = lock.lock().unwrap_or_else(|e| e.into_inner());
+            f()
+        };
+        let mut generating = self.generating.lock().unwrap();
+        // Forget the lock if nobody else is using it.
+        if Arc::strong_count(&lock) == 2 { generating.remove(cache_name); }
+        ret
+    }
+
+    /// Returns the filename of the cached thumbnail of `leaf_name` with
+    /// `scale` times the usual resolution, creating its directory if
+    /// necessary.
+    fn thumbnail_name(&self, dir_name: &str, leaf_name: &str, scale: u32) -> Result<PathBuf, HttpError> {
+        let thumbnail_dir = self.cache_root().join(dir_name);
+        std::fs::create_dir_all(&thumbnail_dir)?;
+        Ok(if scale == 1 {
+            thumbnail_dir.join(leaf_name)
+        } else {
+            thumbnail_dir.join(format!("{}@{}x", leaf_name, scale))
+        })
+    }
+
+    /// Generate a thumbnail for `leaf_name` with `scale` times the usual
+    /// resolution, and write it to the cache.
+    fn write_thumb(&self, dir_name: &str, leaf_name: &str, scale: u32) -> Result<(), HttpError> {
+        let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
+        let d = Dimensions {w: self.thumbnail_dimensions.w * scale, h: self.thumbnail_dimensions.h * scale};
+        // Crop, so that every thumbnail is the same shape.
+        let data = Self::resize_jpeg(&jpeg_name, d, None, Mode::Crop, BLACK, self.thumbnail_quality)?;
+        write_atomically(&self.thumbnail_name(dir_name, leaf_name, scale)?, &data)?;
+        Ok(())
+    }
+
+    /// Serve a JSON description of the cached files derived from `leaf_name`.
+    /// Requires `check_admin()`.
+    pub fn cache_list(&self, dir_name: &str, leaf_name: &str, headers: Headers) -> Result<HttpOkay, HttpError> {
+        self.check_admin(headers)?;
+        for name in dir_name.split('/') { safe_name(name)?; }
+        let leaf_name = safe_name(leaf_name)?;
+        let mut variants = Vec::new();
+        let thumbnail_dir = self.cache_root().join(dir_name);
+        if thumbnail_dir.is_dir() {
+            for dir_entry in thumbnail_dir.read_dir()? {
+                let dir_entry = dir_entry?;
+                let file_name = dir_entry.file_name();
+                let Some(name) = file_name.to_str() else { continue; };
+                let Some(kind) = cache_kind_of(name, leaf_name) else { continue; };
+                let metadata = dir_entry.metadata()?;
+                // Thumbnails have no extension, and a resized photo might
+                // not be in the format that its extension names, so sniff.
+                let reader = image::ImageReader::open(dir_entry.path())?.with_guessed_format()?;
+                let format = reader.format().and_then(Format::from_image_format).map(|format| format.to_string());
+                let size = reader.into_dimensions().ok();
+                let (kind, scale, requested) = match kind {
+                    CacheKind::Thumbnail(scale) => ("thumbnail", Some(scale), None),
+                    CacheKind::Resized(d) => ("resized", None, Some(d)),
+                };
+                variants.push(CachedVariant {
+                    name: name.into(),
+                    kind,
+                    scale,
+                    requested,
+                    format,
+                    width: size.map(|(w, _)| w),
+                    height: size.map(|(_, h)| h),
+                    bytes: metadata.len(),
+                    mtime: metadata.modified()?.duration_since(SystemTime::UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+                });
+            }
+        }
+        variants.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(HttpOkay::Json(serde_json::to_string(&variants).map_err(HttpError::new)?))
+    }
+
+    /// Returns the thumbnail resolution requested by the `Sec-CH-DPR` client
+    /// hint, as a multiple of the usual resolution.
+    fn thumbnail_scale(headers: Headers) -> u32 {
+        let dpr = headers.get("Sec-CH-DPR").and_then(|s| s.trim().parse::<f32>().ok()).unwrap_or(1.0);
+        if dpr.is_finite() { (dpr.ceil() as u32).clamp(1, MAX_THUMBNAIL_SCALE) } else { 1 }
+    }
+
+    /// Returns the filename of the cached thumbnail of `leaf_name` with
+    /// `scale` times the usual resolution, generating it if necessary.
+    fn cached_thumb(&self, dir_name: &str, leaf_name: &str, scale: u32) -> Result<PathBuf, HttpError> {
+        let thumbnail_name = self.thumbnail_name(dir_name, leaf_name, scale)?;
+        let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
+        self.with_generation_lock(&thumbnail_name, || {
+            // If the cached thumbnail file is missing or out of date, generate it.
+            if !is_up_to_date(&thumbnail_name, &jpeg_name)? { self.write_thumb(dir_name, leaf_name, scale)?; }
+            Ok::<_, HttpError>(())
+        })?;
+        Ok(thumbnail_name)
+    }
+
+    /// Returns a tiny JPEG preview of `leaf_name`, for inlining in the index.
+    ///
+    /// It is made from the thumbnail, and cached next to it.
+    fn preview(&self, dir_name: &str, leaf_name: &str) -> Result<Vec<u8>, HttpError> {
+        let thumbnail_name = self.cached_thumb(dir_name, leaf_name, 1)?;
+        let preview_name = self.cache_root().join(dir_name).join(format!("{}@preview", leaf_name));
+        self.with_generation_lock(&preview_name, || {
+            if is_up_to_date(&preview_name, &thumbnail_name)? { return Ok(std::fs::read(&preview_name)?); }
+            let ret = Self::resize_jpeg(&thumbnail_name, PREVIEW_DIMENSIONS, None, Mode::Fit, BLACK, self.thumbnail_quality)?;
+            write_atomically(&preview_name, &ret)?;
+            Ok(ret)
+        })
+    }
+
+    /// Serve a JPEG thumbnail.
+    ///
+    /// Its entity tag depends on the original photo and on everything that
+    /// affects the thumbnail, so a revalidation is answered without reading
+    /// either file. Its last-modified date is that of the original photo.
+    pub fn thumb(&self, dir_name: &str, leaf_name: &str, _params: &Params, headers: Headers) -> Result<HttpOkay, HttpError> {
+        let scale = if self.client_hints { Self::thumbnail_scale(headers) } else { 1 };
+        let (version, last_modified) = validators(&self.document_root.join(dir_name).join(leaf_name))?;
+        let etag = format!(
+            "\"{:016x}\"",
+            hash((version, scale, &self.thumbnail_dimensions, Mode::Crop, self.thumbnail_quality, self.cache_version)),
+        );
+        let ret = if headers.is_fresh(&etag, &last_modified) {
+            HttpOkay::NotModified
+        } else {
+            HttpOkay::File {file: File::open(self.cached_thumb(dir_name, leaf_name, scale)?)?, content_type: "image/jpeg".into()}
+        }.with_header("ETag", etag).with_header("Last-Modified", last_modified);
+        Ok(if self.client_hints { ret.with_header("Vary", "Sec-CH-DPR") } else { ret })
+    }
+
+    /// Serve an animated GIF that cycles through the thumbnails of the first
+    /// `MAX_SLIDESHOW_FRAMES` photos in `dir_name`, showing each for
+    /// `params.delay` milliseconds.
+    ///
+    /// It is cached under a name that depends on the photos and their
+    /// modification times, so it is regenerated when the album changes.
+    pub fn slideshow(&self, dir_name: &str, params: &Params) -> Result<HttpOkay, HttpError> {
+        let delay = params.delay.unwrap_or(1000).clamp(20, 60000);
+        let album = self.album(dir_name)?;
+        let jpegs = self.photo_order(dir_name, &album, params.get_order(), params.reversed)?;
+        let leaf_names = &jpegs[..jpegs.len().min(MAX_SLIDESHOW_FRAMES)];
+        if leaf_names.is_empty() { return Err(HttpError::NotFound); }
+        let mut mtimes = Vec::new();
+        for leaf_name in leaf_names {
+            mtimes.push(std::fs::metadata(self.document_root.join(dir_name).join(leaf_name))?.modified()?);
+        }
+        let version = hash((leaf_names, mtimes, delay));
+        let slideshow_name = self.thumbnail_name(dir_name, &format!("@slideshow-{:016x}", version), 1)?;
+        self.with_generation_lock(&slideshow_name, || {
+            if !slideshow_name.is_file() {
+                let d = &self.thumbnail_dimensions;
+                let mut frames = Vec::new();
+                for leaf_name in leaf_names {
+                    let thumbnail = match self.cached_thumb(dir_name, leaf_name, 1).and_then(
+                        |name| image::open(name).map_err(HttpError::new)
+                    ) {
+                        Ok(thumbnail) => thumbnail.to_rgba8(),
+                        Err(e) => { println!("Warning: Slideshow skipping {}/{}: {}", dir_name, leaf_name, e); continue; },
+                    };
+                    // Centre each thumbnail on a black frame of the usual size.
+                    let mut canvas = image::RgbaImage::from_pixel(d.w, d.h, image::Rgba([0, 0, 0, 255]));
+                    let x = d.w.saturating_sub(thumbnail.width()) / 2;
+                    let y = d.h.saturating_sub(thumbnail.height()) / 2;
+                    image::imageops::overlay(&mut canvas, &thumbnail, x.into(), y.into());
+                    frames.push(image::Frame::from_parts(canvas, 0, 0, image::Delay::from_numer_denom_ms(delay, 1)));
+                }
+                let mut data = Vec::new();
+                {
+                    let mut encoder = image::codecs::gif::GifEncoder::new_with_speed(&mut data, 10);
+                    encoder.set_repeat(image::codecs::gif::Repeat::Infinite).map_err(HttpError::new)?;
+                    encoder.encode_frames(frames).map_err(HttpError::new)?;
+                }
+                write_atomically(&slideshow_name, &data)?;
+            }
+            Ok::<_, HttpError>(())
+        })?;
+        let file = File::open(&slideshow_name)?;
+        Ok(HttpOkay::Stream {
+            length: Some(file.metadata()?.len()),
+            reader: Box::new(file),
+            content_type: "image/gif".into(),
+        })
+    }
+
+    /// Returns the time at which the previous call to `precache()` started,
+    /// if known.
+    fn last_precache(&self) -> Option<SystemTime> {
+        let mut text = String::new();
+        File::open(self.cache_root().join(PRECACHE_STATE)).ok()?.read_to_string(&mut text).ok()?;
+        let seconds = text.trim().parse::<u64>().ok()?;
+        SystemTime::UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+    }
+
+    /// Generate thumbnails for all photos in all albums, or only in `album`.
+    ///
+    /// If a previous sweep of all albums completed, only photos modified
+    /// since it started (or whose thumbnails are missing) are processed.
+    /// Otherwise, all photos are processed, replacing any cached thumbnails.
+    ///
+    /// Returns the number of photos whose thumbnails could not be generated.
+    pub fn precache(&self, album: Option<&str>) -> Result<usize, HttpError> {
+        let start = SystemTime::now();
+        let since = if album.is_none() { self.last_precache() } else { None };
+        let (mut generated, mut failed) = (0, 0);
+        // Every album, public or not, since thumbnails are checked when served.
+        let mut dir_names = Vec::new();
+        if let Some(album) = album { dir_names.push(album.to_owned()); }
+        self.sub_albums(album.unwrap_or(""), MAX_MANIFEST_DEPTH - 1, None, &mut dir_names)?;
+        for dir_name in &dir_names {
+            let album = match self.album(dir_name) {
+                Ok(album) => album,
+                Err(e) => { println!("Precache: Skipping {}: {}", dir_name, e); continue; },
+            };
+            for leaf_name in &album.jpegs {
+                let thumbnail_name = self.thumbnail_name(dir_name, leaf_name, 1)?;
+                if let Some(since) = since {
+                    let mtime = std::fs::metadata(self.document_root.join(dir_name).join(leaf_name))?.modified()?;
+                    if mtime < since && thumbnail_name.exists() { continue; }
+                }
+                self.with_generation_lock(&thumbnail_name, || {
+                    match self.write_thumb(dir_name, leaf_name, 1) {
+                        Ok(()) => { generated += 1; },
+                        Err(e) => {
+                            println!("Precache: Failed {}/{}: {}", dir_name, leaf_name, e);
+                            // Don't keep serving an out-of-date thumbnail.
+                            if thumbnail_name.exists() { std::fs::remove_file(&thumbnail_name)?; }
+                            failed += 1;
+                        },
+                    }
+                    Ok::<_, HttpError>(())
+                })?;
+            }
+        }
+        if album.is_none() {
+            let seconds = start.duration_since(SystemTime::UNIX_EPOCH).map_err(HttpError::new)?.as_secs();
+            write_atomically(&self.cache_root().join(PRECACHE_STATE), format!("{}\n", seconds).as_bytes())?;
+        }
+        println!(
+            "Precache: {} sweep of {} generated {} thumbnails ({} failed) in {:?}",
+            if since.is_some() { "Incremental" } else { "Full" }, album.unwrap_or("all albums"),
+            generated, failed, start.elapsed().unwrap_or_default(),
+        );
+        Ok(failed)
+    }
+
+    /// Delete the cached files covered by `rebuild`, and regenerate the
+    /// thumbnails.
+    fn rebuild_cache(&self, rebuild: &Rebuild) -> Result<usize, HttpError> {
+        println!("Precache: Rebuilding {:?}", rebuild);
+        let (cache_dir, album) = match rebuild {
+            Rebuild::All => (self.cache_root(), None),
+            Rebuild::Album(album) => (self.cache_root().join(album), Some(album.as_str())),
+        };
+        match std::fs::remove_dir_all(&cache_dir) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => { return Err(e.into()); },
+            _ => {},
+        }
+        std::fs::create_dir_all(self.cache_root())?;
+        self.precache(album)
+    }
+
+    /// Delete the cached files derived from `leaf_name`, e.g. because it has
+    /// been replaced. They would be regenerated anyway, because they are
+    /// older than the photo, but they would waste space until then.
+    fn forget_cached(&self, dir_name: &str, leaf_name: &str) -> Result<(), HttpError> {
+        let cache_dir = self.cache_root().join(dir_name);
+        if !cache_dir.is_dir() { return Ok(()); }
+        let prefix = format!("{}@", leaf_name);
+        for dir_entry in cache_dir.read_dir()? {
+            let dir_entry = dir_entry?;
+            let file_name = dir_entry.file_name();
+            let Some(name) = file_name.to_str() else { continue; };
+            if name != leaf_name && !name.starts_with(&prefix) { continue; }
+            self.with_generation_lock(&dir_entry.path(), || std::fs::remove_file(dir_entry.path()))?;
+        }
+        Ok(())
+    }
+
+    /// Save the JPEG files in the `multipart/form-data` `body` to the album
+    /// `path`, replacing any with the same names. Requires `check_admin()`.
+    ///
+    /// Nothing is saved unless every file is a JPEG file with a valid name.
+    /// Fields that are not files are ignored.
+    ///
+    /// With `If-Match`, nothing is saved unless every file replaces a photo
+    /// whose entity tag, as served by `static_file()`, matches.
+    pub fn upload(&self, path: &[String], headers: Headers, body: &mut dyn Read) -> Result<HttpOkay, HttpError> {
+        self.check_admin(headers)?;
+        for name in path { safe_name(name)?; }
+        let dir_name = path.join("/");
+        let dir_path = self.document_root.join(&dir_name);
+        if !dir_path.is_dir() {
+            // Photos and other files can only be fetched.
+            return Err(if dir_path.exists() { HttpError::MethodNotAllowed } else { HttpError::NotFound });
+        }
+        let Some(boundary) = headers.get("Content-Type").and_then(multipart::boundary) else {
+            println!("Refused: Upload to {} is not multipart/form-data", dir_name);
+            return Err(HttpError::Invalid);
+        };
+        // The server limits its length.
+        let mut data = Vec::new();
+        body.read_to_end(&mut data)?;
+        let Some(parts) = multipart::parse(&data, &boundary) else {
+            println!("Refused: Upload to {} is malformed", dir_name);
+            return Err(HttpError::Invalid);
+        };
+        let mut files = Vec::new();
+        for part in parts {
+            let Some(filename) = &part.filename else { continue; };
+            // An empty file input.
+            if filename.is_empty() && part.data.is_empty() { continue; }
+            // Some browsers send the whole path.
+            let leaf_name = filename.rsplit(['/', '\\']).next().unwrap();
+            // Only names that `Album::new()` will list as photos.
+            let is_jpeg = safe_name(leaf_name).is_ok() && !leaf_name.starts_with(TEMPORARY_PREFIX)
+                && remove_extension(leaf_name, "jpg").is_some()
+                && part.data.starts_with(&[0xFF, 0xD8, 0xFF]);
+            if !is_jpeg {
+                println!("Refused: Upload of {:?} to {} is not a JPEG file", filename, dir_name);
+                return Err(HttpError::Invalid);
+            }
+            files.push((leaf_name.to_owned(), part.data));
+        }
+        if files.is_empty() {
+            println!("Refused: Upload to {} contains no files", dir_name);
+            return Err(HttpError::Invalid);
+        }
+        // Concurrent uploads to the album cannot change a photo between
+        // checking and replacing it.
+        self.with_generation_lock(&dir_path, || {
+            for (leaf_name, _) in &files {
+                let file_name = dir_path.join(leaf_name);
+                let etag = if file_name.is_file() { Some(format!("\"{}\"", validators(&file_name)?.0)) } else { None };
+                if !headers.if_match(etag.as_deref()) {
+                    println!("Refused: Upload of {}/{} does not match If-Match", dir_name, leaf_name);
+                    return Err(HttpError::PreconditionFailed);
+                }
+            }
+            let mut report = String::new();
+            for (leaf_name, data) in files {
+                write_atomically(&dir_path.join(&leaf_name), data)?;
+                self.forget_cached(&dir_name, &leaf_name)?;
+                println!("Uploaded {}/{} ({} bytes)", dir_name, leaf_name, data.len());
+                report.push_str(&format!("Saved {}\n", leaf_name));
+            }
+            Ok(HttpOkay::Text(report))
+        })
+    }
+
+    /// Ask `maintain()` to rebuild the cache. Requires `check_admin()`.
+    pub fn request_rebuild(&self, params: &Params, headers: Headers) -> Result<HttpOkay, HttpError> {
+        self.check_admin(headers)?;
+        let rebuild = match &params.album {
+            Some(album) => {
+                let album = safe_name(album)?;
+                if !self.document_root.join(album).is_dir() { return Err(HttpError::NotFound); }
+                Rebuild::Album(album.into())
+            },
+            None => Rebuild::All,
+        };
+        let mut pending = self.rebuild.lock().unwrap();
+        // Combine with any request that has not started yet.
+        *pending = Some(match pending.take() {
+            Some(other) if other != rebuild => Rebuild::All,
+            _ => rebuild,
+        });
+        self.rebuild_requested.notify_all();
+        Ok(HttpOkay::Text(format!("Queued rebuild of {:?}\n", pending.as_ref().unwrap())))
+    }
+
+    /// Write the public albums to the directory `output` as a static web
+    /// site: the index and photo pages as HTML files, with the thumbnails,
+    /// resized photos and other files that they link to. Nested albums are
+    /// written to the matching subdirectories of `output`.
+    ///
+    /// Returns the number of files that could not be written.
+    pub fn export(&self, output: &Path) -> Result<usize, HttpError> {
+        let start = Instant::now();
+        let mut dir_names = Vec::new();
+        self.sub_albums("", MAX_MANIFEST_DEPTH - 1, Some(&[]), &mut dir_names)?;
+        std::fs::create_dir_all(output)?;
+        let mut failed = 0;
+        for dir_name in &dir_names {
+            failed += self.export_album(dir_name, &output.join(dir_name)).unwrap_or_else(|e| {
+                println!("Export: Failed {}: {}", dir_name, e);
+                1
+            });
+        }
+        let links: Vec<_> = dir_names.iter().filter(|name| !name.contains('/')).map(|name| format!(
+            r#"<a href="{name}/index.html">{name}</a><br/>"#,
+            name = html_escape_attribute(name),
+        )).collect();
+        std::fs::write(output.join("index.html"), format!(
+r#"<html>
+ <head>
+  <title>Albums</title>
+ </head>
+ <body>
+  <h2>Albums</h2>
+  {links}
+  {footer}
+ </body>
+</html>"#,
+            links = links.join("\n  "),
+            footer = self.footer(),
+        ))?;
+        println!(
+            "Export: Wrote {} albums to {} ({} files failed) in {:?}",
+            dir_names.len(), output.display(), failed, start.elapsed(),
+        );
+        Ok(failed)
     }
 
-    /// Serve a JPEG thumbnail.
-    pub fn thumb(&self, dir_name: &str, leaf_name: &str, _params: &Params) -> Result<HttpOkay, HttpError> {
-        let thumbnail_dir = self.thumbnail_root.join(dir_name);
-        std::fs::create_dir_all(&thumbnail_dir)?;
-        let thumbnail_name = thumbnail_dir.join(leaf_name);
-        if let Ok(mut file) = File::create_new(&thumbnail_name) {
-            // Cached thumbnail file is missing; generate it.
-            let jpeg_name = self.document_root.join(dir_name).join(leaf_name);
-            file.write(&Self::resize_jpeg(&jpeg_name, Dimensions {w: 128, h: 96})?)?;
+    /// Export the album `dir_name` to the directory `output`. See `export()`.
+    ///
+    /// Returns the number of files that could not be written.
+    fn export_album(&self, dir_name: &str, output: &Path) -> Result<usize, HttpError> {
+        let album_config = AlbumConfig::load(&self.document_root.join(dir_name));
+        let params = Params {
+            max_dimension: Some(self.max_dimension),
+            default_w: album_config.w.or(Some(self.default_dimensions.w)),
+            default_h: album_config.h.or(Some(self.default_dimensions.h)),
+            default_order: album_config.order.as_deref().and_then(Order::parse),
+            // There is no "show all" page.
+            all: true,
+            ..Params::default()
+        };
+        let album = self.album(dir_name)?;
+        println!("Export: {} ({} photos, {} other files)", dir_name, album.jpegs.len(), album.others.len());
+        std::fs::create_dir_all(output)?;
+        let mut failed = 0;
+        let mut check = |leaf_name: &str, result: Result<(), HttpError>| {
+            if let Err(e) = result {
+                println!("Export: Failed {}/{}: {}", dir_name, leaf_name, e);
+                failed += 1;
+            }
+        };
+        // Leave out the albums that `export()` leaves out.
+        check("index.html", self.index(dir_name, &params, |path| is_public(path, &[])).and_then(
+            |page| write_static_page(&output.join("index.html"), page)
+        ));
+        for leaf_name in &album.jpegs {
+            check(leaf_name, self.export_photo(dir_name, leaf_name, &params, output));
+        }
+        for leaf_name in &album.others {
+            let path = self.document_root.join(dir_name).join(leaf_name);
+            if path.is_dir() { continue; }
+            check(leaf_name, std::fs::copy(&path, output.join(leaf_name)).map(|_| ()).map_err(HttpError::from));
+        }
+        Ok(failed)
+    }
+
+    /// Export the photo `leaf_name` to the directory `output`, with its page,
+    /// thumbnail and resized copy. See `export()`.
+    fn export_photo(&self, dir_name: &str, leaf_name: &str, params: &Params, output: &Path) -> Result<(), HttpError> {
+        write_static_page(&output.join(format!("{}.html", leaf_name)), self.frame(dir_name, leaf_name, params)?)?;
+        std::fs::copy(self.cached_thumb(dir_name, leaf_name, 1)?, output.join(format!("{}.thumb.jpg", leaf_name)))?;
+        // `rescale()` leaves the resized photo in the cache.
+        self.rescale(dir_name, leaf_name, params)?;
+        std::fs::copy(
+            self.resized_name(dir_name, leaf_name, params)?,
+            output.join(exported_resized_name(leaf_name, params)),
+        )?;
+        std::fs::copy(self.document_root.join(dir_name).join(leaf_name), output.join(leaf_name))?;
+        Ok(())
+    }
+
+    /// Append to `issues` the entries of `dir_name` (relative to the
+    /// document root, or `""` for the root), and of its subdirectories down
+    /// to `depth` further levels, that have dubious names or are photos whose
+    /// headers cannot be decoded.
+    ///
+    /// Stops when `issues` reaches `MAX_ISSUES`, and sets `truncated`.
+    fn find_issues(&self, dir_name: &str, depth: u32, issues: &mut Vec<Issue>, truncated: &mut bool) {
+        fn report(issues: &mut Vec<Issue>, truncated: &mut bool, path: String, reason: String) {
+            if issues.len() < MAX_ISSUES { issues.push(Issue {path, reason}); } else { *truncated = true; }
+        }
+        let path = self.document_root.join(dir_name);
+        let read_dir = match path.read_dir() {
+            Ok(read_dir) => read_dir,
+            Err(e) => { report(issues, truncated, dir_name.into(), format!("Cannot list: {}", e)); return; },
+        };
+        let mut names = Vec::new();
+        for dir_entry in read_dir {
+            let file_name = match dir_entry {
+                Ok(dir_entry) => dir_entry.file_name(),
+                Err(e) => { report(issues, truncated, dir_name.into(), format!("Cannot list: {}", e)); continue; },
+            };
+            let sub_name = |name: &str| if dir_name.is_empty() { name.to_owned() } else { format!("{}/{}", dir_name, name) };
+            match validate_name(&file_name) {
+                Ok(name) => { names.push(sub_name(name)); },
+                Err(e) => { report(issues, truncated, sub_name(&file_name.to_string_lossy()), e.to_string()); },
+            }
+        }
+        names.sort();
+        for sub_name in names {
+            if *truncated { return; }
+            let sub_path = self.document_root.join(&sub_name);
+            if sub_path.is_dir() {
+                if depth > 0 { self.find_issues(&sub_name, depth - 1, issues, truncated); }
+            } else if photo_base_name(&sub_name).is_some() && let Err(e) = self.image_size(&sub_path) {
+                report(issues, truncated, sub_name, format!("Cannot decode: {}", e));
+            }
+        }
+    }
+
+    /// List the files that albums skip or cannot show. Requires
+    /// `check_admin()`.
+    pub fn issues(&self, headers: Headers) -> Result<HttpOkay, HttpError> {
+        self.check_admin(headers)?;
+        let mut issues = Vec::new();
+        let mut truncated = false;
+        self.find_issues("", MAX_MANIFEST_DEPTH, &mut issues, &mut truncated);
+        let issues = Issues {issues, truncated};
+        Ok(HttpOkay::Json(serde_json::to_string(&issues).map_err(HttpError::new)?))
+    }
+
+    /// Run `precache()` at startup and then every `precache_minutes` (once
+    /// if `0`; never if `None`), and rebuild the cache whenever requested.
+    pub fn maintain(&self, precache_minutes: Option<u32>) -> ! {
+        let mut next_precache = precache_minutes.map(|_| Instant::now());
+        let mut pending = self.rebuild.lock().unwrap();
+        loop {
+            if let Some(rebuild) = pending.take() {
+                drop(pending);
+                if let Err(e) = self.rebuild_cache(&rebuild) { println!("Precache: Error: {}", e); }
+                pending = self.rebuild.lock().unwrap();
+                continue;
+            }
+            let now = Instant::now();
+            match next_precache {
+                Some(time) if time <= now => {
+                    drop(pending);
+                    if let Err(e) = self.precache(None) { println!("Precache: Error: {}", e); }
+                    next_precache = match precache_minutes {
+                        Some(0) | None => None,
+                        Some(minutes) => Some(now + Duration::from_secs(u64::from(minutes) * 60)),
+                    };
+                    pending = self.rebuild.lock().unwrap();
+                },
+                Some(time) => { pending = self.rebuild_requested.wait_timeout(pending, time - now).unwrap().0; },
+                None => { pending = self.rebuild_requested.wait(pending).unwrap(); },
+            }
         }
-        Ok(HttpOkay::File(File::open(&thumbnail_name)?))
     }
 }
 
@@ -268,32 +3026,271 @@ impl<'a> Handler for PhotoServer<'a> {
 
     /// Handle a single request.
     fn handle_get(
+        &self,
+        path: Vec<String>,
+        mut params: Self::Params,
+        headers: Headers,
+    ) -> Result<HttpOkay, HttpError> {
+        if path.len() == 2 && path[0] == "admin" && path[1] == "issues" {
+            return self.issues(headers);
+        }
+        let route = Route::parse(&path, &params, self.document_root)?;
+        params.max_dimension = Some(self.max_dimension);
+        params.default_w = Some(self.default_dimensions.w);
+        params.default_h = Some(self.default_dimensions.h);
+        // Apply the album's settings.
+        if let Some(dir_name) = route.dir() {
+            let album_config = AlbumConfig::load(&self.document_root.join(dir_name));
+            if let Some(w) = album_config.w { params.default_w = Some(w); }
+            if let Some(h) = album_config.h { params.default_h = Some(h); }
+            params.default_order = album_config.order.as_deref().and_then(Order::parse);
+        }
+        let tokens: Vec<&str> = params.token.iter().map(String::as_str).chain(headers.cookies("token")).collect();
+        let cookie = match route.dir() {
+            Some(dir_name) => self.check_album_tokens(dir_name, &params, &tokens)?,
+            None => None,
+        };
+        if let Some(dir_name) = route.dir() { self.check_album_users(dir_name, headers)?; }
+        let ret = self.dispatch(route, &params, headers, &tokens)?;
+        // Remember the secret so that relative links work.
+        Ok(match cookie {
+            Some(secret) => ret.with_header("Set-Cookie", format!("token={}; HttpOnly; SameSite=Lax", secret)),
+            None => ret,
+        })
+    }
+
+    /// Album indexes contain relative links, so need a trailing slash.
+    fn is_directory(&self, path: &[String], params: &Self::Params, headers: Headers) -> bool {
+        let Ok(Route::Index(dir_name)) = Route::parse(path, params, self.document_root) else { return false; };
+        let tokens: Vec<&str> = params.token.iter().map(String::as_str).chain(headers.cookies("token")).collect();
+        self.document_root.join(&dir_name).is_dir() && self.check_album_tokens(&dir_name, params, &tokens).is_ok()
+    }
+
+    /// Handle an administrative request.
+    fn handle_post(
         &self,
         path: Vec<String>,
         params: Self::Params,
+        headers: Headers,
+        body: &mut dyn Read,
     ) -> Result<HttpOkay, HttpError> {
-        // Dispatch to the appropriate method.
-        let mut path_iter = path.into_iter();
-        let dir_name = &path_iter.next().ok_or(HttpError::Invalid)?;
-        if let Some(leaf_name) = &path_iter.next() {
-            if let Some(_) = remove_extension(leaf_name, "jpg") {
-                if params.w.is_some() || params.h.is_some() {
-                    return self.rescale(dir_name, leaf_name, &params);
-                }
-            } else if let Some(jpeg_name) = remove_extension(leaf_name, "html") {
-                if let Some(_) = remove_extension(jpeg_name, "jpg") {
-                    return self.frame(dir_name, &jpeg_name, &params);
-                }
-            } else if let Some(jpeg_name) = remove_extension(leaf_name, "thumb") {
-                if let Some(_) = remove_extension(jpeg_name, "jpg") {
-                    return self.thumb(dir_name, &jpeg_name, &params);
-                }
-            }
-            // Any other `leaf_name` is a static file.
-            let document_name = self.document_root.join(dir_name).join(leaf_name);
-            return Ok(HttpOkay::File(File::open(&document_name)?));
+        match path.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+            ["admin", "rebuild-cache"] => self.request_rebuild(&params, headers),
+            ["admin", ..] | [] => Err(HttpError::NotFound),
+            _ => self.upload(&path, headers, body),
+        }
+    }
+
+    /// Show a page with links to the parent directory and the root.
+    fn error_html(&self, status: u16, message: &str, url: &str) -> Option<String> {
+        let path = url.split(['?', '#']).next().unwrap();
+        // The parent of `/dir/` is `..`, and the parent of `/dir/leaf` is `.`.
+        let up = if path.ends_with('/') { ".." } else { "." };
+        Some(format!(
+r#"<html>
+<head>
+<title>{status} {message}</title>
+<style type="text/css">
+{STYLESHEET}
+</style>
+</head>
+<body>
+<center>
+<h3>{status} {message}</h3>
+<p>There is nothing at {path}.</p>
+<a href="{up}">up</a>
+<a href="{root}">home</a>
+</center>
+{footer}
+</body>
+</html>"#,
+            message = html_escape(message),
+            path = html_escape(&url_escape::decode(path)),
+            root = html_escape_attribute(self.base_url.as_str()),
+            footer = self.footer(),
+        ))
+    }
+}
+
+impl<'a> PhotoServer<'a> {
+    /// Serve the file `leaf_name` unchanged.
+    ///
+    /// If `params.download` is set, the browser is asked to save the file
+    /// instead of showing it. All other parameters are ignored.
+    ///
+    /// The response carries validators, so that caches can revalidate it.
+    /// If `self.referer_check` is set, links from other sites are refused.
+    pub fn static_file(
+        &self,
+        dir_name: &str,
+        leaf_name: &str,
+        params: &Params,
+        headers: Headers,
+    ) -> Result<HttpOkay, HttpError> {
+        if leaf_name == TOKEN_FILENAME || leaf_name == AUTH_FILENAME || leaf_name == ALBUM_CONFIG_FILENAME
+            || leaf_name.starts_with(TEMPORARY_PREFIX)
+        {
+            return Err(HttpError::NotFound);
+        }
+        self.check_referer(headers)?;
+        let document_name = self.document_root.join(dir_name).join(leaf_name);
+        // E.g. a nested album, which cannot be served as a file.
+        if !document_name.is_file() { return Err(HttpError::NotFound); }
+        let mut file = File::open(&document_name)?;
+        let (version, last_modified) = validators(&document_name)?;
+        let etag = format!("\"{}\"", version);
+        if headers.is_fresh(&etag, &last_modified) {
+            return Ok(HttpOkay::NotModified.with_header("ETag", etag));
+        }
+        // Label text as UTF-8 if it is, so that browsers show it correctly.
+        let mut content_type = content_type(leaf_name);
+        if remove_extension(leaf_name, "txt").is_some() {
+            if is_utf8(&file)? { content_type = "text/plain; charset=utf-8"; }
+            file.rewind()?;
+        }
+        let ret = HttpOkay::Stream {
+            length: Some(file.metadata()?.len()),
+            reader: Box::new(file),
+            content_type: content_type.into(),
+        }.with_header("ETag", etag)
+            .with_header("Last-Modified", last_modified)
+            .with_header("Cache-Control", "public, max-age=86400");
+        if !params.download { return Ok(ret); }
+        Ok(ret.with_header("Content-Disposition", match validate_name(OsStr::new(leaf_name)) {
+            Ok(name) => format!("attachment; filename=\"{}\"", name),
+            Err(_) => "attachment".into(),
+        }))
+    }
+
+    /// Serve a ZIP archive of the photos and other files in `dir_name`,
+    /// but not its nested albums.
+    ///
+    /// With `params.since` or `params.until`, it contains only the photos
+    /// whose EXIF capture dates are in that range, inclusive. Other files,
+    /// and photos with no capture date, are omitted. If no photos match,
+    /// the response is `404`. Malformed dates are refused.
+    ///
+    /// Archives bigger than `self.max_zip_bytes` are refused. If
+    /// `self.referer_check` is set, links from other sites are refused.
+    pub fn zip(&self, dir_name: &str, params: &Params, headers: Headers) -> Result<HttpOkay, HttpError> {
+        self.check_referer(headers)?;
+        let since = params.since.as_deref().map(|date| parse_day(date).ok_or(())).transpose();
+        let until = params.until.as_deref().map(|date| parse_day(date).ok_or(())).transpose();
+        let (Ok(since), Ok(until)) = (since, until) else {
+            println!("Refused: Malformed date range {:?} to {:?} for {}", params.since, params.until, dir_name);
+            return Err(HttpError::Invalid);
+        };
+        let album = self.album(dir_name)?;
+        let names: Vec<String> = if since.is_none() && until.is_none() {
+            album.readme.into_iter().chain(album.jpegs).chain(album.others).collect()
         } else {
-            return self.index(dir_name, &params);
+            let in_range = |name: &String| {
+                let details = photo_details(&self.document_root.join(dir_name).join(name));
+                let Some(taken) = details.ok().and_then(|details| details.taken) else { return false; };
+                let day = taken.split(' ').next().unwrap_or(&taken);
+                since.is_none_or(|since| since <= day) && until.is_none_or(|until| day <= until)
+            };
+            let names: Vec<String> = album.jpegs.into_iter().filter(in_range).collect();
+            if names.is_empty() {
+                println!("Refused: No photos in {} were taken from {:?} to {:?}", dir_name, since, until);
+                return Err(HttpError::NotFound);
+            }
+            names
+        };
+        let mut entries = Vec::new();
+        for name in names {
+            let path = self.document_root.join(dir_name).join(&name);
+            let Some(metadata) = metadata_if_exists(&path)? else { continue; };
+            if !metadata.is_file() { continue; }
+            entries.push(zip::Entry {name, path, size: metadata.len(), mtime: metadata.modified()?});
+        }
+        let stream = zip::ZipStream::new(entries).filter(|stream| stream.len() <= self.max_zip_bytes);
+        let Some(stream) = stream else {
+            println!("Refused: {} is too big to download as a ZIP archive", dir_name);
+            return Err(HttpError::Invalid);
+        };
+        let leaf_name = dir_name.rsplit('/').next().unwrap_or(dir_name);
+        Ok(HttpOkay::Stream {
+            length: Some(stream.len()),
+            reader: Box::new(stream),
+            content_type: "application/zip".into(),
+        }.with_header("Content-Disposition", format!("attachment; filename=\"{}.zip\"", leaf_name)))
+    }
+
+    /// Dispatch a request to the appropriate method, according to its
+    /// `Route`.
+    ///
+    /// - `manifest.json` describes all albums, and takes `depth`.
+    /// - `sitemap.xml` lists all public pages, and takes `page`.
+    /// - `favicon.ico` and `robots.txt`, unless they exist, are built in.
+    /// - `photo-of-the-day` redirects to a frame chosen from all albums, and
+    ///   takes the same parameters as a frame, which it passes on.
+    /// - `dir/` is an album index, where `dir` is one or more directories,
+    ///   e.g. `2023/summer/`. It takes the size parameters `w` and `h`, which
+    ///   are passed on to links, `all`, `min_rating`, `sort` and `order`.
+    /// - `dir/?download=zip` is a ZIP archive of the files in the album, and
+    ///   takes `since` and `until`.
+    /// - `dir/?format=json` describes the album, and takes `min_rating`, `sort`
+    ///   and `order`.
+    /// - `dir/leaf.jpg` with any of the parameters `w`, `h`, `flip`, `mode`,
+    ///   `fmt` or `q` is a resized photo. `mode=pad` also takes `bg`. Without
+    ///   them, it is an original photo, and takes `original` and `download`
+    ///   (or `dl`).
+    /// - `dir/leaf.jpg.html` is a frame around a photo, and takes the same
+    ///   parameters as a resized photo, `order`, `min_rating` and
+    ///   `slideshow`.
+    /// - `dir/leaf.jpg.thumb` is a thumbnail, and takes no parameters.
+    /// - `dir/preview.gif`, unless it exists, is a slideshow, and takes `delay`
+    ///   and `order`.
+    /// - `dir/photo-of-the-day`, unless it exists, is like `photo-of-the-day`
+    ///   but only for `dir`.
+    /// - Anything else in `dir` is a static file, and takes only `download`
+    ///   (or `dl`).
+    ///
+    /// Unrecognised parameters are ignored.
+    fn dispatch(
+        &self,
+        route: Route,
+        params: &Params,
+        headers: Headers,
+        tokens: &[&str],
+    ) -> Result<HttpOkay, HttpError> {
+        let route = match route {
+            // A real file takes precedence over a generated one.
+            Route::Slideshow(dir) if self.document_root.join(&dir).join(SLIDESHOW_NAME).exists() => {
+                Route::Static {dir, leaf: SLIDESHOW_NAME.into()}
+            },
+            Route::PhotoOfTheDay(Some(dir)) if self.document_root.join(&dir).join(PHOTO_OF_THE_DAY_NAME).exists() => {
+                Route::Static {dir, leaf: PHOTO_OF_THE_DAY_NAME.into()}
+            },
+            Route::Favicon if self.document_root.join(FAVICON_NAME).is_file() => {
+                Route::Static {dir: String::new(), leaf: FAVICON_NAME.into()}
+            },
+            Route::Robots if self.document_root.join(ROBOTS_NAME).is_file() => {
+                Route::Static {dir: String::new(), leaf: ROBOTS_NAME.into()}
+            },
+            route => route,
+        };
+        match route {
+            Route::Manifest => self.manifest(params, tokens),
+            Route::Sitemap => self.sitemap(params),
+            Route::Favicon => Ok(HttpOkay::Stream {
+                reader: Box::new(FAVICON),
+                content_type: content_type(FAVICON_NAME).into(),
+                length: Some(FAVICON.len() as u64),
+            }.with_header("Cache-Control", "public, max-age=86400")),
+            Route::Robots => Ok(HttpOkay::Text(self.robots_txt.clone())),
+            Route::PhotoOfTheDay(dir) => self.photo_of_the_day(dir.as_deref(), params, tokens),
+            Route::Index(dir) => self.index(&dir, params, |path| check_token(path, tokens)),
+            Route::Zip(dir) => self.zip(&dir, params, headers),
+            Route::Listing(dir) => self.listing(&dir, params, tokens),
+            Route::CacheList {dir, leaf} => self.cache_list(&dir, &leaf, headers),
+            Route::Rescale {dir, leaf} => self.negotiate_rescale(&dir, &leaf, params, headers),
+            Route::Original {dir, leaf} => self.original(&dir, &leaf, params, headers),
+            Route::Frame {dir, leaf} => self.frame(&dir, &leaf, params),
+            Route::Thumb {dir, leaf} => self.thumb(&dir, &leaf, params, headers),
+            Route::Slideshow(dir) => self.slideshow(&dir, params),
+            Route::Static {dir, leaf} => self.static_file(&dir, &leaf, params, headers),
         }
     }
 }
@@ -301,22 +3298,1186 @@ impl<'a> Handler for PhotoServer<'a> {
 // ----------------------------------------------------------------------------
 
 /// Where the photo albums are.
-const DOCUMENT_ROOT: &'static str = "./document_root";
+const DOCUMENT_ROOT: &str = "./document_root";
 
 /// Where we can cache thumbnails.
-const THUMBNAIL_ROOT: &'static str = "./thumbnail_root";
+const THUMBNAIL_ROOT: &str = "./thumbnail_root";
+
+/// Used to interpret `Config::new_days`.
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
 
 /// The default server address and port to listen on.
-const SERVER_ADDRESS: &'static str = "127.0.0.1:8082";
+const SERVER_ADDRESS: &str = "127.0.0.1:8082";
+
+/// Set the OS scheduling priority ("niceness") of the calling thread, and
+/// of any threads it subsequently spawns.
+///
+/// This is a best-effort hint. It is only implemented on Unix, and some
+/// systems apply it to the whole process.
+#[cfg(unix)]
+fn set_niceness(niceness: i32) -> std::io::Result<()> {
+    // SAFETY: `setpriority()` has no memory-safety preconditions.
+    if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, niceness) } == 0 {
+        Ok(())
+    } else {
+        Err(std::io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn set_niceness(_niceness: i32) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Make SIGINT and SIGTERM set `server::STOP`, so that the server finishes
+/// the requests in progress and exits. A second signal exits immediately.
+#[cfg(unix)]
+fn handle_signals() {
+    extern "C" fn on_signal(signal: libc::c_int) {
+        if server::STOP.swap(true, std::sync::atomic::Ordering::Relaxed) {
+            // SAFETY: `_exit()` is async-signal-safe.
+            unsafe { libc::_exit(128 + signal); }
+        }
+    }
+    let on_signal: extern "C" fn(libc::c_int) = on_signal;
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        // SAFETY: `on_signal()` only does async-signal-safe things.
+        unsafe { libc::signal(signal, on_signal as libc::sighandler_t); }
+    }
+}
+
+#[cfg(not(unix))]
+fn handle_signals() {}
 
 fn main() {
+    let config = Config::from_command_line();
+    #[cfg(feature = "heif")]
+    libheif_rs::integration::image::register_all_decoding_hooks();
+    // Lower our priority before spawning any threads, so that they inherit it.
+    if let Some(niceness) = config.niceness {
+        match set_niceness(niceness) {
+            Ok(()) => println!("Running at niceness {}", niceness),
+            Err(e) => println!("Could not set niceness {}: {}", niceness, e),
+        }
+    }
     // Application-specific part.
-    let document_root = env::var("PHOTO_SERVER_DOCUMENT_ROOT").unwrap_or_else(|_| DOCUMENT_ROOT.to_owned());
-    let thumbnail_root = env::var("PHOTO_SERVER_THUMBNAIL_ROOT").unwrap_or_else(|_| THUMBNAIL_ROOT.to_owned());
-    let photo_server = PhotoServer::new(&document_root, &thumbnail_root);
-    // Web server part.
-    let server_address = env::var("PHOTO_SERVER_ADDRESS").unwrap_or_else(|_| SERVER_ADDRESS.to_owned());
-    let base_url = env::var("PHOTO_SERVER_BASE_URL").ok();
-    // Run for ever!
-    server::start(server_address, base_url, photo_server);
+    let document_root = config.document_root.unwrap_or_else(|| DOCUMENT_ROOT.to_owned());
+    let thumbnail_root = config.thumbnail_root.unwrap_or_else(|| THUMBNAIL_ROOT.to_owned());
+    let server_address = config.address.unwrap_or_else(|| SERVER_ADDRESS.to_owned());
+    let base_url = config.base_url.clone().unwrap_or_else(|| format!("http://{}/", server_address));
+    let parsed_base_url = Url::parse(&base_url).expect("Could not parse the base URL");
+    let mut photo_server = PhotoServer::new(&document_root, &thumbnail_root, parsed_base_url);
+    photo_server.new_window = config.new_days.map(
+        |days| Duration::from_secs(u64::from(days) * SECONDS_PER_DAY)
+    );
+    photo_server.versioned_urls = config.versioned_urls.unwrap_or(false);
+    photo_server.client_hints = config.client_hints.unwrap_or(false);
+    photo_server.cap_originals = config.cap_originals.unwrap_or(false);
+    photo_server.preload_images = config.preload_images.unwrap_or(false);
+    photo_server.negotiate_format = config.negotiate_format.unwrap_or(false);
+    if let Some(avif_min_dimension) = config.avif_min_dimension { photo_server.avif_min_dimension = avif_min_dimension; }
+    if photo_server.negotiate_format && !cfg!(feature = "avif") {
+        println!("Warning: negotiate_format has no effect without the avif feature");
+    }
+    photo_server.footer_text = config.footer_text;
+    photo_server.footer_url = config.footer_url;
+    if let Some(robots_txt) = config.robots_txt { photo_server.robots_txt = robots_txt; }
+    photo_server.admin_token = config.admin_token;
+    photo_server.cache_version = config.cache_version.unwrap_or(0);
+    photo_server.jpeg_quality = config.jpeg_quality.unwrap_or(85).clamp(1, 100) as u8;
+    photo_server.thumbnail_quality = config.thumbnail_quality.unwrap_or(75).clamp(1, 100) as u8;
+    photo_server.thumbnail_dimensions = Dimensions {
+        w: config.thumb_width.unwrap_or(THUMBNAIL_DIMENSIONS.w).clamp(1, 1024),
+        h: config.thumb_height.unwrap_or(THUMBNAIL_DIMENSIONS.h).clamp(1, 1024),
+    };
+    photo_server.max_dimension = config.max_dimension.unwrap_or(MAX_DIMENSION).clamp(1, 16384);
+    photo_server.default_dimensions = Dimensions {
+        w: config.default_width.unwrap_or(DEFAULT_DIMENSIONS.w).max(1),
+        h: config.default_height.unwrap_or(DEFAULT_DIMENSIONS.h).max(1),
+    };
+    if let Some(readme_names) = config.readme_names { photo_server.readme_names = readme_names; }
+    if let Some(max_readme_bytes) = config.max_readme_bytes { photo_server.max_readme_bytes = max_readme_bytes.into(); }
+    photo_server.max_others = config.max_others.unwrap_or(200) as usize;
+    photo_server.referer_check = config.referer_check.unwrap_or(false);
+    photo_server.allow_empty_referer = config.allow_empty_referer.unwrap_or(true);
+    photo_server.include_unrated = config.include_unrated.unwrap_or(false);
+    if let Some(max_zip_bytes) = config.max_zip_bytes { photo_server.max_zip_bytes = max_zip_bytes.into(); }
+    if config.generate_thumbnails_only == Some(true) {
+        let status = match photo_server.precache(None) {
+            Ok(0) => 0,
+            Ok(_) => 1,
+            Err(e) => { println!("Precache: Error: {}", e); 1 },
+        };
+        std::process::exit(status);
+    }
+    if let Some(output) = &config.export_to {
+        let status = match photo_server.export(Path::new(output)) {
+            Ok(0) => 0,
+            Ok(_) => 1,
+            Err(e) => { println!("Export: Error: {}", e); 1 },
+        };
+        std::process::exit(status);
+    }
+    let credentials = match (config.auth_user, config.auth_password) {
+        (Some(user), Some(password)) => Some((user, password)),
+        (None, None) => None,
+        _ => panic!("auth_user and auth_password must be set together"),
+    };
+    let max_body_bytes = config.max_body_bytes.map_or(MAX_BODY_BYTES, u64::from);
+    let log_format = config.log_format.as_deref().map_or(server::LogFormat::Plain, |s| {
+        server::LogFormat::parse(s).unwrap_or_else(|| panic!("Unknown log_format {:?}", s))
+    });
+    let threads = config.threads.map_or_else(
+        || thread::available_parallelism().map_or(1, std::num::NonZero::get),
+        |threads| threads.max(1) as usize,
+    );
+    let max_requests = config.max_requests.map_or(MAX_REQUESTS, |max_requests| max_requests.max(1) as usize);
+    // If the port is 0, the default `base_url` depends on the port chosen.
+    let listener = server::Listener::new(&server_address);
+    if config.base_url.is_none() { photo_server.base_url = listener.url().clone(); }
+    handle_signals();
+    // Run until stopped by a signal.
+    thread::scope(|scope| {
+        let photo_server = &photo_server;
+        scope.spawn(move || photo_server.maintain(config.precache_minutes));
+        // Web server part.
+        server::start(listener, config.base_url, threads, max_requests, credentials, max_body_bytes, log_format, photo_server);
+        // Don't wait for `maintain()`, which never returns.
+        std::process::exit(0);
+    });
+}
+
+// ----------------------------------------------------------------------------
+
+#[cfg(test)]
+mod tests {
+    use std::path::{PathBuf};
+
+    use super::*;
+    use server::{Listener};
+    use server::testing::{TestServer};
+
+    /// A fresh directory, deleted when this is dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> Self {
+            static COUNTER: AtomicU64 = AtomicU64::new(0);
+            let name = format!("photo_server-test-{}-{}", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed));
+            let path = std::env::temp_dir().join(name);
+            std::fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+
+        /// Returns the path of `name` in this directory.
+        fn join(&self, name: &str) -> PathBuf { self.0.join(name) }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) { let _ = std::fs::remove_dir_all(&self.0); }
+    }
+
+    /// Write a `w` by `h` JPEG photo to `path`, creating its directory.
+    fn write_jpeg(path: &Path, w: u32, h: u32) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let image = image::RgbImage::from_fn(w, h, |x, y| image::Rgb([(x * 255 / w) as u8, (y * 255 / h) as u8, 128]));
+        image.save(path).unwrap();
+    }
+
+    /// Like `write_jpeg()`, with an EXIF capture date, e.g.
+    /// `"2019:06:15 12:00:00"`.
+    fn write_jpeg_taken(path: &Path, w: u32, h: u32, taken: &str) {
+        write_jpeg(path, w, h);
+        // A little-endian TIFF with an IFD0 pointing to an EXIF IFD.
+        let mut tiff = b"II*\0".to_vec();
+        let u16 = |tiff: &mut Vec<u8>, x: u16| tiff.extend(x.to_le_bytes());
+        let u32 = |tiff: &mut Vec<u8>, x: u32| tiff.extend(x.to_le_bytes());
+        u32(&mut tiff, 8);
+        u16(&mut tiff, 1);
+        for x in [EXIF_IFD, 4] { u16(&mut tiff, x); }
+        for x in [1, 26, 0] { u32(&mut tiff, x); }
+        u16(&mut tiff, 1);
+        for x in [EXIF_DATE_TIME_ORIGINAL, 2] { u16(&mut tiff, x); }
+        for x in [taken.len() as u32 + 1, 44, 0] { u32(&mut tiff, x); }
+        tiff.extend(taken.as_bytes());
+        tiff.push(0);
+        let jpeg = std::fs::read(path).unwrap();
+        let mut data = jpeg[..2].to_vec();
+        data.extend([0xFF, 0xE1]);
+        data.extend((2 + 6 + tiff.len() as u16).to_be_bytes());
+        data.extend(b"Exif\0\0");
+        data.extend(tiff);
+        data.extend(&jpeg[2..]);
+        std::fs::write(path, data).unwrap();
+    }
+
+    /// Returns the names of the entries in the ZIP archive `data`, from its
+    /// central directory.
+    fn zip_names(data: &[u8]) -> Vec<String> {
+        let u16 = |offset: usize| usize::from(u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap()));
+        let u32 = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+        let end = data.len() - 22;
+        let mut offset = u32(end + 16);
+        let mut ret = Vec::new();
+        for _ in 0..u16(end + 10) {
+            let name_length = u16(offset + 28);
+            ret.push(String::from_utf8(data[offset + 46..offset + 46 + name_length].to_vec()).unwrap());
+            offset += 46 + name_length + u16(offset + 30) + u16(offset + 32);
+        }
+        ret
+    }
+
+    /// A `PhotoServer` for `root`, which serves `root/docs` and caches in
+    /// `root/thumbs`, configured by `configure`, on an ephemeral port.
+    fn serve(root: &TempDir, configure: impl FnOnce(&mut PhotoServer<'static>)) -> TestServer {
+        let leak = |path: PathBuf| &*String::leak(path.into_os_string().into_string().unwrap());
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        let listener = Listener::new("127.0.0.1:0");
+        let mut photo_server = PhotoServer::new(leak(root.join("docs")), leak(root.join("thumbs")), listener.url().clone());
+        configure(&mut photo_server);
+        TestServer::start(listener, photo_server)
+    }
+
+    /// Parse a query string as `Server` does, dropping empty parameters.
+    fn params(query: &str) -> Params {
+        url::form_urlencoded::parse(query.as_bytes()).filter(
+            |(key, value)| !key.is_empty() && !value.is_empty()
+        ).map(
+            |(key, value)| (key.into_owned(), value.into_owned())
+        ).collect()
+    }
+
+    /// Split a URL path as `Server` does, dropping empty segments.
+    fn segments(path: &str) -> Vec<String> {
+        path.split('/').filter(|s| !s.is_empty()).map(String::from).collect()
+    }
+
+    #[test]
+    fn avif_min_dimension() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 640, 480);
+        let server = serve(&root, |photo_server| { photo_server.negotiate_format = true; });
+        let accept = [("Accept", "image/avif,image/*")];
+        // Small photos stay JPEG, whatever the client accepts.
+        let small = server.get("/album/a.jpg?w=256&h=200", &accept);
+        assert_eq!(small.status, 200);
+        assert_eq!(small.header("Content-Type"), Some("image/jpeg"));
+        assert_eq!(small.header("Vary"), None);
+        let large = server.get("/album/a.jpg?w=257&h=200", &accept);
+        assert_eq!(large.status, 200);
+        if cfg!(feature = "avif") {
+            assert_eq!(large.header("Content-Type"), Some("image/avif"));
+            assert_eq!(large.header("Vary"), Some("Accept"));
+        } else {
+            assert_eq!(large.header("Content-Type"), Some("image/jpeg"));
+        }
+    }
+
+    #[test]
+    fn zip_date_range() {
+        let root = TempDir::new();
+        write_jpeg_taken(&root.join("docs/album/a.jpg"), 40, 30, "2019:05:31 23:59:59");
+        write_jpeg_taken(&root.join("docs/album/b.jpg"), 40, 30, "2019:06:01 00:00:00");
+        write_jpeg_taken(&root.join("docs/album/c.jpg"), 40, 30, "2019:06:30 12:00:00");
+        write_jpeg(&root.join("docs/album/d.jpg"), 40, 30);
+        std::fs::write(root.join("docs/album/notes.txt"), "Notes").unwrap();
+        let server = serve(&root, |_| {});
+        let names = |query: &str| {
+            let response = server.get(&format!("/album/?download=zip{}", query), &[]);
+            assert_eq!(response.status, 200);
+            assert_eq!(response.header("Content-Type"), Some("application/zip"));
+            zip_names(&response.body)
+        };
+        assert_eq!(names(""), ["a.jpg", "b.jpg", "c.jpg", "d.jpg", "notes.txt"]);
+        // Inclusive, and only photos with capture dates.
+        assert_eq!(names("&since=2019-06-01&until=2019-06-30"), ["b.jpg", "c.jpg"]);
+        assert_eq!(names("&until=2019-06-01"), ["a.jpg", "b.jpg"]);
+        assert_eq!(names("&since=2019-06-02"), ["c.jpg"]);
+        // Nothing matches.
+        assert_eq!(server.get("/album/?download=zip&since=2020-01-01", &[]).status, 404);
+        assert_eq!(server.get("/album/?download=zip&since=2019-07-01&until=2019-06-01", &[]).status, 404);
+        // Malformed.
+        for date in ["2019-6-1", "2019-13-01", "2019-06-32", "20190601", "yesterday", "2019-06-01T00:00"] {
+            assert_eq!(server.get(&format!("/album/?download=zip&since={}", date), &[]).status, 400, "{}", date);
+            assert_eq!(server.get(&format!("/album/?download=zip&until={}", date), &[]).status, 400, "{}", date);
+        }
+    }
+
+    #[test]
+    fn route_parse() {
+        let root = TempDir::new();
+        std::fs::create_dir_all(root.join("2023/summer")).unwrap();
+        let route = |url: &str| {
+            let (path, query) = url.split_once('?').unwrap_or((url, ""));
+            Route::parse(&segments(path), &params(query), &root.0).ok()
+        };
+        let file = |dir: &str, leaf: &str| (String::from(dir), String::from(leaf));
+        // Albums, with or without trailing and repeated slashes.
+        for url in ["/album/", "/album", "//album//"] {
+            assert_eq!(route(url), Some(Route::Index("album".into())), "{}", url);
+        }
+        assert_eq!(route("/2023/summer/"), Some(Route::Index("2023/summer".into())));
+        assert_eq!(route("/2023/summer"), Some(Route::Index("2023/summer".into())));
+        assert_eq!(route("/album/?download=zip"), Some(Route::Zip("album".into())));
+        assert_eq!(route("/album/?format=json"), Some(Route::Listing("album".into())));
+        // Photos.
+        let (dir, leaf) = file("album", "a.jpg");
+        assert_eq!(route("/album/a.jpg"), Some(Route::Original {dir: dir.clone(), leaf: leaf.clone()}));
+        for query in ["w=100", "h=100", "flip=h", "mode=crop", "fmt=png", "q=50"] {
+            let url = format!("/album/a.jpg?{}", query);
+            assert_eq!(route(&url), Some(Route::Rescale {dir: dir.clone(), leaf: leaf.clone()}), "{}", url);
+        }
+        // An empty parameter is absent.
+        assert_eq!(route("/album/a.jpg?w="), Some(Route::Original {dir: dir.clone(), leaf: leaf.clone()}));
+        assert_eq!(route("/album/a.jpg?cache=list&w=100"), Some(Route::CacheList {dir: dir.clone(), leaf: leaf.clone()}));
+        assert_eq!(route("/album/a.jpg.html"), Some(Route::Frame {dir: dir.clone(), leaf: leaf.clone()}));
+        assert_eq!(route("/album/a.jpg.thumb"), Some(Route::Thumb {dir: dir.clone(), leaf: leaf.clone()}));
+        let (dir, leaf) = file("2023/summer", "b.JPG");
+        assert_eq!(route("/2023/summer/b.JPG.html"), Some(Route::Frame {dir, leaf}));
+        // Other files, including the frames of non-photos.
+        for (url, dir, leaf) in [
+            ("/album/doc.pdf", "album", "doc.pdf"),
+            ("/album/doc.pdf.html", "album", "doc.pdf.html"),
+            ("/album/doc.pdf?w=100", "album", "doc.pdf"),
+            ("/2023/winter", "2023", "winter"),
+        ] {
+            let (dir, leaf) = file(dir, leaf);
+            assert_eq!(route(url), Some(Route::Static {dir, leaf}), "{}", url);
+        }
+        // Special names.
+        assert_eq!(route("/album/preview.gif"), Some(Route::Slideshow("album".into())));
+        assert_eq!(route("/album/photo-of-the-day"), Some(Route::PhotoOfTheDay(Some("album".into()))));
+        assert_eq!(route("/photo-of-the-day"), Some(Route::PhotoOfTheDay(None)));
+        assert_eq!(route("/manifest.json"), Some(Route::Manifest));
+        assert_eq!(route("/sitemap.xml"), Some(Route::Sitemap));
+        assert_eq!(route("/favicon.ico"), Some(Route::Favicon));
+        assert_eq!(route("/robots.txt"), Some(Route::Robots));
+        // Invalid paths.
+        for url in ["/", "", "/al bum/", "/album/a%20b.jpg", "/album/../a.jpg"] {
+            assert_eq!(route(url), None, "{}", url);
+        }
+    }
+
+    #[test]
+    fn dms_to_degrees_signs() {
+        let close = |degrees: Option<f64>, expected: f64| (degrees.unwrap() - expected).abs() < 1e-9;
+        // The Eiffel Tower: 48° 51' 29.6" N, 2° 17' 40.2" E.
+        let latitude = [(48, 1), (51, 1), (296, 10)];
+        let longitude = [(2, 1), (17, 1), (402, 10)];
+        assert!(close(dms_to_degrees(&latitude, "N"), 48.0 + 51.0 / 60.0 + 29.6 / 3600.0));
+        assert!(close(dms_to_degrees(&longitude, "E"), 2.0 + 17.0 / 60.0 + 40.2 / 3600.0));
+        // Rio de Janeiro: 22° 54.41' S, 43° 12.6' W, in decimal minutes.
+        assert!(close(dms_to_degrees(&[(22, 1), (5441, 100), (0, 1)], "S"), -(22.0 + 54.41 / 60.0)));
+        assert!(close(dms_to_degrees(&[(43, 1), (126, 10), (0, 1)], "W "), -(43.0 + 12.6 / 60.0)));
+        // Malformed values.
+        assert_eq!(dms_to_degrees(&[(48, 1), (51, 0), (0, 1)], "N"), None);
+        assert_eq!(dms_to_degrees(&[], "N"), None);
+        assert_eq!(dms_to_degrees(&[(48, 1), (51, 1)], "N"), None);
+        assert_eq!(dms_to_degrees(&latitude, "X"), None);
+    }
+
+    #[test]
+    fn cache_kinds() {
+        let resized = |w, h| Some(CacheKind::Resized(Dimensions {w, h}));
+        assert_eq!(cache_kind_of("a.jpg", "a.jpg"), Some(CacheKind::Thumbnail(1)));
+        assert_eq!(cache_kind_of("a.jpg@2x", "a.jpg"), Some(CacheKind::Thumbnail(2)));
+        assert_eq!(cache_kind_of("a.jpg@300x600-q85.jpeg", "a.jpg"), resized(300, 600));
+        assert_eq!(cache_kind_of("a.jpg@300x600-h-crop-q50.webp", "a.jpg"), resized(300, 600));
+        assert_eq!(cache_kind_of("a.jpg@30x60-v-pad00ff00-q85.png", "a.jpg"), resized(30, 60));
+        for name in ["b.jpg", "a.jpg.tmp", "a.jpg@300x600-q85.jpeg.tmp", "a.jpg@300x600.jpeg", "a.jpg@x", "a.jpgx"] {
+            assert_eq!(cache_kind_of(name, "a.jpg"), None, "{}", name);
+        }
+    }
+
+    #[test]
+    fn cache_list() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/sub/b.jpg"), 40, 30);
+        let server = serve(&root, |photo_server| { photo_server.admin_token = Some("secret".into()); });
+        let admin = [("Authorization", "Bearer secret")];
+        let list = || {
+            let response = server.get("/album/sub/b.jpg?cache=list", &admin);
+            assert_eq!(response.status, 200);
+            serde_json::from_slice::<serde_json::Value>(&response.body).unwrap()
+        };
+        assert_eq!(list(), serde_json::json!([]));
+        assert_eq!(server.get("/album/sub/b.jpg.thumb", &[]).status, 200);
+        assert_eq!(server.get("/album/sub/b.jpg?w=20&h=20&fmt=png", &[]).status, 200);
+        let list = list();
+        let list = list.as_array().unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0]["kind"], "thumbnail");
+        assert_eq!(list[0]["format"], "jpeg");
+        assert_eq!(list[0]["scale"], 1);
+        assert_eq!(list[1]["name"], "b.jpg@20x20-q85.png");
+        assert_eq!(list[1]["kind"], "resized");
+        assert_eq!(list[1]["format"], "png");
+        assert_eq!(list[1]["requested"], serde_json::json!({"w": 20, "h": 20}));
+        assert_eq!((list[1]["width"].as_u64(), list[1]["height"].as_u64()), (Some(20), Some(15)));
+        // Only for administrators.
+        assert_eq!(server.get("/album/sub/b.jpg?cache=list", &[]).status, 404);
+    }
+
+    /// Encode `files`, each `(name, data)`, as a `multipart/form-data` body
+    /// with boundary `BOUNDARY`.
+    fn multipart_body(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        for (name, data) in files {
+            ret.extend_from_slice(format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"photo\"; filename=\"{}\"\r\n\r\n", BOUNDARY, name,
+            ).as_bytes());
+            ret.extend_from_slice(data);
+            ret.extend_from_slice(b"\r\n");
+        }
+        ret.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+        ret
+    }
+
+    const BOUNDARY: &str = "XyZzY";
+
+    #[test]
+    fn upload() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        write_jpeg(&root.join("new.jpg"), 20, 10);
+        let jpeg = std::fs::read(root.join("new.jpg")).unwrap();
+        let server = serve(&root, |photo_server| { photo_server.admin_token = Some("secret".into()); });
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        let post = |url: &str, files: &[(&str, &[u8])]| server.request(
+            "POST", url, &[("Authorization", "Bearer secret"), ("Content-Type", &content_type)], &multipart_body(files),
+        ).status;
+        // Photos must be JPEG files whose names end `.jpg`.
+        assert_eq!(post("/album/", &[("b.jpeg", &jpeg)]), 400);
+        assert_eq!(post("/album/", &[("b.jpg", b"GIF89a")]), 400);
+        assert_eq!(post("/album/", &[("b.jpg", &jpeg), ("c.png", &jpeg)]), 400);
+        assert_eq!(post("/album/", &[(".tmp-1-1-b.jpg", &jpeg)]), 400);
+        assert!(!root.join("docs/album/b.jpg").exists());
+        assert_eq!(post("/album/", &[("b.jpg", &jpeg), ("Photos/c.JPG", &jpeg)]), 200);
+        assert_eq!(std::fs::read(root.join("docs/album/b.jpg")).unwrap(), jpeg);
+        let listing: serde_json::Value = serde_json::from_slice(&server.get("/album/?format=json", &[]).body).unwrap();
+        let names: Vec<_> = listing["images"].as_array().unwrap().iter().map(|image| image["name"].clone()).collect();
+        assert_eq!(names, ["a.jpg", "b.jpg", "c.JPG"]);
+        assert_eq!(listing["others"], serde_json::json!([]));
+        // Only into albums.
+        assert_eq!(post("/album/a.jpg", &[("b.jpg", &jpeg)]), 405);
+        assert_eq!(post("/nowhere/", &[("b.jpg", &jpeg)]), 404);
+    }
+
+    #[test]
+    fn upload_if_match() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        write_jpeg(&root.join("new.jpg"), 20, 10);
+        let jpeg = std::fs::read(root.join("new.jpg")).unwrap();
+        let server = serve(&root, |photo_server| { photo_server.admin_token = Some("secret".into()); });
+        let content_type = format!("multipart/form-data; boundary={}", BOUNDARY);
+        let post = |files: &[(&str, &[u8])], if_match: &str| server.request(
+            "POST", "/album/",
+            &[("Authorization", "Bearer secret"), ("Content-Type", &content_type), ("If-Match", if_match)],
+            &multipart_body(files),
+        ).status;
+        let etag = server.get("/album/a.jpg", &[]).header("ETag").unwrap().to_owned();
+        // Someone else replaces the photo.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_jpeg(&root.join("docs/album/a.jpg"), 30, 40);
+        let theirs = std::fs::read(root.join("docs/album/a.jpg")).unwrap();
+        assert_eq!(post(&[("a.jpg", &jpeg)], &etag), 412);
+        assert_eq!(std::fs::read(root.join("docs/album/a.jpg")).unwrap(), theirs);
+        // With the current tag, the upload succeeds, and changes the tag.
+        let etag = server.get("/album/a.jpg", &[]).header("ETag").unwrap().to_owned();
+        assert_eq!(post(&[("a.jpg", &jpeg)], &etag), 200);
+        assert_eq!(std::fs::read(root.join("docs/album/a.jpg")).unwrap(), jpeg);
+        assert_ne!(server.get("/album/a.jpg", &[]).header("ETag"), Some(etag.as_str()));
+        // A new photo matches nothing, not even `*`.
+        assert_eq!(post(&[("a.jpg", &jpeg), ("b.jpg", &jpeg)], "*"), 412);
+        assert!(!root.join("docs/album/b.jpg").exists());
+        assert_eq!(post(&[("a.jpg", &jpeg)], "*"), 200);
+    }
+
+    #[test]
+    fn temporary_files_are_hidden() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        std::fs::write(root.join("docs/album/.tmp-1-2-b.jpg"), b"partial").unwrap();
+        let server = serve(&root, |_| {});
+        let listing: serde_json::Value = serde_json::from_slice(&server.get("/album/?format=json", &[]).body).unwrap();
+        assert_eq!(listing["images"].as_array().unwrap().len(), 1);
+        assert_eq!(listing["others"], serde_json::json!([]));
+        assert_eq!(server.get("/album/.tmp-1-2-b.jpg", &[]).status, 404);
+    }
+
+    #[test]
+    fn concurrent_atomic_writes() {
+        let root = TempDir::new();
+        let file_name = root.join("file");
+        let contents: Vec<Vec<u8>> = (0..8u8).map(|i| vec![i; 100_000]).collect();
+        std::thread::scope(|scope| {
+            for data in &contents {
+                let file_name = &file_name;
+                let contents = &contents;
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        write_atomically(file_name, data).unwrap();
+                        let read = std::fs::read(file_name).unwrap();
+                        assert!(contents.contains(&read), "A partial or mixed file");
+                    }
+                });
+            }
+        });
+        // No temporary files are left behind.
+        assert_eq!(std::fs::read_dir(&root.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn index_lists_photos() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        let response = server.get("/album/", &[]);
+        assert_eq!(response.status, 200);
+        assert!(response.text().contains("a.jpg.html"));
+        // Absolute URLs use the port chosen by the OS.
+        let manifest: serde_json::Value = serde_json::from_slice(&server.get("/manifest.json", &[]).body).unwrap();
+        assert_eq!(manifest["url"], server.url.as_str());
+    }
+
+    #[test]
+    fn zero_dimensions() {
+        let params: Params = [("w".into(), "0".into()), ("h".into(), "0".into())].into_iter().collect();
+        assert_eq!(params.get_dimensions(), Dimensions {w: 1, h: 1});
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        for query in ["w=0&mode=pad", "h=0&mode=pad", "w=0&h=0&mode=pad", "w=0&mode=crop", "w=0"] {
+            let response = server.get(&format!("/album/a.jpg?{}", query), &[]);
+            assert_eq!(response.status, 200, "{}", query);
+            let image = image::load_from_memory(&response.body).unwrap();
+            assert!(image.width() >= 1 && image.height() >= 1);
+        }
+    }
+
+    #[test]
+    fn frame_of_non_photo() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        std::fs::write(root.join("docs/album/document.pdf"), b"%PDF-1.4").unwrap();
+        std::fs::write(root.join("docs/album/page.html"), "<p>Hello</p>").unwrap();
+        let server = serve(&root, |_| {});
+        assert_eq!(server.get("/album/a.jpg.html", &[]).status, 200);
+        assert_eq!(server.get("/album/missing.jpg.html", &[]).status, 404);
+        assert_eq!(server.get("/album/document.pdf.html", &[]).status, 404);
+        // A real HTML file is served as it is.
+        let page = server.get("/album/page.html", &[]);
+        assert_eq!(page.status, 200);
+        assert_eq!(page.text(), "<p>Hello</p>");
+    }
+
+    #[test]
+    fn undecodable_photo() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        // Partially written.
+        let jpeg = std::fs::read(root.join("docs/album/a.jpg")).unwrap();
+        std::fs::write(root.join("docs/album/b.jpg"), &jpeg[..jpeg.len() / 4]).unwrap();
+        std::fs::write(root.join("docs/album/c.jpg"), b"Not a photo").unwrap();
+        let server = serve(&root, |_| {});
+        assert_eq!(server.get("/album/a.jpg.thumb", &[]).status, 200);
+        for name in ["b.jpg", "c.jpg"] {
+            assert_eq!(server.get(&format!("/album/{}.thumb", name), &[]).status, 415, "{}", name);
+            assert_eq!(server.get(&format!("/album/{}?w=20", name), &[]).status, 415, "{}", name);
+            // The file itself can still be fetched.
+            assert_eq!(server.get(&format!("/album/{}", name), &[]).status, 200, "{}", name);
+        }
+    }
+
+    #[test]
+    fn static_file_download() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        std::fs::write(root.join("docs/album/notes.txt"), "Notes").unwrap();
+        let server = serve(&root, |_| {});
+        let notes = server.get("/album/notes.txt", &[]);
+        assert_eq!(notes.text(), "Notes");
+        assert_eq!(notes.header("Content-Disposition"), None);
+        for query in ["?download=1", "?download=yes", "?dl=true"] {
+            let notes = server.get(&format!("/album/notes.txt{}", query), &[]);
+            assert_eq!(notes.text(), "Notes");
+            assert_eq!(notes.header("Content-Disposition"), Some("attachment; filename=\"notes.txt\""), "{}", query);
+        }
+        assert_eq!(server.get("/album/notes.txt?download=0", &[]).header("Content-Disposition"), None);
+        // Photos without size parameters are static files too.
+        let original = server.get("/album/a.jpg?download=1", &[]);
+        assert_eq!(original.body, std::fs::read(root.join("docs/album/a.jpg")).unwrap());
+        assert_eq!(original.header("Content-Disposition"), Some("attachment; filename=\"a.jpg\""));
+        // Parameters that do not apply are ignored.
+        let notes = server.get("/album/notes.txt?w=20&sort=date&bogus=1", &[]);
+        assert_eq!(notes.status, 200);
+        assert_eq!(notes.text(), "Notes");
+    }
+
+    /// Returns the files in `dir` and its subdirectories.
+    fn files_in(dir: &Path) -> Vec<PathBuf> {
+        let mut ret = Vec::new();
+        for dir_entry in std::fs::read_dir(dir).unwrap() {
+            let path = dir_entry.unwrap().path();
+            if path.is_dir() { ret.extend(files_in(&path)); } else { ret.push(path); }
+        }
+        ret
+    }
+
+    #[test]
+    fn cache_version() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 400, 300);
+        let urls = ["/album/a.jpg.thumb", "/album/a.jpg?w=200"];
+        let server = serve(&root, |_| {});
+        for url in urls { assert_eq!(server.get(url, &[]).status, 200); }
+        let thumb_etag = server.get(urls[0], &[]).header("ETag").unwrap().to_owned();
+        // Make the cached files stale, as if the image settings had changed.
+        let cached = files_in(&root.join("thumbs"));
+        assert_eq!(cached.len(), 2);
+        for path in &cached { std::fs::write(path, b"Stale").unwrap(); }
+        for url in urls { assert_eq!(server.get(url, &[]).body, b"Stale"); }
+        // Bumping the version regenerates them, in a new place.
+        let server = serve(&root, |photo_server| { photo_server.cache_version = 1; });
+        for url in urls { assert!(image::load_from_memory(&server.get(url, &[]).body).is_ok(), "{}", url); }
+        // Revalidation with an old entity tag fails.
+        assert_eq!(server.get(urls[0], &[("If-None-Match", &thumb_etag)]).status, 200);
+        assert_eq!(files_in(&root.join("thumbs")).len(), 4);
+        for path in cached { assert_eq!(std::fs::read(path).unwrap(), b"Stale"); }
+    }
+
+    #[test]
+    fn referer_check() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |photo_server| { photo_server.referer_check = true; });
+        let ours = format!("{}album/a.jpg.html", server.url);
+        let status = |url: &str, referer: Option<&str>| {
+            server.get(url, referer.map(|referer| ("Referer", referer)).as_slice()).status
+        };
+        for url in ["/album/a.jpg", "/album/a.jpg?download=1", "/album/?download=zip"] {
+            assert_eq!(status(url, Some(&ours)), 200, "{}", url);
+            assert_eq!(status(url, None), 200, "{}", url);
+            assert_eq!(status(url, Some("http://elsewhere.example/album/a.jpg.html")), 403, "{}", url);
+        }
+        // Only static files are protected.
+        assert_eq!(status("/album/a.jpg.thumb", Some("http://elsewhere.example/")), 200);
+        assert_eq!(status("/album/a.jpg?w=20", Some("http://elsewhere.example/")), 200);
+        let server = serve(&root, |photo_server| {
+            photo_server.referer_check = true;
+            photo_server.allow_empty_referer = false;
+        });
+        assert_eq!(server.get("/album/a.jpg", &[]).status, 403);
+    }
+
+    #[test]
+    fn query_with_round_trips() {
+        let query = "w=640&h=480&flip=h&mode=crop&bg=ff8000&fmt=png&q=70&order=date&order=desc&sort=size\
+            &min_rating=3&slideshow=5&per_page=20";
+        let original = params(query);
+        let count = |query: &str, key: &str| {
+            url::form_urlencoded::parse(query.strip_prefix('?').unwrap().as_bytes()).filter(|(k, _)| k == key).count()
+        };
+        // Every parameter that carries from page to page survives.
+        assert_eq!(params(original.query().strip_prefix('?').unwrap()), original);
+        // The key is set once, replacing any previous value.
+        let next = original.query_with("page", Some("2"));
+        assert_eq!(params(next.strip_prefix('?').unwrap()), Params {page: Some(2), ..original.clone()});
+        assert_eq!(count(&next, "page"), 1);
+        let wider = original.query_with("w", Some("800"));
+        assert_eq!(params(wider.strip_prefix('?').unwrap()), Params {w: Some(800), ..original.clone()});
+        assert_eq!(count(&wider, "w"), 1);
+        let unflipped = original.query_with("flip", None);
+        assert_eq!(params(unflipped.strip_prefix('?').unwrap()), Params {flip: None, ..original.clone()});
+        // Values are escaped.
+        let album = original.query_with("album", Some("a b&c=d"));
+        assert_eq!(params(album.strip_prefix('?').unwrap()).album.as_deref(), Some("a b&c=d"));
+        // Others do not carry.
+        let others = params("w=640&h=480&page=3&token=secret&download=zip&all=1&format=json&cache=list");
+        assert_eq!(others.query(), "?w=640&h=480");
+        // Defaults are made explicit.
+        assert_eq!(params("").query(), "?w=800&h=600");
+    }
+
+    #[test]
+    fn thumbnail_quality() {
+        let root = TempDir::new();
+        // Detailed, so that the quality makes a difference.
+        let image = image::RgbImage::from_fn(400, 300, |x, y| image::Rgb([(x * y % 251) as u8, (x ^ y) as u8, (x + y) as u8]));
+        std::fs::create_dir_all(root.join("docs/album")).unwrap();
+        image.save(root.join("docs/album/a.jpg")).unwrap();
+        let low_thumbs = serve(&root, |photo_server| {
+            photo_server.thumbnail_quality = 20;
+            photo_server.jpeg_quality = 95;
+        });
+        let high_thumbs = serve(&root, |photo_server| {
+            photo_server.thumbnail_quality = 95;
+            photo_server.jpeg_quality = 20;
+        });
+        let size = |server: &TestServer, url: &str| server.get(url, &[]).body.len();
+        assert!(size(&low_thumbs, "/album/a.jpg.thumb") < size(&high_thumbs, "/album/a.jpg.thumb"));
+        assert!(size(&low_thumbs, "/album/a.jpg?w=200") > size(&high_thumbs, "/album/a.jpg?w=200"));
+        // `q` overrides `jpeg_quality`, but not `thumbnail_quality`.
+        assert_eq!(
+            low_thumbs.get("/album/a.jpg?w=200&q=50", &[]).body,
+            high_thumbs.get("/album/a.jpg?w=200&q=50", &[]).body,
+        );
+        assert_eq!(low_thumbs.get("/album/a.jpg.thumb?q=95", &[]).body, low_thumbs.get("/album/a.jpg.thumb", &[]).body);
+    }
+
+    #[test]
+    fn album_changing_while_listed() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        write_jpeg(&root.join("churn.jpg"), 40, 30);
+        let jpeg = std::fs::read(root.join("churn.jpg")).unwrap();
+        let server = serve(&root, |_| {});
+        let album = root.join("docs/album");
+        std::thread::scope(|scope| {
+            let churn = scope.spawn(|| {
+                for _ in 0..200 {
+                    for i in 0..20 { std::fs::write(album.join(format!("b{}.jpg", i)), &jpeg).unwrap(); }
+                    for i in 0..20 { std::fs::remove_file(album.join(format!("b{}.jpg", i))).unwrap(); }
+                }
+            });
+            let mut listings = 0;
+            while listings < 10 || !churn.is_finished() {
+                listings += 1;
+                let listed = Album::new(&album, &[]).unwrap();
+                assert!(listed.jpegs.contains(&"a.jpg".to_owned()));
+                assert!(listed.jpegs.is_sorted());
+                let urls = ["/album/", "/album/?format=json", "/album/?sort=date", "/album/?sort=size", "/album/a.jpg.html", "/sitemap.xml"];
+                for url in urls {
+                    assert_eq!(server.get(url, &[]).status, 200, "{}", url);
+                }
+            }
+        });
+    }
+
+    #[test]
+    fn thumbnail_etag() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        let thumb = server.get("/album/a.jpg.thumb", &[]);
+        assert_eq!(thumb.status, 200);
+        let etag = thumb.header("ETag").unwrap().to_owned();
+        let revalidated = server.get("/album/a.jpg.thumb", &[("If-None-Match", &etag)]);
+        assert_eq!(revalidated.status, 304);
+        assert!(revalidated.body.is_empty());
+        assert_eq!(revalidated.header("ETag"), Some(etag.as_str()));
+        assert_eq!(server.get("/album/a.jpg.thumb", &[("If-None-Match", "\"other\"")]).status, 200);
+        // Replacing the photo changes the tag.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_jpeg(&root.join("docs/album/a.jpg"), 30, 40);
+        let thumb = server.get("/album/a.jpg.thumb", &[("If-None-Match", &etag)]);
+        assert_eq!(thumb.status, 200);
+        assert_ne!(thumb.header("ETag"), Some(etag.as_str()));
+        // So do the thumbnail settings.
+        let server = serve(&root, |photo_server| { photo_server.thumbnail_quality = 50; });
+        assert_ne!(server.get("/album/a.jpg.thumb", &[]).header("ETag"), thumb.header("ETag"));
+    }
+
+    #[test]
+    fn generation_lock() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 400, 300);
+        let photo_server = PhotoServer::new("unused", "unused", Url::parse("http://localhost/").unwrap());
+        // Callers with the same name take turns; others do not wait.
+        let [running, most_running, total_running] = [(); 3].map(|_| AtomicU64::new(0));
+        std::thread::scope(|scope| {
+            for i in 0..8 {
+                let (photo_server, running, most_running, total_running) = (&photo_server, &running, &most_running, &total_running);
+                scope.spawn(move || {
+                    let name = PathBuf::from(if i % 2 == 0 { "even" } else { "odd" });
+                    photo_server.with_generation_lock(&name, || {
+                        let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_running.fetch_max(now, Ordering::SeqCst);
+                        total_running.fetch_add(1, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(50));
+                        running.fetch_sub(1, Ordering::SeqCst);
+                    });
+                });
+            }
+        });
+        assert_eq!(total_running.into_inner(), 8);
+        assert_eq!(most_running.into_inner(), 2);
+        // Locks that nobody is using are forgotten.
+        assert!(photo_server.generating.lock().unwrap().is_empty());
+        // Concurrent requests for the same uncached photo all get it.
+        let server = serve(&root, |_| {});
+        let server = &server;
+        let bodies: Vec<_> = std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4).map(|_| scope.spawn(move || server.get("/album/a.jpg?w=300", &[]).body)).collect();
+            threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+        });
+        assert!(image::load_from_memory(&bodies[0]).is_ok());
+        assert!(bodies.iter().all(|body| *body == bodies[0]));
+    }
+
+    #[test]
+    fn error_formats() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        let page = server.get("/album/missing.jpg.html", &[("Accept", "text/html,*/*")]);
+        assert_eq!(page.status, 404);
+        assert_eq!(page.header("Content-Type"), Some("text/html"));
+        let json = server.get("/album/missing.jpg.html?format=json", &[("Accept", "text/html,*/*")]);
+        assert_eq!(json.status, 404);
+        assert_eq!(json.header("Content-Type"), Some("application/json"));
+        let json: serde_json::Value = serde_json::from_slice(&json.body).unwrap();
+        assert_eq!(json, serde_json::json!({"error": "Not found", "status": 404}));
+        let invalid = server.get("/album/..%2Fa.jpg", &[("Accept", "application/json")]);
+        assert_eq!(invalid.status, 400);
+        assert_eq!(invalid.header("Content-Type"), Some("application/json"));
+    }
+
+    #[test]
+    fn preload_link() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/sub/a-b_1.jpg"), 40, 30);
+        write_jpeg(&root.join("docs/album/sub/c.jpg"), 40, 30);
+        for versioned_urls in [false, true] {
+            let server = serve(&root, |photo_server| {
+                photo_server.preload_images = true;
+                photo_server.versioned_urls = versioned_urls;
+            });
+            let page_url = server.url.join("album/sub/a-b_1.jpg.html?w=640&h=480&mode=crop").unwrap();
+            let page = server.get(&page_url[url::Position::BeforePath..], &[]);
+            assert_eq!(page.status, 200);
+            let link = page.header("Link").unwrap();
+            let preloaded = link.strip_prefix('<').unwrap().strip_suffix(">; rel=preload; as=image").unwrap();
+            // The URL of the photo, as the browser will resolve it.
+            let src = page.text().split(r#"<img src=""#).nth(1).unwrap().split('"').next().unwrap().replace("&amp;", "&");
+            assert!(src.starts_with("a-b_1.jpg?"), "{}", src);
+            assert_eq!(page_url.join(&src).unwrap(), server.url.join(preloaded).unwrap());
+            assert_eq!(server.get(preloaded, &[]).header("Content-Type"), Some("image/jpeg"));
+        }
+        let server = serve(&root, |_| {});
+        assert_eq!(server.get("/album/sub/c.jpg.html", &[]).header("Link"), None);
+    }
+
+    /// Returns the photos that the previous and next links of `page` name.
+    fn frame_links(page: &str) -> (String, String) {
+        let link = |text: &str| {
+            let end = page.find(&format!(">{}</a>", text)).unwrap();
+            let start = page[..end].rfind(r#"href=""#).unwrap() + r#"href=""#.len();
+            page[start..end].split(".html").next().unwrap().to_owned()
+        };
+        (link("previous"), link("next"))
+    }
+
+    #[test]
+    fn previous_next_wraps() {
+        let jpegs: Vec<String> = ["a.jpg", "b.jpg", "c.jpg"].map(String::from).into();
+        assert_eq!(previous_next(&jpegs, "a.jpg"), Some(("c.jpg", "b.jpg")));
+        assert_eq!(previous_next(&jpegs, "b.jpg"), Some(("a.jpg", "c.jpg")));
+        assert_eq!(previous_next(&jpegs, "c.jpg"), Some(("b.jpg", "a.jpg")));
+        assert_eq!(previous_next(&jpegs, "d.jpg"), None);
+        assert_eq!(previous_next(&jpegs[..2], "a.jpg"), Some(("b.jpg", "b.jpg")));
+        // The only photo is its own neighbour.
+        assert_eq!(previous_next(&jpegs[..1], "a.jpg"), Some(("a.jpg", "a.jpg")));
+        assert_eq!(previous_next(&[], "a.jpg"), None);
+    }
+
+    #[test]
+    fn frame_of_only_photo() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        let page = server.get("/album/a.jpg.html", &[]);
+        assert_eq!(page.status, 200);
+        assert_eq!(frame_links(page.text()), ("a.jpg".into(), "a.jpg".into()));
+    }
+
+    #[test]
+    fn last_modified() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let modified = server::http_date(std::fs::metadata(root.join("docs/album/a.jpg")).unwrap().modified().unwrap());
+        let server = serve(&root, |_| {});
+        for url in ["/album/a.jpg.thumb", "/album/a.jpg"] {
+            let response = server.get(url, &[]);
+            assert_eq!(response.status, 200, "{}", url);
+            assert_eq!(response.header("Last-Modified"), Some(modified.as_str()), "{}", url);
+            let status = |since: &str| server.get(url, &[("If-Modified-Since", since)]).status;
+            assert_eq!(status(&modified), 304, "{}", url);
+            assert_eq!(status("Fri, 31 Dec 9999 23:59:59 GMT"), 304, "{}", url);
+            assert_eq!(status("Thu, 01 Jan 1970 00:00:00 GMT"), 200, "{}", url);
+            assert_eq!(status("garbage"), 200, "{}", url);
+        }
+    }
+
+    #[test]
+    fn redirects() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        write_jpeg(&root.join("docs/album/sub-dir/b.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        // Albums need a trailing `/`, so that relative links work.
+        for (url, location) in [
+            ("/album", "album/"),
+            ("/album/sub-dir", "sub-dir/"),
+            ("/album/sub-dir?w=640&h=480", "sub-dir/?w=640&h=480"),
+        ] {
+            let response = server.get(url, &[]);
+            assert_eq!(response.status, 301, "{}", url);
+            assert_eq!(response.header("Location"), Some(location), "{}", url);
+            assert_eq!(server.url.join(url).unwrap().join(location).unwrap().path().chars().last(), Some('/'));
+        }
+        assert_eq!(server.get("/album/", &[]).status, 200);
+        assert_eq!(server.get("/album/a.jpg", &[]).status, 200);
+        // The photo of the day changes, so the redirect is temporary.
+        let response = server.get("/photo-of-the-day", &[]);
+        assert_eq!(response.status, 302);
+        let location = response.header("Location").unwrap();
+        assert!(location.starts_with(&format!("{}album/", server.url)), "{}", location);
+        assert!(location.ends_with(".jpg.html?w=800&h=600"), "{}", location);
+    }
+
+    #[test]
+    fn failed_thumbnail_not_cached() {
+        let root = TempDir::new();
+        std::fs::create_dir_all(root.join("docs/album")).unwrap();
+        std::fs::write(root.join("docs/album/a.jpg"), b"\xFF\xD8\xFF\xE0 Truncated").unwrap();
+        let server = serve(&root, |_| {});
+        for _ in 0..2 { assert_eq!(server.get("/album/a.jpg.thumb", &[]).status, 415); }
+        assert_eq!(server.get("/album/a.jpg?w=20", &[]).status, 415);
+        // Nothing partial is left behind, not even a temporary file.
+        let thumbs = root.join("thumbs");
+        assert!(!thumbs.exists() || files_in(&thumbs).is_empty(), "{:?}", files_in(&thumbs));
+        // Once the photo is fixed, its thumbnail is generated.
+        std::thread::sleep(std::time::Duration::from_millis(10));
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let thumb = server.get("/album/a.jpg.thumb", &[]);
+        assert_eq!(thumb.status, 200);
+        assert!(image::load_from_memory(&thumb.body).is_ok());
+    }
+
+    #[test]
+    fn reversed_order() {
+        let root = TempDir::new();
+        // Taken in the opposite order to their names.
+        for (i, name) in ["c.jpg", "b.jpg", "a.jpg"].iter().enumerate() {
+            let path = root.join("docs/album").join(name);
+            write_jpeg(&path, 40, 30);
+            let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000 + 3600 * i as u64);
+            File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        }
+        let server = serve(&root, |_| {});
+        let index = |query: &str| -> Vec<String> {
+            let page = server.get(&format!("/album/{}", query), &[]);
+            page.text().split(r#"<a class="thumb" href=""#).skip(1).map(|s| s.split(".html").next().unwrap().into()).collect()
+        };
+        let frame = |leaf: &str, query: &str| frame_links(server.get(&format!("/album/{}.html{}", leaf, query), &[]).text());
+        let pair = |previous: &str, next: &str| (previous.to_owned(), next.to_owned());
+        assert_eq!(index(""), ["a.jpg", "b.jpg", "c.jpg"]);
+        assert_eq!(index("?order=desc"), ["c.jpg", "b.jpg", "a.jpg"]);
+        assert_eq!(index("?sort=date"), ["c.jpg", "b.jpg", "a.jpg"]);
+        assert_eq!(index("?sort=date&order=desc"), ["a.jpg", "b.jpg", "c.jpg"]);
+        // Frames agree with the index.
+        assert_eq!(frame("b.jpg", ""), pair("a.jpg", "c.jpg"));
+        assert_eq!(frame("b.jpg", "?order=desc"), pair("c.jpg", "a.jpg"));
+        assert_eq!(frame("a.jpg", "?order=desc"), pair("b.jpg", "c.jpg"));
+        assert_eq!(frame("c.jpg", "?sort=date"), pair("a.jpg", "b.jpg"));
+        assert_eq!(frame("c.jpg", "?sort=date&order=desc"), pair("b.jpg", "a.jpg"));
+        // The links keep the order.
+        let page = server.get("/album/b.jpg.html?order=desc", &[]);
+        assert!(page.text().contains("c.jpg.html?w=800&amp;h=600&amp;order=desc"), "{}", page.text());
+        let listing: serde_json::Value = serde_json::from_slice(&server.get("/album/?format=json&order=desc", &[]).body).unwrap();
+        let names: Vec<_> = listing["images"].as_array().unwrap().iter().map(|image| image["name"].clone()).collect();
+        assert_eq!(names, ["c.jpg", "b.jpg", "a.jpg"]);
+    }
+
+    #[test]
+    fn head_like_get() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/album/a.jpg"), 40, 30);
+        let server = serve(&root, |_| {});
+        for path in ["/album/a.jpg", "/album/a.jpg.thumb", "/album/a.jpg.html", "/album/", "/album", "/album/missing.jpg"] {
+            let get = server.get(path, &[]);
+            let head = server.request("HEAD", path, &[], b"");
+            assert_eq!(head.status, get.status, "{}", path);
+            assert!(head.body.is_empty(), "{}", path);
+            for name in ["Content-Type", "Content-Length", "ETag", "Last-Modified", "Location", "Cache-Control"] {
+                assert_eq!(head.header(name), get.header(name), "{} {}", path, name);
+            }
+        }
+        let head = server.request("HEAD", "/album/a.jpg", &[], b"");
+        assert_eq!(head.header("Content-Length"), Some(&*std::fs::metadata(root.join("docs/album/a.jpg")).unwrap().len().to_string()));
+        assert_eq!(server.request("OPTIONS", "/album/a.jpg", &[], b"").header("Allow"), Some("GET, HEAD, POST, OPTIONS"));
+    }
+
+    #[test]
+    fn compressed_json() {
+        let root = TempDir::new();
+        for i in 0..30 { write_jpeg(&root.join(&format!("docs/album/photo-{:02}.jpg", i)), 40, 30); }
+        let server = serve(&root, |_| {});
+        let plain = server.get("/album/?format=json", &[]);
+        assert_eq!(plain.header("Content-Encoding"), None);
+        assert!(plain.body.len() >= 1024, "The listing is too short to compress");
+        let gzip = server.get("/album/?format=json", &[("Accept-Encoding", "gzip")]);
+        assert_eq!(gzip.status, 200);
+        assert_eq!(gzip.header("Content-Encoding"), Some("gzip"));
+        assert_eq!(gzip.header("Vary"), Some("Accept-Encoding"));
+        assert!(gzip.header("Content-Type").unwrap().starts_with("application/json"));
+        let mut json = Vec::new();
+        std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&gzip.body[..]), &mut json).unwrap();
+        assert_eq!(json, plain.body);
+        // Images are sent as they are.
+        for path in ["/album/photo-00.jpg", "/album/photo-00.jpg.thumb"] {
+            let image = server.get(path, &[("Accept-Encoding", "gzip, br")]);
+            assert_eq!(image.status, 200);
+            assert_eq!(image.header("Content-Encoding"), None, "{}", path);
+        }
+    }
+
+    /// The value of an `Authorization` header that logs in as `user`.
+    fn basic(user: &str, password: &str) -> String {
+        use base64::prelude::{BASE64_STANDARD, Engine};
+        format!("Basic {}", BASE64_STANDARD.encode(format!("{}:{}", user, password)))
+    }
+
+    #[test]
+    fn album_passwords() {
+        let root = TempDir::new();
+        write_jpeg(&root.join("docs/private/a.jpg"), 40, 30);
+        write_jpeg(&root.join("docs/private/inner/b.jpg"), 40, 30);
+        write_jpeg(&root.join("docs/public/c.jpg"), 40, 30);
+        let hash = bcrypt::hash("secret", 4).unwrap();
+        std::fs::write(root.join("docs/private/.auth"), format!("# Comment\n\nalice:{}\nbob:not-a-hash\n", hash)).unwrap();
+        let server = serve(&root, |_| {});
+        let alice = basic("alice", "secret");
+        for path in ["/private/", "/private/a.jpg", "/private/a.jpg.thumb", "/private/inner/", "/private/inner/b.jpg.html"] {
+            let missing = server.get(path, &[]);
+            assert_eq!(missing.status, 401, "{}", path);
+            assert!(missing.header("WWW-Authenticate").unwrap().starts_with("Basic realm="), "{}", path);
+            for wrong in [basic("alice", "wrong"), basic("carol", "secret"), basic("bob", ""), "Bearer secret".into()] {
+                let response = server.get(path, &[("Authorization", &wrong)]);
+                assert_eq!(response.status, 401, "{} {}", path, wrong);
+                assert!(response.header("WWW-Authenticate").is_some());
+            }
+            assert_eq!(server.get(path, &[("Authorization", &alice)]).status, 200, "{}", path);
+        }
+        // The file itself is never served or listed.
+        assert_eq!(server.get("/private/.auth", &[("Authorization", &alice)]).status, 404);
+        assert!(!server.get("/private/", &[("Authorization", &alice)]).text().contains(".auth"));
+        // Other albums are unaffected.
+        assert_eq!(server.get("/public/c.jpg", &[]).status, 200);
+    }
+
+    #[test]
+    fn edited_photo_regenerates_thumbnail() {
+        let root = TempDir::new();
+        let path = root.join("docs/album/a.jpg");
+        write_jpeg(&path, 40, 30);
+        let server = serve(&root, |_| {});
+        let thumb = server.get("/album/a.jpg.thumb", &[]);
+        let index = server.get("/album/?inline_previews=1", &[]);
+        // Edit the photo in place, as an editor would.
+        image::RgbImage::from_pixel(40, 30, image::Rgb([255, 0, 0])).save(&path).unwrap();
+        let mtime = SystemTime::now() + Duration::from_secs(1);
+        File::options().write(true).open(&path).unwrap().set_modified(mtime).unwrap();
+        let edited = server.get("/album/a.jpg.thumb", &[]);
+        assert_ne!(edited.header("ETag"), thumb.header("ETag"));
+        assert!(edited.body != thumb.body, "The thumbnail is stale");
+        // The bytes are those of a thumbnail made from scratch.
+        let fresh = TempDir::new();
+        std::fs::create_dir_all(fresh.join("docs/album")).unwrap();
+        std::fs::copy(&path, fresh.join("docs/album/a.jpg")).unwrap();
+        assert!(serve(&fresh, |_| {}).get("/album/a.jpg.thumb", &[]).body == edited.body);
+        // As is the preview made from it.
+        assert!(server.get("/album/?inline_previews=1", &[]).body != index.body, "The preview is stale");
+    }
+
+    #[test]
+    fn photo_of_the_day_choice() {
+        let photos: Vec<String> = ["a/1.jpg", "a/2.jpg", "b/3.jpg"].iter().map(|&s| s.into()).collect();
+        // Fixed, so that upgrading the server does not change it.
+        assert_eq!(photo_of_the_day(&photos, "2024-12-31"), Some("b/3.jpg"));
+        let chosen: std::collections::HashSet<_> = (1..=28).map(|day| photo_of_the_day(&photos, &format!("2024-02-{:02}", day)).unwrap()).collect();
+        assert_eq!(chosen.len(), 3);
+        assert_eq!(photo_of_the_day(&photos[..1], "2024-12-31"), Some("a/1.jpg"));
+        assert_eq!(photo_of_the_day(&[], "2024-12-31"), None);
+    }
+
+    #[test]
+    fn nested_albums_everywhere() {
+        let root = TempDir::new();
+        for name in ["top/a.jpg", "top/inner/b.jpg", "top/inner/deeper/c.jpg", "top/secret/d.jpg", "top/secret/under/e.jpg", "top/locked/f.jpg"] {
+            write_jpeg(&root.join(&format!("docs/{}", name)), 40, 30);
+        }
+        std::fs::write(root.join("docs/top/secret/.token"), "s3").unwrap();
+        std::fs::write(root.join("docs/top/locked/.auth"), "alice:not-a-hash\n").unwrap();
+        let leak = |path: PathBuf| &*String::leak(path.into_os_string().into_string().unwrap());
+        let photo_server = PhotoServer::new(leak(root.join("docs")), leak(root.join("thumbs")), Url::parse("http://localhost/").unwrap());
+        // Precaching covers every album, since thumbnails are checked when served.
+        assert_eq!(photo_server.precache(None).unwrap(), 0);
+        let cached: Vec<_> = files_in(&root.join("thumbs")).iter().filter_map(|path| {
+            let path = path.strip_prefix(photo_server.cache_root()).ok()?;
+            Some(path.to_str().unwrap().to_owned())
+        }).collect();
+        for name in ["top/a.jpg", "top/inner/b.jpg", "top/inner/deeper/c.jpg", "top/secret/d.jpg", "top/secret/under/e.jpg", "top/locked/f.jpg"] {
+            assert!(cached.iter().any(|path| path == name), "{} not in {:?}", name, cached);
+        }
+        // Exporting covers the public ones.
+        let output = root.join("out");
+        assert_eq!(photo_server.export(&output).unwrap(), 0);
+        for name in ["index.html", "top/index.html", "top/inner/index.html", "top/inner/deeper/index.html", "top/inner/deeper/c.jpg.html"] {
+            assert!(output.join(name).is_file(), "{}", name);
+        }
+        for name in ["top/secret", "top/locked"] { assert!(!output.join(name).exists(), "{}", name); }
+        // Every relative link in the export leads to a file in it.
+        for page in files_in(&output).into_iter().filter(|path| path.extension().is_some_and(|e| e == "html")) {
+            let text = std::fs::read_to_string(&page).unwrap();
+            for attribute in ["href=\"", "src=\""] {
+                for link in text.split(attribute).skip(1).map(|s| html_escape::decode_html_entities(s.split('"').next().unwrap()).into_owned()) {
+                    if link.starts_with('/') || link.starts_with('#') || Url::parse(&link).is_ok() { continue; }
+                    assert!(page.parent().unwrap().join(&link).is_file(), "{} links to {}", page.display(), link);
+                }
+            }
+        }
+        // Photo of the day comes from the public albums, at any depth.
+        let only_nested = TempDir::new();
+        write_jpeg(&only_nested.join("docs/top/secret/under/e.jpg"), 40, 30);
+        std::fs::write(only_nested.join("docs/top/secret/.token"), "s3").unwrap();
+        let server = serve(&only_nested, |_| {});
+        assert_eq!(server.get("/photo-of-the-day", &[]).status, 404);
+        let response = server.get("/photo-of-the-day?token=s3", &[]);
+        assert_eq!(response.status, 302);
+        assert!(response.header("Location").unwrap().starts_with(&format!("{}top/secret/under/e.jpg.html", server.url)));
+        write_jpeg(&only_nested.join("docs/top/inner/deeper/c.jpg"), 40, 30);
+        let response = server.get("/photo-of-the-day", &[]);
+        assert!(response.header("Location").unwrap().starts_with(&format!("{}top/inner/deeper/c.jpg.html", server.url)));
+    }
+
+    #[test]
+    fn text_file_ranges() {
+        let root = TempDir::new();
+        // Longer than `UTF8_SNIFF_BYTES`, which ends in the middle of an "é".
+        let text = format!("a{}", "é".repeat(50_000));
+        std::fs::create_dir_all(root.join("docs/album")).unwrap();
+        std::fs::write(root.join("docs/album/notes.txt"), &text).unwrap();
+        std::fs::write(root.join("docs/album/latin1.txt"), b"caf\xE9").unwrap();
+        let server = serve(&root, |_| {});
+        let whole = server.get("/album/notes.txt", &[]);
+        assert_eq!(whole.status, 200);
+        assert_eq!(whole.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(whole.text(), text);
+        let length = text.len();
+        for (range, start, end) in [("bytes=1-4", 1, 4), ("bytes=99999-", 99999, length - 1), ("bytes=-3", length - 3, length - 1)] {
+            let part = server.get("/album/notes.txt", &[("Range", range)]);
+            assert_eq!(part.status, 206, "{}", range);
+            assert_eq!(part.header("Content-Range"), Some(&*format!("bytes {}-{}/{}", start, end, length)), "{}", range);
+            assert_eq!(part.header("Content-Type"), Some("text/plain; charset=utf-8"));
+            assert_eq!(part.body, text.as_bytes()[start..=end], "{}", range);
+        }
+        assert_eq!(server.get("/album/notes.txt", &[("Range", &format!("bytes={}-", length))]).status, 416);
+        // Text that is not UTF-8 is not labelled as such.
+        let latin1 = server.get("/album/latin1.txt", &[]);
+        assert_eq!(latin1.header("Content-Type"), Some("text/plain"));
+        assert_eq!(latin1.body, b"caf\xE9");
+    }
 }
@@ -0,0 +1,97 @@
+//! Parsing `multipart/form-data` request bodies, as sent by HTML forms with
+//! `enctype="multipart/form-data"`.
+//!
+//! The whole body must be in memory. Only what is needed for uploading
+//! files is understood: the `name` and `filename` parameters of each part's
+//! `Content-Disposition` header. Other headers are ignored, and so is the
+//! `filename*` parameter.
+
+/// One part of a `multipart/form-data` body.
+pub struct Part<'a> {
+    /// The name of the form field.
+    pub name: Option<String>,
+
+    /// The name of the file, if the field is a file.
+    pub filename: Option<String>,
+
+    /// The contents of the field.
+    pub data: &'a [u8],
+}
+
+/// Returns the `boundary` parameter of a `multipart/form-data`
+/// `Content-Type` header value.
+pub fn boundary(content_type: &str) -> Option<String> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") { return None; }
+    parameters(params).into_iter().find_map(
+        |(key, value)| if key.eq_ignore_ascii_case("boundary") { Some(value) } else { None }
+    ).filter(|boundary| !boundary.is_empty())
+}
+
+/// Parse `; key=value; key="quoted value"`, ignoring anything malformed.
+fn parameters(s: &str) -> Vec<(String, String)> {
+    let mut ret = Vec::new();
+    let mut chars = s.chars().peekable();
+    loop {
+        // Skip separators.
+        while chars.next_if(|&c| c == ';' || c.is_whitespace()).is_some() {}
+        let mut key = String::new();
+        while let Some(c) = chars.next_if(|&c| c != '=' && c != ';') { key.push(c); }
+        if chars.next() != Some('=') {
+            if chars.peek().is_none() { return ret; }
+            continue;
+        }
+        let mut value = String::new();
+        if chars.next_if_eq(&'"').is_some() {
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => { if let Some(c) = chars.next() { value.push(c); } },
+                    c => value.push(c),
+                }
+            }
+        } else {
+            while let Some(c) = chars.next_if(|&c| c != ';') { value.push(c); }
+            value = value.trim_end().into();
+        }
+        ret.push((key.trim().into(), value));
+    }
+}
+
+/// Returns the index of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Split `body` into its parts, which are separated by `boundary`. Returns
+/// `None` if `body` is malformed, e.g. if it is truncated.
+pub fn parse<'a>(body: &'a [u8], boundary: &str) -> Option<Vec<Part<'a>>> {
+    let delimiter = format!("--{}", boundary);
+    let separator = format!("\r\n--{}", boundary);
+    let mut ret = Vec::new();
+    // Skip the preamble, if any.
+    let mut rest = &body[find(body, delimiter.as_bytes())? + delimiter.len()..];
+    loop {
+        if rest.starts_with(b"--") { return Some(ret); }
+        // Skip any transport padding.
+        let line_end = find(rest, b"\r\n")?;
+        if !rest[..line_end].iter().all(|&b| b == b' ' || b == b'\t') { return None; }
+        rest = &rest[line_end + 2..];
+        let headers_end = if rest.starts_with(b"\r\n") { 0 } else { find(rest, b"\r\n\r\n")? + 2 };
+        let headers = std::str::from_utf8(&rest[..headers_end]).ok()?;
+        rest = &rest[headers_end + 2..];
+        let data_end = find(rest, separator.as_bytes())?;
+        let mut part = Part {name: None, filename: None, data: &rest[..data_end]};
+        rest = &rest[data_end + separator.len()..];
+        for header in headers.split("\r\n") {
+            let Some((key, value)) = header.split_once(':') else { continue; };
+            if !key.trim().eq_ignore_ascii_case("Content-Disposition") { continue; }
+            let Some((_, params)) = value.split_once(';') else { continue; };
+            for (key, value) in parameters(params) {
+                if key.eq_ignore_ascii_case("name") { part.name = Some(value); }
+                else if key.eq_ignore_ascii_case("filename") { part.filename = Some(value); }
+            }
+        }
+        ret.push(part);
+    }
+}
@@ -0,0 +1,125 @@
+//! Showing RAW files using the JPEG previews that cameras embed in them.
+//!
+//! The supported formats are all based on TIFF. Some of their IFDs describe
+//! JPEG files. We show the largest JPEG that the `image` crate can decode.
+//! We never decode the RAW data itself.
+
+use std::io::{Cursor};
+use std::path::{Path};
+
+use image::{DynamicImage, ImageFormat, ImageReader, ImageResult};
+
+use super::server::{remove_extension};
+use super::tiff::{Tiff};
+
+/// The extensions of RAW files: Canon, Nikon and Sony respectively.
+pub const RAW_EXTENSIONS: [&str; 3] = ["cr2", "nef", "arw"];
+
+/// The size of the image shown for a RAW file that has no usable preview.
+const PLACEHOLDER_SIZE: (u32, u32) = (640, 480);
+
+/// The maximum number of IFDs to read, in case they form a loop.
+const MAX_IFDS: usize = 32;
+
+// TIFF tags.
+const COMPRESSION: u16 = 0x0103;
+const STRIP_OFFSETS: u16 = 0x0111;
+const STRIP_BYTE_COUNTS: u16 = 0x0117;
+const SUB_IFDS: u16 = 0x014A;
+const JPEG_OFFSET: u16 = 0x0201;
+const JPEG_LENGTH: u16 = 0x0202;
+
+/// Values of the `COMPRESSION` tag that mean JPEG.
+const JPEG_COMPRESSIONS: [u32; 2] = [6, 7];
+
+/// Returns `true` if `file_name` has one of `RAW_EXTENSIONS`.
+pub fn is_raw(file_name: &Path) -> bool {
+    let Some(file_name) = file_name.file_name().and_then(|s| s.to_str()) else { return false; };
+    RAW_EXTENSIONS.iter().any(|extension| remove_extension(file_name, extension).is_some())
+}
+
+/// Returns the JPEG files described by the IFD at `ifd`, and the offsets of
+/// the IFDs that it refers to.
+fn read_ifd<'a>(tiff: &Tiff<'a>, ifd: usize) -> Option<(Vec<&'a [u8]>, Vec<usize>)> {
+    let (mut jpegs, mut ifds) = (Vec::new(), Vec::new());
+    let (mut compression, mut strip_offsets, mut strip_lengths) = (None, None, None);
+    let (mut jpeg_offset, mut jpeg_length) = (None, None);
+    for (tag, values) in tiff.entries(ifd)? {
+        match tag {
+            COMPRESSION => { compression = values.first().copied(); },
+            STRIP_OFFSETS => { strip_offsets = Some(values); },
+            STRIP_BYTE_COUNTS => { strip_lengths = Some(values); },
+            SUB_IFDS => { ifds.extend(values.into_iter().map(|v| v as usize)); },
+            JPEG_OFFSET => { jpeg_offset = values.first().copied(); },
+            JPEG_LENGTH => { jpeg_length = values.first().copied(); },
+            _ => {},
+        }
+    }
+    ifds.extend(tiff.next_ifd(ifd));
+    let mut found = |offset: u32, length: u32| {
+        let (offset, length) = (offset as usize, length as usize);
+        if let Some(jpeg) = tiff.data.get(offset..offset.saturating_add(length)) { jpegs.push(jpeg); }
+    };
+    if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) { found(offset, length); }
+    // A JPEG in a single strip, e.g. the big preview in a CR2 file.
+    if compression.is_some_and(|c| JPEG_COMPRESSIONS.contains(&c))
+        && let (Some([offset]), Some([length])) = (strip_offsets.as_deref(), strip_lengths.as_deref())
+    {
+        found(*offset, *length);
+    }
+    Some((jpegs, ifds))
+}
+
+/// Returns `true` if `data` is a baseline or progressive JPEG file. Other
+/// kinds, e.g. the lossless JPEG in which some cameras store their RAW data,
+/// cannot be decoded by the `image` crate.
+fn is_lossy_jpeg(data: &[u8]) -> bool {
+    if !data.starts_with(&[0xFF, 0xD8]) { return false; }
+    let mut offset = 2;
+    while let Some(&[0xFF, marker, high, low]) = data.get(offset..offset + 4) {
+        match marker {
+            0xC0 ..= 0xC2 => return true,
+            0xC3 | 0xC5 ..= 0xC7 | 0xC9 ..= 0xCB | 0xCD ..= 0xCF => return false,
+            _ => { offset += 2 + usize::from(u16::from_be_bytes([high, low])); },
+        }
+    }
+    false
+}
+
+/// Returns the largest decodable JPEG file embedded in `data`, if any.
+fn embedded_jpeg(data: &[u8]) -> Option<&[u8]> {
+    let tiff = Tiff::new(data)?;
+    let mut ifds = vec![tiff.first_ifd()?];
+    let mut ret: Option<&[u8]> = None;
+    for _ in 0..MAX_IFDS {
+        let Some(ifd) = ifds.pop() else { break; };
+        let Some((jpegs, more_ifds)) = read_ifd(&tiff, ifd) else { continue; };
+        for jpeg in jpegs {
+            if is_lossy_jpeg(jpeg) && ret.is_none_or(|ret| ret.len() < jpeg.len()) { ret = Some(jpeg); }
+        }
+        ifds.extend(more_ifds);
+    }
+    ret
+}
+
+/// Load the embedded preview of the RAW file `file_name`, or a grey
+/// placeholder if it has none.
+pub fn open(file_name: &Path) -> ImageResult<DynamicImage> {
+    let data = std::fs::read(file_name)?;
+    match embedded_jpeg(&data) {
+        Some(jpeg) => image::load_from_memory_with_format(jpeg, ImageFormat::Jpeg),
+        None => {
+            let (w, h) = PLACEHOLDER_SIZE;
+            Ok(image::RgbImage::from_pixel(w, h, image::Rgb([128, 128, 128])).into())
+        },
+    }
+}
+
+/// Returns the width and height of the image that `open()` would return.
+pub fn dimensions(file_name: &Path) -> ImageResult<(u32, u32)> {
+    let data = std::fs::read(file_name)?;
+    match embedded_jpeg(&data) {
+        Some(jpeg) => ImageReader::with_format(Cursor::new(jpeg), ImageFormat::Jpeg).into_dimensions(),
+        None => Ok(PLACEHOLDER_SIZE),
+    }
+}
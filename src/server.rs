@@ -1,26 +1,129 @@
 use std::{fmt};
+use std::collections::{VecDeque};
 use std::error::{Error};
 use std::ffi::{OsStr, OsString};
 use std::fs::{File};
+use std::hash::{BuildHasher, DefaultHasher, Hash, Hasher, RandomState};
+use std::io::{Cursor, Read, Write};
+use std::net::{IpAddr};
+use std::sync::{Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use base64::prelude::{BASE64_STANDARD, Engine};
 use tiny_http::{Method, Request, Response, Header};
 
-pub use html_escape::{encode_text as html_escape};
+pub use html_escape::{encode_text as html_escape, encode_double_quoted_attribute as html_escape_attribute};
 pub use url::{Url};
 
 /// Given `"foo.BAR"` and `"bar"` returns `Some("foo")`.
 pub fn remove_extension<'a>(filename: &'a str, extension: &str) -> Option<&'a str> {
-    if let Some(index) = filename.len().checked_sub(".".len() + extension.len()) {
-        if let Some((ret, tail)) = filename.split_at_checked(index) {
-            let mut tail = tail.chars();
-            if let Some('.') = tail.next() {
-                if extension.eq_ignore_ascii_case(tail.as_str()) { return Some(ret); }
-            }
-        }
+    if let Some(index) = filename.len().checked_sub(".".len() + extension.len())
+        && let Some((ret, tail)) = filename.split_at_checked(index)
+    {
+        let mut tail = tail.chars();
+        if tail.next() == Some('.') && extension.eq_ignore_ascii_case(tail.as_str()) { return Some(ret); }
     }
     None
 }
 
+/// Compares two byte strings in time that depends only on their lengths.
+///
+/// Use this for comparing secrets, so that an attacker can't learn how much
+/// of a guess was correct by timing the response.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() { return false; }
+    a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Hash `value` using an algorithm that is fixed for a given build.
+pub fn hash(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Tests whether the value of an `If-None-Match` header matches `etag`.
+/// Weak and strong tags match if their opaque parts are the same.
+fn etag_matches(tags: &str, etag: &str) -> bool {
+    let strong = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    tags.split(',').any(|tag| tag.trim() == "*" || strong(tag) == strong(etag))
+}
+
+/// Converts a number of days since 1970 to a year, a month (0 for January)
+/// and a day of the month. See
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719468;
+    let (era, doe) = (z / 146097, z % 146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = (mp + 2) % 12;
+    let year = era * 400 + yoe + if month < 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The inverse of `civil_from_days()`. See
+/// http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: u64, month: u64, day: u64) -> Option<u64> {
+    let year = if month < 2 { year.checked_sub(1)? } else { year };
+    let (era, yoe) = (year / 400, year % 400);
+    let doy = (153 * ((month + 10) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    (era * 146097 + doe).checked_sub(719468)
+}
+
+/// The month abbreviations used in HTTP dates.
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+/// Formats `time` as an HTTP date, e.g. `"Sun, 06 Nov 1994 08:49:37 GMT"`.
+pub fn http_date(time: SystemTime) -> String {
+    const DAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        DAYS[(days % 7) as usize], day, MONTHS[month as usize], year,
+        seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
+
+/// Formats `time` as in the Common Log Format, e.g.
+/// `"10/Oct/2000:13:55:36 +0000"`.
+fn clf_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, seconds) = (seconds / 86400, seconds % 86400);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day, MONTHS[month as usize], year, seconds / 3600, seconds / 60 % 60, seconds % 60,
+    )
+}
+
+/// Parses an HTTP date in the format that `http_date()` produces, returning
+/// the number of seconds since 1970. The obsolete formats that RFC 7231 also
+/// allows are not understood.
+pub fn parse_http_date(s: &str) -> Option<u64> {
+    let [_, day, month, year, time, "GMT"] = s.split_whitespace().collect::<Vec<_>>()[..] else { return None; };
+    let month = MONTHS.iter().position(|&m| m == month)? as u64;
+    let (day, year): (u64, u64) = (day.parse().ok()?, year.parse().ok()?);
+    if !(1..=31).contains(&day) { return None; }
+    let time: Vec<u64> = time.split(':').map(|x| x.parse().ok()).collect::<Option<_>>()?;
+    let [hours, minutes, seconds] = time[..] else { return None; };
+    if hours > 23 || minutes > 59 || seconds > 60 { return None; }
+    Some(days_from_civil(year, month, day)? * 86400 + hours * 3600 + minutes * 60 + seconds)
+}
+
+/// Formats the date of `time` as in ISO 8601, e.g. `"1994-11-06"`.
+pub fn iso_date(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    format!("{:04}-{:02}-{:02}", year, month + 1, day)
+}
+
 // ----------------------------------------------------------------------------
 
 /// `Error` returned by `validate_name()` if it doesn't like the filename.
@@ -45,9 +148,9 @@ impl Error for DubiousFilename {}
 pub fn validate_name(s: &OsStr) -> Result<&str, DubiousFilename> {
     for b in s.as_encoded_bytes() {
         match b {
-            b'0' .. b'9' => {},
-            b'A' .. b'Z' => {},
-            b'a' .. b'z' => {},
+            b'0' ..= b'9' => {},
+            b'A' ..= b'Z' => {},
+            b'a' ..= b'z' => {},
             b'_' | b'.' | b'-' => {}
             _ => { return Err(DubiousFilename(s.to_owned())); }
         }
@@ -57,20 +160,147 @@ pub fn validate_name(s: &OsStr) -> Result<&str, DubiousFilename> {
 
 // ----------------------------------------------------------------------------
 
+/// The HTTP headers of a request.
+#[derive(Debug, Copy, Clone)]
+pub struct Headers<'a>(&'a [Header]);
+
+impl<'a> Headers<'a> {
+    /// Tests whether `header` is called `name`, ignoring case.
+    fn is(header: &Header, name: &str) -> bool {
+        header.field.as_str().as_str().eq_ignore_ascii_case(name)
+    }
+
+    /// Returns the value of the first header called `name`, ignoring case.
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.0.iter().find(|h| Self::is(h, name)).map(|h| h.value.as_str())
+    }
+
+    /// Returns the values of all cookies called `name`.
+    pub fn cookies(&self, name: &str) -> impl Iterator<Item = &'a str> {
+        self.0.iter().filter(|h| Self::is(h, "Cookie")).flat_map(
+            |h| h.value.as_str().split(';')
+        ).filter_map(move |cookie| {
+            let (key, value) = cookie.split_once('=')?;
+            if key.trim() == name { Some(value.trim()) } else { None }
+        })
+    }
+
+    /// Tests whether the `Accept` header names the MIME type `mime` with a
+    /// non-zero q-value. Wildcards such as `image/*` do not count.
+    pub fn accepts(&self, mime: &str) -> bool {
+        let Some(accept) = self.get("Accept") else { return false; };
+        accept.split(',').any(|item| {
+            let mut parts = item.split(';');
+            let matches = parts.next().unwrap().trim().eq_ignore_ascii_case(mime);
+            let q = parts.find_map(|p| p.trim().strip_prefix("q=")).map_or(Some(1.0), |q| q.trim().parse::<f32>().ok());
+            matches && q.is_some_and(|q| q > 0.0)
+        })
+    }
+
+    /// Returns the user name and password of an `Authorization: Basic`
+    /// header, if there is one.
+    pub fn basic_auth(&self) -> Option<(String, String)> {
+        let (scheme, encoded) = self.get("Authorization")?.trim().split_once(' ')?;
+        if !scheme.eq_ignore_ascii_case("Basic") { return None; }
+        let decoded = String::from_utf8(BASE64_STANDARD.decode(encoded.trim()).ok()?).ok()?;
+        let (user, password) = decoded.split_once(':')?;
+        Some((user.into(), password.into()))
+    }
+
+    /// Tests whether a cached response with validators `etag` and
+    /// `last_modified` can be reused, according to `If-None-Match` and
+    /// `If-Modified-Since`.
+    ///
+    /// `If-None-Match` takes precedence. `If-Modified-Since` is honoured if
+    /// it is no earlier than `last_modified`, both being formatted as by
+    /// `http_date()`.
+    pub fn is_fresh(&self, etag: &str, last_modified: &str) -> bool {
+        if let Some(tags) = self.get("If-None-Match") { return etag_matches(tags, etag); }
+        let Some(since) = self.get("If-Modified-Since").and_then(parse_http_date) else { return false; };
+        parse_http_date(last_modified).is_some_and(|modified| modified <= since)
+    }
+
+    /// Tests whether a change to a resource whose current entity tag is
+    /// `etag`, or which does not exist if `None`, may proceed, according to
+    /// `If-Match`.
+    ///
+    /// With no `If-Match`, it may. `If-Match: *` requires the resource to
+    /// exist. Otherwise one of the listed tags must be strong and equal to
+    /// `etag`.
+    pub fn if_match(&self, etag: Option<&str>) -> bool {
+        let Some(tags) = self.get("If-Match") else { return true; };
+        let Some(etag) = etag else { return false; };
+        tags.split(',').map(str::trim).any(|tag| tag == "*" || (!tag.starts_with("W/") && tag == etag))
+    }
+}
+
+// ----------------------------------------------------------------------------
+
 /// A normal HTTP response.
-// TODO: Redirect.
-#[derive(Debug)]
 pub enum HttpOkay {
-    File(File),
+    /// The contents of `file`, which has MIME type `content_type`.
+    File {file: File, content_type: String},
+
+    /// The contents of `reader`, which are `length` bytes long if known.
+    Stream {reader: Box<dyn Read + Send>, content_type: String, length: Option<u64>},
+
     Html(String),
     Jpeg(Vec<u8>),
+    Json(String),
+    Png(Vec<u8>),
+    WebP(Vec<u8>),
+    Avif(Vec<u8>),
+    Text(String),
+    Xml(String),
+
+    /// An empty response; status 204.
+    NoContent,
+
+    /// The client's cached copy is still valid; status 304.
+    NotModified,
+
+    /// The resource is at `location`; status 301 if `permanent`, otherwise
+    /// 302.
+    Redirect {location: String, permanent: bool},
+
+    /// Another response, with an extra HTTP header.
+    Header(Box<HttpOkay>, String, String),
+}
+
+impl HttpOkay {
+    /// Add an HTTP header to `self`.
+    pub fn with_header(self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        Self::Header(Box::new(self), key.into(), value.into())
+    }
 }
 
 /// An erroneous HTTP response.
 #[derive(Debug)]
 pub enum HttpError {
     Invalid,
+
+    /// The request lacks the credentials needed to see the resource.
+    Unauthorized,
+
+    Forbidden,
     NotFound,
+
+    /// The resource exists but does not support the request's method.
+    MethodNotAllowed,
+
+    /// The server is too busy. Sent with `Retry-After`.
+    Unavailable,
+
+    /// A condition in the request, e.g. `If-Match`, is false.
+    PreconditionFailed,
+
+    /// The request body is longer than `Server` allows.
+    PayloadTooLarge,
+
+    /// The requested file exists but cannot be decoded, e.g. because it is
+    /// corrupt or only partially written.
+    UnsupportedMedia,
+
     Error(Box<dyn Error>),
 }
 
@@ -94,7 +324,53 @@ macro_rules! impl_httperror_from {
     };
 }
 
-impl_httperror_from!(std::io::Error);
+impl From<std::io::Error> for HttpError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::NotFound { return Self::NotFound; }
+        if e.get_ref().is_some_and(|inner| inner.is::<BodyTooLarge>()) { return Self::PayloadTooLarge; }
+        Self::new(e)
+    }
+}
+
+/// The error from reading a request body that is longer than `Server`
+/// allows. It converts to `HttpError::PayloadTooLarge`.
+#[derive(Debug)]
+struct BodyTooLarge;
+
+impl fmt::Display for BodyTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Request body too large")
+    }
+}
+
+impl Error for BodyTooLarge {}
+
+/// A request body that fails with `BodyTooLarge` as soon as it is found to
+/// be longer than a limit, without reading further.
+struct LimitedBody<R: Read> {
+    inner: R,
+
+    /// The number of bytes that may still be read.
+    remaining: u64,
+}
+
+impl<R: Read> Read for LimitedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        if self.remaining == 0 {
+            // Fail only if there is more.
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(std::io::Error::other(BodyTooLarge)),
+            };
+        }
+        let n = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..n])?;
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
 impl_httperror_from!(url::ParseError);
 impl_httperror_from!(DubiousFilename);
 
@@ -109,13 +385,19 @@ pub trait Handler {
     /// provide something with more type-checking.
     type Params: FromIterator<(String, String)>;
 
-    /// Called for each GET request.
+    /// Called for each GET or HEAD request. For HEAD, the body of the
+    /// response is not sent.
     ///
     /// - path - The part of the requested URL relative to `base_url`.
     ///   For example if this Handler is at `http://example.com/foo` and
     ///   the client requests is `http://example.com/foo/bar/baz` then `path`
     ///   will be `["bar", "baz"]`.
     /// - params - the parsed URL request parameters.
+    /// - headers - all the HTTP request headers, unchanged, e.g. `Accept`,
+    ///   `If-None-Match` and `Authorization`, even if `start()` was given
+    ///   credentials and checked them. Look them up with [`Headers::get()`].
+    ///   `Range`, `If-Range`, `If-None-Match` and `Accept-Encoding` are also
+    ///   applied to the response afterwards, so handlers need not.
     ///
     /// Note that the [`String`]s in `path` and `params` might contain special
     /// characters such as `/` and `?`, and non-ASCII characters. Be careful if
@@ -124,11 +406,224 @@ pub trait Handler {
         &self,
         path: Vec<String>,
         params: Self::Params,
+        headers: Headers,
     ) -> Result<HttpOkay, HttpError>;
+
+    /// Called for each POST request. The arguments are as for `handle_get()`,
+    /// and also:
+    ///
+    /// - body - the request body, already decoded if it was sent with
+    ///   `Transfer-Encoding: chunked`. Reading more than the limit passed to
+    ///   `start()` fails with an error that converts to
+    ///   `HttpError::PayloadTooLarge`. Anything left unread is discarded.
+    ///
+    /// By default, POST requests are not allowed.
+    fn handle_post(
+        &self,
+        _path: Vec<String>,
+        _params: Self::Params,
+        _headers: Headers,
+        _body: &mut dyn Read,
+    ) -> Result<HttpOkay, HttpError> {
+        Err(HttpError::MethodNotAllowed)
+    }
+
+    /// Called to describe an error to a client that accepts HTML.
+    ///
+    /// - status - The HTTP status code, e.g. `404`.
+    /// - message - A short description of the status, e.g. `"Not found"`.
+    /// - url - The requested URL, relative to the server root, e.g.
+    ///   `"/bar/baz?x=y"`.
+    ///
+    /// Returns an HTML page, or `None` to use a plain-text response.
+    fn error_html(&self, _status: u16, _message: &str, _url: &str) -> Option<String> { None }
+
+    /// Called before `handle_get()` if the requested URL does not end with
+    /// `/`. The arguments are as for `handle_get()`.
+    ///
+    /// Returns `true` to redirect the client to the same URL with `/`
+    /// appended, e.g. because the page contains relative links.
+    fn is_directory(&self, _path: &[String], _params: &Self::Params, _headers: Headers) -> bool { false }
+}
+
+/// A shared reference to a `Handler` is a `Handler`. Useful for keeping
+/// access to the handler while the server runs.
+impl<H: Handler> Handler for &H {
+    type Params = H::Params;
+
+    fn handle_get(
+        &self,
+        path: Vec<String>,
+        params: Self::Params,
+        headers: Headers,
+    ) -> Result<HttpOkay, HttpError> {
+        (*self).handle_get(path, params, headers)
+    }
+
+    fn handle_post(
+        &self,
+        path: Vec<String>,
+        params: Self::Params,
+        headers: Headers,
+        body: &mut dyn Read,
+    ) -> Result<HttpOkay, HttpError> {
+        (*self).handle_post(path, params, headers, body)
+    }
+
+    fn error_html(&self, status: u16, message: &str, url: &str) -> Option<String> {
+        (*self).error_html(status, message, url)
+    }
+
+    fn is_directory(&self, path: &[String], params: &Self::Params, headers: Headers) -> bool {
+        (*self).is_directory(path, params, headers)
+    }
 }
 
 // ----------------------------------------------------------------------------
 
+/// Returns a short string that identifies a request, for correlating logs
+/// with reports from users. It combines a counter with some randomness, so
+/// that IDs are unlikely to repeat across restarts.
+fn request_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let count = COUNTER.fetch_add(1, Ordering::Relaxed);
+    let random = RandomState::new().hash_one(count);
+    format!("{:06x}-{:x}", random & 0xFFFFFF, count)
+}
+
+/// A content coding that `Server` can apply to textual responses.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Encoding {
+    Brotli,
+    Gzip,
+    Identity,
+}
+
+impl Encoding {
+    /// The name of `self` in HTTP headers.
+    fn name(self) -> &'static str {
+        match self { Self::Brotli => "br", Self::Gzip => "gzip", Self::Identity => "identity" }
+    }
+
+    /// Choose the encoding with the highest q-value in `accept_encoding`, the
+    /// value of an `Accept-Encoding` header. Ties are broken in favour of
+    /// better compression.
+    fn negotiate(accept_encoding: Option<&str>) -> Self {
+        let Some(accept_encoding) = accept_encoding else { return Self::Identity; };
+        // The q-value of each coding, and of "*".
+        let mut qs: Vec<(&str, f32)> = Vec::new();
+        for item in accept_encoding.split(',') {
+            let mut parts = item.split(';');
+            let coding = parts.next().unwrap().trim();
+            let q = parts.find_map(|p| p.trim().strip_prefix("q=")).map_or(Some(1.0), |q| q.trim().parse().ok());
+            if let Some(q) = q { qs.push((coding, q)); }
+        }
+        let q_of = |coding: &str| {
+            qs.iter().find(|(c, _)| c.eq_ignore_ascii_case(coding))
+                .or_else(|| qs.iter().find(|(c, _)| *c == "*"))
+                .map(|&(_, q)| q)
+        };
+        let mut best = (Self::Identity, q_of("identity").unwrap_or(0.001));
+        for encoding in [Self::Gzip, Self::Brotli] {
+            let q = q_of(encoding.name()).unwrap_or(0.0);
+            if q > 0.0 && q >= best.1 { best = (encoding, q); }
+        }
+        best.0
+    }
+
+    /// Apply `self` to `data`.
+    fn encode(self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli => {
+                let mut ret = Vec::new();
+                let mut writer = brotli::CompressorWriter::new(&mut ret, 4096, 5, 22);
+                writer.write_all(data)?;
+                drop(writer);
+                Ok(ret)
+            },
+            Self::Gzip => {
+                let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            },
+            Self::Identity => Ok(data.to_vec()),
+        }
+    }
+}
+
+/// Textual responses shorter than this many bytes are not compressed.
+const MIN_COMPRESSED_LENGTH: usize = 1024;
+
+/// The HTTP methods that `Server` supports, for the `Allow` header.
+const ALLOWED_METHODS: &str = "GET, HEAD, POST, OPTIONS";
+
+/// The HTTP methods that `Server` supports other than POST.
+const ALLOWED_GET_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Once this is set, `start()` stops accepting requests, finishes those in
+/// progress, and returns. Setting it is safe in a signal handler.
+pub static STOP: AtomicBool = AtomicBool::new(false);
+
+/// The realm of HTTP basic authentication, which browsers may show when
+/// asking for a password.
+const REALM: &str = "Photos";
+
+/// How often threads waiting for a request check `STOP`.
+const STOP_INTERVAL: Duration = Duration::from_millis(200);
+
+/// How long a request beyond `max_requests` waits for others to finish
+/// before it gets `503 Service Unavailable`.
+const QUEUE_WAIT: Duration = Duration::from_secs(1);
+
+/// The requests that `Server` has received but not yet answered.
+#[derive(Default)]
+struct Queue {
+    /// Requests waiting for a thread, oldest first, with when they arrived.
+    waiting: VecDeque<(Instant, Request)>,
+
+    /// The number of requests being handled.
+    busy: usize,
+}
+
+impl Queue {
+    /// Remove and return the requests beyond `max_requests` that have waited
+    /// for `QUEUE_WAIT`.
+    fn expired(&mut self, max_requests: usize) -> Vec<Request> {
+        let admitted = max_requests.saturating_sub(self.busy);
+        let mut ret = Vec::new();
+        while self.waiting.len() > admitted && self.waiting[admitted].0.elapsed() >= QUEUE_WAIT {
+            ret.push(self.waiting.remove(admitted).unwrap().1);
+        }
+        ret
+    }
+}
+
+/// A socket on which `start()` can serve requests.
+pub struct Listener {
+    /// Web server.
+    server: tiny_http::Server,
+
+    /// The local URL that `server` serves.
+    url: Url,
+}
+
+impl Listener {
+    /// Listen on `server_address`, e.g. "127.0.0.1:8082". If the port is 0,
+    /// the OS chooses a free port, and `url()` says which.
+    pub fn new(server_address: &str) -> Self {
+        let server = tiny_http::Server::http(server_address).expect("Could not create the web server");
+        // Use the address actually bound, which differs if the port is 0.
+        let url = match server.server_addr().to_ip() {
+            Some(address) => format!("http://{}/", address),
+            None => format!("http://{}/", server_address),
+        };
+        Listener {server, url: Url::parse(&url).expect("Could not parse the server URL")}
+    }
+
+    /// Returns the local URL that `self` serves, including the port.
+    pub fn url(&self) -> &Url { &self.url }
+}
+
 struct Server<H: Handler> {
     /// Web server.
     pub server: tiny_http::Server,
@@ -139,47 +634,127 @@ struct Server<H: Handler> {
     /// The publicly visible external URL, which may differ from `server_url`.
     pub _base_url: Url,
 
+    /// If set, every request must send this `user:password` using HTTP
+    /// basic authentication.
+    pub credentials: Option<String>,
+
+    /// The maximum length of a request body.
+    pub max_body_bytes: u64,
+
+    /// How to log each request.
+    pub log_format: LogFormat,
+
+    /// The number of requests that may be waiting or being handled before
+    /// more are refused.
+    pub max_requests: usize,
+
+    /// The requests in flight.
+    queue: Mutex<Queue>,
+
+    /// Notified when a request is added to `queue`.
+    queue_changed: Condvar,
+
     /// The application-specific state.
     pub handler: H,
 }
 
 impl<H: Handler> Server<H> {
-    fn new(server_address: &str, base_url: Option<&str>, handler: H) -> Self {
-        let server_url = &format!("http://{}/", server_address);
-        let base_url = base_url.unwrap_or_else(|| server_url);
+    fn new(
+        listener: Listener,
+        base_url: Option<&str>,
+        credentials: Option<String>,
+        max_body_bytes: u64,
+        log_format: LogFormat,
+        max_requests: usize,
+        handler: H,
+    ) -> Self {
+        let Listener {server, url: server_url} = listener;
+        let base_url = base_url.map_or_else(
+            || server_url.clone(),
+            |base_url| Url::parse(base_url).expect("Could not parse the base URL"),
+        );
         Server {
-            server: tiny_http::Server::http(server_address).expect("Could not create the web server"),
-            server_url: url::Url::parse(server_url).expect("Could not parse the server URL"),
-            _base_url: url::Url::parse(base_url).expect("Could not parse the base URL"),
+            server,
+            server_url,
+            _base_url: base_url,
+            credentials,
+            max_body_bytes,
+            log_format,
+            max_requests,
+            queue: Mutex::new(Queue::default()),
+            queue_changed: Condvar::new(),
             handler,
         }
     }
 
+    /// Parse `request` and pass it to `self.handler`.
+    ///
+    /// Edge cases in the URL are normalised:
+    /// - Query parameters with an empty name or value, as in `?`, `?&` and
+    ///   `?w=`, are dropped, so they look absent to the handler.
+    /// - A fragment, which clients should not send, is ignored.
+    /// - Empty path segments, as in `dir//leaf` or `dir/`, are dropped. So
+    ///   are leading ones, as in `//dir`.
     fn handle_request(&self, request: &mut Request) -> Result<HttpOkay, HttpError> {
-        let request_url = self.server_url.join(request.url())?;
-        let relative_url = self.server_url.make_relative(&request_url).unwrap(); // By construction.
-        println!("{} {}", request.remote_addr().unwrap().ip(), relative_url);
-        // Parse the query parameters.
-        let params = request_url.query_pairs().map(
-            |(key, value)| (
-                url_escape::decode(key.as_ref()).into_owned(),
-                url_escape::decode(value.as_ref()).into_owned(),
-            )
+        // E.g. `//a/b` is a path with an empty segment, not a URL whose host
+        // is `a`.
+        let mut target = request.url();
+        while target.starts_with("//") { target = &target[1..]; }
+        let request_url = self.server_url.join(target)?;
+        if !self.is_authorized(Headers(request.headers())) { return Err(HttpError::Unauthorized); }
+        if *request.method() == Method::Options {
+            // Applies equally to `*` and to every URL.
+            return Ok(HttpOkay::NoContent.with_header("Allow", ALLOWED_METHODS));
+        }
+        // Parse the query parameters. `query_pairs()` decodes them.
+        let params = request_url.query_pairs().filter(
+            |(key, value)| !key.is_empty() && !value.is_empty()
+        ).map(
+            |(key, value)| (key.into_owned(), value.into_owned())
         ).collect();
         // Parse the path segments.
-        let mut path: Vec<String> = request_url.path_segments().ok_or(HttpError::Invalid)?.map(
+        let path: Vec<String> = request_url.path_segments().ok_or(HttpError::Invalid)?.filter(
+            |s| !s.is_empty()
+        ).map(
             |s| url_escape::decode(s).into_owned()
         ).collect();
-        if let Some(last) = path.last() {
-            if "" == last { path.pop(); }
+        // `tiny_http` omits the body of the response to a `HEAD` request.
+        let is_get = matches!(request.method(), Method::Get | Method::Head);
+        // Relative links in a directory only work if its URL ends with `/`.
+        if is_get && !request_url.path().ends_with('/')
+            && self.handler.is_directory(&path, &params, Headers(request.headers()))
+        {
+            // The last segment, still percent-encoded.
+            let leaf = request_url.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default();
+            let query = request_url.query().map_or(String::new(), |query| format!("?{}", query));
+            return Ok(HttpOkay::Redirect {location: format!("{}/{}", leaf, query), permanent: true});
         }
         // Dispatch based on HTTP method.
         match request.method() {
-            Method::Get => self.handler.handle_get(path, params),
-            _ => Err(HttpError::Invalid),
+            _ if is_get => self.handler.handle_get(path, params, Headers(request.headers())),
+            Method::Post => {
+                // Refuse early if we can.
+                if request.body_length().is_some_and(|length| length as u64 > self.max_body_bytes) {
+                    return Err(HttpError::PayloadTooLarge);
+                }
+                // `as_reader()` borrows `request` mutably, so copy the headers.
+                let headers = request.headers().to_vec();
+                let mut body = LimitedBody {inner: request.as_reader(), remaining: self.max_body_bytes};
+                self.handler.handle_post(path, params, Headers(&headers), &mut body)
+            },
+            _ => Err(HttpError::MethodNotAllowed),
         }
     }
 
+    /// Checks the `Authorization` header in `headers` against
+    /// `self.credentials`, in constant time.
+    fn is_authorized(&self, headers: Headers) -> bool {
+        let Some(credentials) = &self.credentials else { return true; };
+        headers.basic_auth().is_some_and(
+            |(user, password)| constant_time_eq(format!("{}:{}", user, password).as_bytes(), credentials.as_bytes())
+        )
+    }
+
     /// Construct an HTTP header.
     fn header(key: &str, value: &str) -> tiny_http::Header {
         Header::from_bytes(
@@ -188,46 +763,885 @@ impl<H: Handler> Server<H> {
         ).unwrap() // depends only on data fixed at compile time
     }
 
-    /// Handle requests for ever.
-    pub fn handle_requests(&self) -> ! {
-        for mut request in self.server.incoming_requests() {
-            match self.handle_request(&mut request) {
-                Ok(HttpOkay::File(file)) => {
-                    request.respond(Response::from_file(file))
-                },
-                Ok(HttpOkay::Html(text)) => {
-                    let header = Self::header("Content-Type", "text/html");
-                    request.respond(Response::from_string(text).with_header(header))
-                },
-                Ok(HttpOkay::Jpeg(data)) => {
-                    let header = Self::header("Content-Type", "image/jpeg");
-                    request.respond(Response::from_data(data).with_header(header))
-                },
-                Err(HttpError::Invalid) => {
-                    request.respond(Response::from_string("Invalid request").with_status_code(400))
-                },
-                Err(HttpError::NotFound) => {
-                    request.respond(Response::from_string("Not found").with_status_code(404))
-                },
-                Err(HttpError::Error(e)) => {
-                    println!("Error: {}", e);
-                    request.respond(Response::from_string("Server error").with_status_code(500))
+    /// Send `response` with extra `headers`.
+    fn respond<R: Read>(
+        request: Request,
+        mut response: Response<R>,
+        headers: Vec<Header>,
+    ) -> std::io::Result<Sent> {
+        for header in headers { response.add_header(header); }
+        let status = response.status_code().0;
+        // `tiny_http` omits the body of the response to a `HEAD` request.
+        let bytes = if *request.method() == Method::Head { Some(0) } else { response.data_length() };
+        request.respond(response)?;
+        Ok(Sent {status, bytes})
+    }
+
+    /// Send `text` with extra `headers`, compressed with `encoding` if it is
+    /// long enough.
+    fn respond_text(
+        request: Request,
+        text: String,
+        content_type: &str,
+        encoding: Encoding,
+        mut headers: Vec<Header>,
+    ) -> std::io::Result<Sent> {
+        headers.push(Self::header("Content-Type", content_type));
+        headers.push(Self::header("Vary", "Accept-Encoding"));
+        if encoding == Encoding::Identity || text.len() < MIN_COMPRESSED_LENGTH {
+            return Self::respond(request, Response::from_string(text), headers);
+        }
+        let data = encoding.encode(text.as_bytes())?;
+        headers.push(Self::header("Content-Encoding", encoding.name()));
+        Self::respond(request, Response::from_data(data), headers)
+    }
+
+    /// Send the `length` bytes of `reader` with status 200, or the part of
+    /// them requested by the `Range` header value `range`, with status 206.
+    /// If `range` cannot be satisfied, send status 416.
+    fn respond_ranged(
+        request: Request,
+        mut reader: Box<dyn Read + Send>,
+        length: u64,
+        range: Option<&str>,
+        mut headers: Vec<Header>,
+    ) -> std::io::Result<Sent> {
+        headers.push(Self::header("Accept-Ranges", "bytes"));
+        let Some(range) = range.and_then(|range| parse_range(range, length)) else {
+            let response = Response::new(200.into(), Vec::new(), reader, usize::try_from(length).ok(), None);
+            return Self::respond(request, response, headers);
+        };
+        let Some((start, end)) = range else {
+            headers.push(Self::header("Content-Range", &format!("bytes */{}", length)));
+            return Self::respond(request, Response::empty(416), headers);
+        };
+        // Skip to `start`.
+        std::io::copy(&mut (&mut reader).take(start), &mut std::io::sink())?;
+        headers.push(Self::header("Content-Range", &format!("bytes {}-{}/{}", start, end, length)));
+        let part = end + 1 - start;
+        let response = Response::new(206.into(), Vec::new(), reader.take(part), usize::try_from(part).ok(), None);
+        Self::respond(request, response, headers)
+    }
+
+    /// Send `data` like `respond_ranged()`.
+    fn respond_data(
+        request: Request,
+        data: Vec<u8>,
+        range: Option<&str>,
+        headers: Vec<Header>,
+    ) -> std::io::Result<Sent> {
+        let length = data.len() as u64;
+        Self::respond_ranged(request, Box::new(Cursor::new(data)), length, range, headers)
+    }
+
+    /// Send an error response with `status` and `message`, in `format`.
+    fn respond_error(
+        &self,
+        request: Request,
+        status: u16,
+        message: &str,
+        format: ErrorFormat,
+        mut headers: Vec<Header>,
+    ) -> std::io::Result<Sent> {
+        let (body, content_type) = match format {
+            ErrorFormat::Json => {
+                (serde_json::json!({"error": message, "status": status}).to_string(), "application/json")
+            },
+            ErrorFormat::Html if status == 404 => match self.handler.error_html(status, message, request.url()) {
+                Some(html) => (html, "text/html"),
+                None => (message.into(), "text/plain; charset=utf-8"),
+            },
+            _ => (message.into(), "text/plain; charset=utf-8"),
+        };
+        headers.push(Self::header("Content-Type", content_type));
+        Self::respond(request, Response::from_string(body).with_status_code(status), headers)
+    }
+
+    /// Handle requests, `threads` at a time, until `STOP` is set.
+    pub fn handle_requests(&self, threads: usize) where H: Sync {
+        std::thread::scope(|scope| {
+            for _ in 0..threads { scope.spawn(|| self.work()); }
+            self.dispatch();
+        });
+    }
+
+    /// Receive requests and queue them for `work()` until `STOP` is set.
+    ///
+    /// Beyond `max_requests` in flight, a request waits up to `QUEUE_WAIT`
+    /// for others to finish, and then gets `503 Service Unavailable`.
+    fn dispatch(&self) {
+        while !STOP.load(Ordering::Relaxed) {
+            match self.server.recv_timeout(STOP_INTERVAL) {
+                Ok(Some(request)) => {
+                    self.queue.lock().unwrap().waiting.push_back((Instant::now(), request));
+                    self.queue_changed.notify_one();
                 },
-            }.unwrap_or_else(|e2| println!("IO Error: {}", e2));
+                Ok(None) => {},
+                Err(e) => println!("IO Error: {}", e),
+            }
+            let expired = self.queue.lock().unwrap().expired(self.max_requests);
+            for request in expired { self.answer(request, |_| Err(HttpError::Unavailable)); }
+        }
+    }
+
+    /// Handle queued requests in the calling thread until `STOP` is set and
+    /// none are left.
+    fn work(&self) {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some((_, request)) = queue.waiting.pop_front() {
+                queue.busy += 1;
+                drop(queue);
+                self.handle(request);
+                queue = self.queue.lock().unwrap();
+                queue.busy -= 1;
+            } else if STOP.load(Ordering::Relaxed) {
+                return;
+            } else {
+                queue = self.queue_changed.wait_timeout(queue, STOP_INTERVAL).unwrap().0;
+            }
+        }
+    }
+
+    /// Handle `request`, logging it and any errors.
+    fn handle(&self, request: Request) {
+        self.answer(request, |request| self.handle_request(request));
+    }
+
+    /// Answer `request` with the result of `f`, logging it and any errors.
+    fn answer(&self, mut request: Request, f: impl FnOnce(&mut Request) -> Result<HttpOkay, HttpError>) {
+        let start = Instant::now();
+        let format = ErrorFormat::negotiate(&request);
+        let encoding = Encoding::negotiate(Headers(request.headers()).get("Accept-Encoding"));
+        let id = request_id();
+        let entry = LogEntry::new(&id, &request);
+        let is_post = *request.method() == Method::Post;
+        let range = Headers(request.headers()).get("Range").map(String::from);
+        let if_range = Headers(request.headers()).get("If-Range").map(String::from);
+        let if_none_match = Headers(request.headers()).get("If-None-Match").map(String::from);
+        let mut result = f(&mut request);
+        // Peel off any extra headers.
+        let mut headers = vec![Self::header("X-Request-Id", &id)];
+        while let Ok(HttpOkay::Header(okay, key, value)) = result {
+            match Header::from_bytes(key.as_bytes(), value.as_bytes()) {
+                Ok(header) => { headers.push(header); },
+                Err(()) => { println!("{} Error: Invalid header {:?}: {:?}", id, key, value); },
+            }
+            result = Ok(*okay);
+        }
+        // Give in-memory images a strong entity tag, if the handler did
+        // not, so that clients can revalidate them.
+        if let Ok(HttpOkay::Jpeg(data) | HttpOkay::Png(data) | HttpOkay::WebP(data) | HttpOkay::Avif(data)) = &result
+            && !headers.iter().any(|h| h.field.equiv("ETag"))
+        {
+            headers.push(Self::header("ETag", &format!("\"{:016x}\"", hash(data))));
+        }
+        // Answer `If-None-Match` for any response with an entity tag.
+        if let Some(tags) = &if_none_match {
+            let fresh = headers.iter().any(|h| h.field.equiv("ETag") && etag_matches(tags, h.value.as_str()));
+            if fresh && result.is_ok() { result = Ok(HttpOkay::NotModified); }
+        }
+        // Ignore `Range` if `If-Range` names another version.
+        let range = range.filter(|_| if_range.as_ref().is_none_or(|if_range| headers.iter().any(
+            |h| (h.field.equiv("ETag") || h.field.equiv("Last-Modified")) && h.value.as_str() == if_range
+        )));
+        let range = range.as_deref();
+        let sent = match result {
+            Ok(HttpOkay::File {file, content_type}) => {
+                match Header::from_bytes("Content-Type", content_type.as_bytes()) {
+                    Ok(header) => { headers.push(header); },
+                    Err(()) => { println!("{} Error: Invalid Content-Type {:?}", id, content_type); },
+                }
+                match file.metadata() {
+                    Ok(metadata) => Self::respond_ranged(request, Box::new(file), metadata.len(), range, headers),
+                    Err(_) => Self::respond(request, Response::from_file(file), headers),
+                }
+            },
+            Ok(HttpOkay::Stream {reader, content_type, length}) => {
+                match Header::from_bytes("Content-Type", content_type.as_bytes()) {
+                    Ok(header) => { headers.push(header); },
+                    Err(()) => { println!("{} Error: Invalid Content-Type {:?}", id, content_type); },
+                }
+                if let Some(length) = length {
+                    Self::respond_ranged(request, reader, length, range, headers)
+                } else {
+                    Self::respond(request, Response::new(200.into(), Vec::new(), reader, None, None), headers)
+                }
+            },
+            Ok(HttpOkay::Html(text)) => {
+                Self::respond_text(request, text, "text/html", encoding, headers)
+            },
+            Ok(HttpOkay::Jpeg(data)) => {
+                headers.push(Self::header("Content-Type", "image/jpeg"));
+                Self::respond_data(request, data, range, headers)
+            },
+            Ok(HttpOkay::Png(data)) => {
+                headers.push(Self::header("Content-Type", "image/png"));
+                Self::respond_data(request, data, range, headers)
+            },
+            Ok(HttpOkay::WebP(data)) => {
+                headers.push(Self::header("Content-Type", "image/webp"));
+                Self::respond_data(request, data, range, headers)
+            },
+            Ok(HttpOkay::Avif(data)) => {
+                headers.push(Self::header("Content-Type", "image/avif"));
+                Self::respond_data(request, data, range, headers)
+            },
+            Ok(HttpOkay::Json(text)) => {
+                Self::respond_text(request, text, "application/json", encoding, headers)
+            },
+            Ok(HttpOkay::Xml(text)) => {
+                headers.push(Self::header("Content-Type", "application/xml"));
+                Self::respond(request, Response::from_string(text), headers)
+            },
+            Ok(HttpOkay::Text(text)) => {
+                Self::respond_text(request, text, "text/plain; charset=utf-8", encoding, headers)
+            },
+            Ok(HttpOkay::NoContent) => {
+                Self::respond(request, Response::empty(204), headers)
+            },
+            Ok(HttpOkay::NotModified) => {
+                Self::respond(request, Response::empty(304), headers)
+            },
+            Ok(HttpOkay::Redirect {location, permanent}) => {
+                match Header::from_bytes("Location", location.as_bytes()) {
+                    Ok(header) => { headers.push(header); },
+                    Err(()) => { println!("{} Error: Invalid Location {:?}", id, location); },
+                }
+                Self::respond(request, Response::empty(if permanent { 301 } else { 302 }), headers)
+            },
+            Ok(HttpOkay::Header(..)) => unreachable!(), // Peeled off above.
+            Err(HttpError::Invalid) => {
+                self.respond_error(request, 400, "Invalid request", format, headers)
+            },
+            Err(HttpError::Unauthorized) => {
+                headers.push(Self::header("WWW-Authenticate", &format!("Basic realm=\"{}\", charset=\"UTF-8\"", REALM)));
+                self.respond_error(request, 401, "Unauthorized", format, headers)
+            },
+            Err(HttpError::Forbidden) => {
+                self.respond_error(request, 403, "Forbidden", format, headers)
+            },
+            Err(HttpError::NotFound) => {
+                self.respond_error(request, 404, "Not found", format, headers)
+            },
+            Err(HttpError::MethodNotAllowed) => {
+                // If the handler refused a POST request, only the other
+                // methods can succeed.
+                let allow = if is_post { ALLOWED_GET_METHODS } else { ALLOWED_METHODS };
+                headers.push(Self::header("Allow", allow));
+                self.respond_error(request, 405, "Method not allowed", format, headers)
+            },
+            Err(HttpError::Unavailable) => {
+                headers.push(Self::header("Retry-After", "1"));
+                self.respond_error(request, 503, "Service unavailable", format, headers)
+            },
+            Err(HttpError::PreconditionFailed) => {
+                self.respond_error(request, 412, "Precondition failed", format, headers)
+            },
+            Err(HttpError::PayloadTooLarge) => {
+                self.respond_error(request, 413, "Payload too large", format, headers)
+            },
+            Err(HttpError::UnsupportedMedia) => {
+                self.respond_error(request, 415, "Unsupported media type", format, headers)
+            },
+            Err(HttpError::Error(e)) => {
+                println!("{} Error: {}", id, e);
+                let text = format!("Server error; request ID {}", id);
+                self.respond_error(request, 500, &text, format, headers)
+            },
+        };
+        match sent {
+            Ok(sent) => println!("{}", entry.format(self.log_format, &sent, start.elapsed())),
+            Err(e2) => println!("{} IO Error: {}", id, e2),
+        }
+    }
+}
+
+// ----------------------------------------------------------------------------
+
+/// The format in which to send an error response.
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ErrorFormat {
+    /// A page from `Handler::error_html()`, for browsers. Only `404` gets
+    /// one; other errors are sent as `Text`.
+    Html,
+
+    /// `{"error": <message>, "status": <status>}`, for API clients.
+    Json,
+
+    /// The message alone.
+    Text,
+}
+
+impl ErrorFormat {
+    /// Choose the format requested by `?format=json` or by the `Accept`
+    /// header of `request`.
+    fn negotiate(request: &Request) -> Self {
+        let query = request.url().split_once('?').map_or("", |(_, query)| query);
+        if url::form_urlencoded::parse(query.as_bytes()).any(|(key, value)| key == "format" && value == "json") {
+            return Self::Json;
+        }
+        let accept = Headers(request.headers()).get("Accept").unwrap_or("");
+        if accept.contains("application/json") {
+            Self::Json
+        } else if accept.contains("text/html") {
+            Self::Html
+        } else {
+            Self::Text
         }
-        unreachable!();
     }
 }
 
-/// Run for ever!
+// ----------------------------------------------------------------------------
+
+/// The format of the line logged for each request, once it has been answered.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum LogFormat {
+    /// `<id> <ip> <method> <url> <status> <bytes> <duration>ms`.
+    Plain,
+
+    /// The Common Log Format of Apache and other web servers, which log
+    /// analysers understand. The request ID is omitted.
+    Clf,
+
+    /// A JSON object with fields `id`, `ip`, `user`, `time` (seconds since
+    /// 1970), `method`, `url`, `status`, `bytes` and `ms`.
+    Json,
+}
+
+impl LogFormat {
+    /// Parse `"plain"`, `"clf"` or `"json"`.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "plain" => Some(Self::Plain),
+            "clf" => Some(Self::Clf),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What `Server` sent in answer to a request.
+struct Sent {
+    /// The status code.
+    status: u16,
+
+    /// The length of the body, if it was known in advance.
+    bytes: Option<usize>,
+}
+
+/// The parts of a request that are logged.
+struct LogEntry {
+    id: String,
+    ip: Option<String>,
+    user: Option<String>,
+    time: SystemTime,
+    method: String,
+    url: String,
+    http_version: String,
+}
+
+impl LogEntry {
+    fn new(id: &str, request: &Request) -> Self {
+        LogEntry {
+            id: id.into(),
+            ip: client_ip(request).map(|ip| ip.to_string()),
+            user: Headers(request.headers()).basic_auth().map(|(user, _)| user),
+            time: SystemTime::now(),
+            method: request.method().to_string(),
+            url: request.url().into(),
+            http_version: request.http_version().to_string(),
+        }
+    }
+
+    /// Describe the request and `sent`, which took `duration`, in `format`.
+    fn format(&self, format: LogFormat, sent: &Sent, duration: Duration) -> String {
+        let ms = duration.as_secs_f64() * 1000.0;
+        match format {
+            LogFormat::Plain => format!(
+                "{} {} {} {} {} {} {:.1}ms",
+                self.id, self.ip.as_deref().unwrap_or("-"), self.method, self.url,
+                sent.status, sent.bytes.map_or("-".into(), |bytes| bytes.to_string()), ms,
+            ),
+            LogFormat::Clf => format!(
+                "{} - {} [{}] \"{}\" {} {}",
+                self.ip.as_deref().unwrap_or("-"),
+                self.user.as_deref().filter(|user| !user.is_empty()).map_or("-".into(), clf_escape),
+                clf_date(self.time),
+                clf_escape(&format!("{} {} HTTP/{}", self.method, self.url, self.http_version)),
+                sent.status, sent.bytes.map_or("-".into(), |bytes| bytes.to_string()),
+            ),
+            LogFormat::Json => serde_json::json!({
+                "id": self.id,
+                "ip": self.ip,
+                "user": self.user,
+                "time": self.time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+                "method": self.method,
+                "url": self.url,
+                "status": sent.status,
+                "bytes": sent.bytes,
+                "ms": (ms * 10.0).round() / 10.0,
+            }).to_string(),
+        }
+    }
+}
+
+/// Returns the address of the client that sent `request`, if known.
 ///
-/// - server_address - E.g. "127.0.0.1:8082".
+/// If `request` came from this host, or over a socket without an address,
+/// it is assumed to come from a reverse proxy, and the address is the last
+/// one in `X-Forwarded-For`, which the proxy added. Other addresses in it are
+/// ignored, since clients can forge them.
+fn client_ip(request: &Request) -> Option<IpAddr> {
+    let peer = request.remote_addr().map(|address| address.ip());
+    if peer.is_none_or(|ip| ip.is_loopback()) {
+        let forwarded = request.headers().iter().rev().find(|h| h.field.equiv("X-Forwarded-For")).and_then(
+            |h| h.value.as_str().rsplit(',').next()?.trim().parse().ok()
+        );
+        if forwarded.is_some() { return forwarded; }
+    }
+    peer
+}
+
+/// Escape `"`, `\` and non-printable characters in `s` as Apache does, so
+/// that a client cannot forge a line of the log.
+fn clf_escape(s: &str) -> String {
+    let mut ret = String::new();
+    for c in s.chars() {
+        match c {
+            '"' | '\\' => { ret.push('\\'); ret.push(c); },
+            c if c.is_control() => { ret.push_str(&format!("\\x{:02x}", c as u32)); },
+            c => ret.push(c),
+        }
+    }
+    ret
+}
+
+// ----------------------------------------------------------------------------
+
+/// Parse the value of a `Range` header, for a body that is `length` bytes
+/// long.
+///
+/// Returns `None` if the whole body should be sent, e.g. because `range` is
+/// not a single byte range. Otherwise, returns `Some` of the first and last
+/// byte positions requested, or `Some(None)` if the range is unsatisfiable.
+fn parse_range(range: &str, length: u64) -> Option<Option<(u64, u64)>> {
+    let range = range.trim().strip_prefix("bytes=")?;
+    if range.contains(',') { return None; }
+    let (first, last) = range.split_once('-')?;
+    let (first, last) = (first.trim(), last.trim());
+    Some(if first.is_empty() {
+        // The last `last` bytes.
+        let suffix = last.parse::<u64>().ok()?;
+        if suffix == 0 || length == 0 { None } else { Some((length.saturating_sub(suffix), length - 1)) }
+    } else {
+        let first = first.parse::<u64>().ok()?;
+        let last = if last.is_empty() { u64::MAX } else { last.parse::<u64>().ok()? };
+        if last < first { return None; }
+        if first >= length { None } else { Some((first, last.min(length - 1))) }
+    })
+}
+
+// ----------------------------------------------------------------------------
+
+/// Run until `STOP` is set.
+///
+/// - listener - The socket on which to serve.
 /// - base_url - The publicly visible URL of this web server, if any. It should
 ///   end with `/`. This is useful for constructing absolute URLs.
-///   If `server_address` is public, `base_url` can be omitted.
+///   If `listener` is public, `base_url` can be omitted.
+/// - threads - The number of requests to handle at once, each in its own
+///   thread.
+/// - max_requests - The number of requests that may be waiting or being
+///   handled. Beyond it, a request waits briefly, then gets
+///   `503 Service Unavailable`.
+/// - credentials - `(user, password)`, if every request must send them using
+///   HTTP basic authentication.
+/// - max_body_bytes - The maximum length of a request body. Longer ones get
+///   `413 Payload Too Large`.
+/// - log_format - How to log each request, once it has been answered.
 /// - handler - Defines the web application.
-pub fn start(server_address: String, base_url: Option<String>, handler: impl Handler) -> ! {
-    let server = Server::new(&server_address, base_url.as_ref().map(AsRef::as_ref), handler);
-    println!("Listening on {}", server.server_url);
-    server.handle_requests();
+#[allow(clippy::too_many_arguments)] // Each is a separate setting.
+pub fn start(
+    listener: Listener,
+    base_url: Option<String>,
+    threads: usize,
+    max_requests: usize,
+    credentials: Option<(String, String)>,
+    max_body_bytes: u64,
+    log_format: LogFormat,
+    handler: impl Handler + Sync,
+) {
+    let credentials = credentials.map(|(user, password)| format!("{}:{}", user, password));
+    let server = Server::new(listener, base_url.as_deref(), credentials, max_body_bytes, log_format, max_requests, handler);
+    println!("Listening on {} with {} threads", server.server_url, threads);
+    server.handle_requests(threads);
+    println!("Stopped");
+}
+
+// ----------------------------------------------------------------------------
+
+/// Helpers for tests that make real HTTP requests to a `Handler`.
+#[cfg(test)]
+pub mod testing {
+    use std::io::{Read, Write};
+    use std::net::{TcpStream};
+
+    use super::{Handler, Listener, LogFormat, Server, Url};
+
+    /// A `Server` that handles requests on a background thread until the
+    /// tests finish.
+    pub struct TestServer {
+        /// The local URL of the server, including the port that the OS chose.
+        pub url: Url,
+    }
+
+    impl TestServer {
+        /// Serve `handler` from `listener`, e.g. `Listener::new("127.0.0.1:0")`.
+        pub fn start(listener: Listener, handler: impl Handler + Send + Sync + 'static) -> Self {
+            Self::start_limited(listener, 2, 100, handler)
+        }
+
+        /// Like `start()`, with `threads` threads and room for
+        /// `max_requests` requests.
+        pub fn start_limited(
+            listener: Listener,
+            threads: usize,
+            max_requests: usize,
+            handler: impl Handler + Send + Sync + 'static,
+        ) -> Self {
+            let url = listener.url().clone();
+            let server = Server::new(listener, None, None, 1 << 20, LogFormat::Plain, max_requests, handler);
+            std::thread::spawn(move || server.handle_requests(threads));
+            TestServer {url}
+        }
+
+        /// Like `start()`, but every request must log in as `user` with
+        /// `password`.
+        pub fn start_authorized(
+            listener: Listener,
+            user: &str,
+            password: &str,
+            handler: impl Handler + Send + Sync + 'static,
+        ) -> Self {
+            let url = listener.url().clone();
+            let credentials = Some(format!("{}:{}", user, password));
+            let server = Server::new(listener, None, credentials, 1 << 20, LogFormat::Plain, 100, handler);
+            std::thread::spawn(move || server.handle_requests(2));
+            TestServer {url}
+        }
+
+        /// Send a request and wait for the whole response.
+        ///
+        /// - method - E.g. `"GET"`.
+        /// - path - The URL relative to the server, e.g. `"/foo?bar=baz"`.
+        /// - headers - Extra request headers.
+        /// - body - The request body, of which `Content-Length` is sent.
+        pub fn request(&self, method: &str, path: &str, headers: &[(&str, &str)], body: &[u8]) -> TestResponse {
+            let address = format!("{}:{}", self.url.host_str().unwrap(), self.url.port().unwrap());
+            let mut stream = TcpStream::connect(address).expect("Could not connect to the test server");
+            let mut request = format!(
+                "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+                method, path, body.len(),
+            );
+            for (key, value) in headers { request.push_str(&format!("{}: {}\r\n", key, value)); }
+            request.push_str("\r\n");
+            stream.write_all(request.as_bytes()).unwrap();
+            stream.write_all(body).unwrap();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            TestResponse::parse(&response)
+        }
+
+        /// Send a GET request with `headers`.
+        pub fn get(&self, path: &str, headers: &[(&str, &str)]) -> TestResponse {
+            self.request("GET", path, headers, b"")
+        }
+    }
+
+    /// A response received by `TestServer::request()`.
+    pub struct TestResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+
+        /// The body, with any chunked transfer encoding removed.
+        pub body: Vec<u8>,
+    }
+
+    impl TestResponse {
+        fn parse(response: &[u8]) -> Self {
+            let end = response.windows(4).position(|w| w == b"\r\n\r\n").expect("Truncated response");
+            let head = std::str::from_utf8(&response[..end]).unwrap();
+            let mut lines = head.split("\r\n");
+            let status = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+            let headers = lines.map(|line| {
+                let (key, value) = line.split_once(':').unwrap();
+                (key.trim().into(), value.trim().into())
+            }).collect();
+            let mut ret = TestResponse {status, headers, body: response[end + 4..].to_vec()};
+            if ret.header("Transfer-Encoding") == Some("chunked") && !ret.body.is_empty() {
+                ret.body = dechunk(&ret.body);
+            }
+            ret
+        }
+
+        /// Returns the value of the first header called `name`, ignoring case.
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(key, _)| key.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str())
+        }
+
+        /// Returns the body, which must be UTF-8.
+        pub fn text(&self) -> &str { std::str::from_utf8(&self.body).expect("The body is not UTF-8") }
+    }
+
+    /// Undo `Transfer-Encoding: chunked`.
+    fn dechunk(mut data: &[u8]) -> Vec<u8> {
+        let mut ret = Vec::new();
+        loop {
+            let line_end = data.windows(2).position(|w| w == b"\r\n").expect("Truncated chunk");
+            let size = std::str::from_utf8(&data[..line_end]).unwrap().split(';').next().unwrap();
+            let size = usize::from_str_radix(size.trim(), 16).unwrap();
+            data = &data[line_end + 2..];
+            if size == 0 { return ret; }
+            ret.extend_from_slice(&data[..size]);
+            data = &data[size + 2..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap};
+    use std::sync::{Arc};
+
+    use super::*;
+    use super::testing::{TestResponse, TestServer};
+
+    /// Answers `GET /hello?name=...` and nothing else.
+    struct Hello;
+
+    impl Handler for Hello {
+        type Params = HashMap<String, String>;
+
+        fn handle_get(&self, path: Vec<String>, params: Self::Params, _headers: Headers) -> Result<HttpOkay, HttpError> {
+            match path.as_slice() {
+                [hello] if hello == "hello" => {
+                    let name = params.get("name").map_or("stranger", String::as_str);
+                    Ok(HttpOkay::Text(format!("Hello, {}!", name)))
+                },
+                _ => Err(HttpError::NotFound),
+            }
+        }
+    }
+
+    #[test]
+    fn ephemeral_port() {
+        let listener = Listener::new("127.0.0.1:0");
+        assert_ne!(listener.url().port(), Some(0));
+        let server = TestServer::start(listener, Hello);
+        let response = server.get("/hello?name=world", &[]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+        assert_eq!(response.text(), "Hello, world!");
+        assert_eq!(server.get("/goodbye", &[]).status, 404);
+    }
+
+    /// Answers `GET /wait` once `open()` has been called, and `GET /now`
+    /// immediately.
+    #[derive(Clone, Default)]
+    struct Gate(Arc<(Mutex<bool>, Condvar)>);
+
+    impl Gate {
+        fn open(&self) {
+            *self.0.0.lock().unwrap() = true;
+            self.0.1.notify_all();
+        }
+    }
+
+    impl Handler for Gate {
+        type Params = HashMap<String, String>;
+
+        fn handle_get(&self, path: Vec<String>, _params: Self::Params, _headers: Headers) -> Result<HttpOkay, HttpError> {
+            match path.as_slice() {
+                [wait] if wait == "wait" => {
+                    let _open = self.0.1.wait_while(self.0.0.lock().unwrap(), |open| !*open).unwrap();
+                    Ok(HttpOkay::Text("Waited".into()))
+                },
+                [now] if now == "now" => Ok(HttpOkay::Text("Now".into())),
+                _ => Err(HttpError::NotFound),
+            }
+        }
+    }
+
+    #[test]
+    fn max_requests() {
+        let gate = Gate::default();
+        let server = Arc::new(TestServer::start_limited(Listener::new("127.0.0.1:0"), 1, 2, gate.clone()));
+        let get = |path: &'static str| {
+            let server = server.clone();
+            let thread = std::thread::spawn(move || server.get(path, &[]));
+            std::thread::sleep(Duration::from_millis(100));
+            thread
+        };
+        // One being handled, and one waiting for the thread.
+        let busy = get("/wait");
+        let waiting = get("/now");
+        // One too many.
+        let start = Instant::now();
+        let refused = server.get("/now", &[]);
+        assert!(start.elapsed() >= QUEUE_WAIT);
+        assert_eq!(refused.status, 503);
+        assert_eq!(refused.header("Retry-After"), Some("1"));
+        gate.open();
+        assert_eq!(busy.join().unwrap().text(), "Waited");
+        assert_eq!(waiting.join().unwrap().text(), "Now");
+        assert_eq!(server.get("/now", &[]).status, 200);
+    }
+
+    /// Builds the headers of a request.
+    fn headers(headers: &[(&str, &str)]) -> Vec<Header> {
+        headers.iter().map(|(key, value)| Header::from_bytes(*key, *value).unwrap()).collect()
+    }
+
+    #[test]
+    fn if_match() {
+        let if_match = |value: Option<&str>, etag: Option<&str>| {
+            let headers = headers(value.map(|value| ("If-Match", value)).as_slice());
+            Headers(&headers).if_match(etag)
+        };
+        assert!(if_match(None, Some("\"a\"")));
+        assert!(if_match(None, None));
+        assert!(if_match(Some("\"a\""), Some("\"a\"")));
+        assert!(if_match(Some("\"b\", \"a\""), Some("\"a\"")));
+        assert!(!if_match(Some("\"b\""), Some("\"a\"")));
+        assert!(!if_match(Some("W/\"a\""), Some("\"a\"")));
+        assert!(!if_match(Some("\"a\""), None));
+        assert!(if_match(Some("*"), Some("\"a\"")));
+        assert!(!if_match(Some("*"), None));
+    }
+
+    #[test]
+    fn options_and_allow() {
+        let server = TestServer::start(Listener::new("127.0.0.1:0"), Hello);
+        for path in ["*", "/hello", "/goodbye"] {
+            let response = server.request("OPTIONS", path, &[], b"");
+            assert_eq!(response.status, 204, "{}", path);
+            assert_eq!(response.header("Allow"), Some(ALLOWED_METHODS), "{}", path);
+            assert!(response.body.is_empty());
+        }
+        let response = server.request("DELETE", "/hello", &[], b"");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some(ALLOWED_METHODS));
+        // `Hello` does not accept POST.
+        let response = server.request("POST", "/hello", &[], b"");
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("Allow"), Some(ALLOWED_GET_METHODS));
+    }
+
+    /// Answers every `GET` with the path segments and sorted parameters.
+    struct Echo;
+
+    impl Handler for Echo {
+        type Params = Vec<(String, String)>;
+
+        fn handle_get(&self, path: Vec<String>, mut params: Self::Params, _headers: Headers) -> Result<HttpOkay, HttpError> {
+            params.sort();
+            Ok(HttpOkay::Text(format!("{:?} {:?}", path, params)))
+        }
+    }
+
+    #[test]
+    fn stray_separators() {
+        let server = TestServer::start(Listener::new("127.0.0.1:0"), Echo);
+        let echo = |path: &str| server.get(path, &[]).text().to_owned();
+        assert_eq!(echo("/a/b"), r#"["a", "b"] []"#);
+        for path in ["/a//b", "/a/b/", "//a/b//", "/a/b?", "/a/b?&", "/a/b?&&=&", "/a/b?x=", "/a/b?=x", "/a/b#c", "/a/b?#c?x=1"] {
+            assert_eq!(echo(path), r#"["a", "b"] []"#, "{}", path);
+        }
+        assert_eq!(echo("/a/b?&x=1&&y=2&"), r#"["a", "b"] [("x", "1"), ("y", "2")]"#);
+        assert_eq!(echo("/a/b?x=1&y=#y=2"), r#"["a", "b"] [("x", "1")]"#);
+        // Escapes are decoded.
+        assert_eq!(echo("/a%20b/c?x=%26%3D&y+z=1+2"), r#"["a b", "c"] [("x", "&="), ("y z", "1 2")]"#);
+        assert_eq!(echo("/"), "[] []");
+    }
+
+    #[test]
+    fn is_fresh_if_none_match() {
+        let is_fresh = |pairs: &[(&str, &str)]| Headers(&headers(pairs)).is_fresh("\"a\"", "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert!(!is_fresh(&[]));
+        assert!(is_fresh(&[("If-None-Match", "\"a\"")]));
+        assert!(is_fresh(&[("If-None-Match", "\"b\", \"a\"")]));
+        // Weak comparison.
+        assert!(is_fresh(&[("If-None-Match", "W/\"a\"")]));
+        assert!(is_fresh(&[("If-None-Match", "*")]));
+        assert!(!is_fresh(&[("If-None-Match", "\"b\"")]));
+        assert!(!is_fresh(&[("If-None-Match", "a")]));
+        // It takes precedence over `If-Modified-Since`.
+        assert!(!is_fresh(&[("If-None-Match", "\"b\""), ("If-Modified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")]));
+    }
+
+    #[test]
+    fn json_errors() {
+        let server = TestServer::start(Listener::new("127.0.0.1:0"), Hello);
+        let json = |response: TestResponse| {
+            assert_eq!(response.header("Content-Type"), Some("application/json"));
+            serde_json::from_slice::<serde_json::Value>(&response.body).unwrap()
+        };
+        let expected = serde_json::json!({"error": "Not found", "status": 404});
+        let response = server.get("/goodbye", &[("Accept", "application/json")]);
+        assert_eq!(response.status, 404);
+        assert_eq!(json(response), expected);
+        let response = server.get("/goodbye?format=json", &[("Accept", "text/html")]);
+        assert_eq!(response.status, 404);
+        assert_eq!(json(response), expected);
+        let response = server.request("POST", "/hello", &[("Accept", "application/json")], b"");
+        assert_eq!(response.status, 405);
+        assert_eq!(json(response), serde_json::json!({"error": "Method not allowed", "status": 405}));
+        // Otherwise, plain text.
+        for accept in ["text/html", "*/*"] {
+            let response = server.get("/goodbye", &[("Accept", accept)]);
+            assert_eq!(response.status, 404);
+            assert_eq!(response.header("Content-Type"), Some("text/plain; charset=utf-8"));
+            assert_eq!(response.text(), "Not found");
+        }
+    }
+
+    #[test]
+    fn is_fresh_if_modified_since() {
+        let modified = "Sun, 06 Nov 1994 08:49:37 GMT";
+        let is_fresh = |since: &str| Headers(&headers(&[("If-Modified-Since", since)])).is_fresh("\"a\"", modified);
+        assert!(is_fresh(modified));
+        assert!(is_fresh("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(is_fresh("Mon, 01 Jan 2024 00:00:00 GMT"));
+        assert!(!is_fresh("Sun, 06 Nov 1994 08:49:36 GMT"));
+        assert!(!is_fresh("Sat, 05 Nov 1994 08:49:37 GMT"));
+        assert!(!is_fresh("yesterday"));
+        assert!(!is_fresh(""));
+        assert!(!Headers(&[]).is_fresh("\"a\"", modified));
+        // The format written by `http_date()` can be read back.
+        let now = SystemTime::now();
+        assert!(Headers(&headers(&[("If-Modified-Since", &http_date(now))])).is_fresh("\"a\"", &http_date(now)));
+    }
+
+    #[test]
+    fn basic_auth() {
+        let server = TestServer::start_authorized(Listener::new("127.0.0.1:0"), "alice", "open sesame", Hello);
+        let basic = |credentials: &str| format!("Basic {}", BASE64_STANDARD.encode(credentials));
+        for authorization in [None, Some(basic("alice:wrong")), Some(basic("bob:open sesame")), Some(basic("alice")), Some("Basic !!!".into())] {
+            let headers: Vec<(&str, &str)> = authorization.iter().map(|value| ("Authorization", value.as_str())).collect();
+            for method in ["GET", "HEAD", "POST", "OPTIONS"] {
+                let response = server.request(method, "/hello", &headers, b"");
+                assert_eq!(response.status, 401, "{} {:?}", method, authorization);
+                assert_eq!(response.header("WWW-Authenticate"), Some("Basic realm=\"Photos\", charset=\"UTF-8\""));
+            }
+        }
+        let alice = basic("alice:open sesame");
+        let response = server.get("/hello?name=Alice", &[("Authorization", &alice)]);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("WWW-Authenticate"), None);
+        assert_eq!(response.text(), "Hello, Alice!");
+        // The scheme is case-insensitive.
+        assert_eq!(server.get("/hello", &[("Authorization", &alice.replace("Basic", "basic"))]).status, 200);
+        assert_eq!(server.get("/goodbye", &[("Authorization", &alice)]).status, 404);
+    }
 }
@@ -0,0 +1,115 @@
+//! Reading the integer, text and fractional tags of TIFF files.
+//!
+//! EXIF metadata is a TIFF file, and so are many RAW formats. A TIFF file
+//! contains a tree of "IFD"s, each a list of tagged values.
+
+/// A TIFF file in memory.
+pub struct Tiff<'a> {
+    pub data: &'a [u8],
+    big_endian: bool,
+}
+
+/// The maximum number of values of a tag that we read.
+const MAX_VALUES: usize = 256;
+
+impl<'a> Tiff<'a> {
+    /// Returns `None` if `data` does not start with a TIFF header.
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        let big_endian = match data.get(0..4)? {
+            b"II*\0" => false,
+            b"MM\0*" => true,
+            _ => return None,
+        };
+        Some(Self {data, big_endian})
+    }
+
+    fn u16(&self, offset: usize) -> Option<u16> {
+        let bytes = self.data.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+        Some(if self.big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+    }
+
+    fn u32(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        Some(if self.big_endian { u32::from_be_bytes(bytes) } else { u32::from_le_bytes(bytes) })
+    }
+
+    /// Returns the offset of the first IFD, e.g. `IFD0` of EXIF metadata.
+    pub fn first_ifd(&self) -> Option<usize> { Some(self.u32(4)? as usize) }
+
+    /// Returns the offset of the IFD after the one at `ifd`, if any.
+    #[cfg(feature = "raw")]
+    pub fn next_ifd(&self, ifd: usize) -> Option<usize> {
+        let count = usize::from(self.u16(ifd)?);
+        let next = self.u32(ifd + 2 + 12 * count)?;
+        if next == 0 { None } else { Some(next as usize) }
+    }
+
+    /// Returns the tags of the IFD at `ifd` that have integer values, with
+    /// their values.
+    pub fn entries(&self, ifd: usize) -> Option<Vec<(u16, Vec<u32>)>> {
+        let count = usize::from(self.u16(ifd)?);
+        let mut ret = Vec::new();
+        for i in 0..count {
+            let entry = ifd + 2 + 12 * i;
+            let tag = self.u16(entry)?;
+            if let Some(values) = self.values(entry) { ret.push((tag, values)); }
+        }
+        Some(ret)
+    }
+
+    /// Returns the offset of the entry for `tag` in the IFD at `ifd`, if any.
+    fn find(&self, ifd: usize, tag: u16) -> Option<usize> {
+        let count = usize::from(self.u16(ifd)?);
+        (0..count).map(|i| ifd + 2 + 12 * i).find(|&entry| self.u16(entry) == Some(tag))
+    }
+
+    /// Returns the value of `tag` in the IFD at `ifd`, if it is ASCII text,
+    /// without its terminating NUL.
+    pub fn ascii(&self, ifd: usize, tag: u16) -> Option<&'a str> {
+        let entry = self.find(ifd, tag)?;
+        if self.u16(entry + 2)? != 2 { return None; }
+        let count = self.u32(entry + 4)? as usize;
+        let start = if count <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        let bytes = self.data.get(start..start.checked_add(count)?)?;
+        let bytes = bytes.split(|&b| b == 0).next()?;
+        std::str::from_utf8(bytes).ok()
+    }
+
+    /// Returns the first value of `tag` in the IFD at `ifd`, if it is an
+    /// unsigned fraction, as its numerator and denominator.
+    pub fn rational(&self, ifd: usize, tag: u16) -> Option<(u32, u32)> {
+        self.rationals(ifd, tag)?.first().copied()
+    }
+
+    /// Returns the values of `tag` in the IFD at `ifd`, if they are unsigned
+    /// fractions, as their numerators and denominators.
+    pub fn rationals(&self, ifd: usize, tag: u16) -> Option<Vec<(u32, u32)>> {
+        let entry = self.find(ifd, tag)?;
+        if self.u16(entry + 2)? != 5 { return None; }
+        let count = self.u32(entry + 4)? as usize;
+        if count > MAX_VALUES { return None; }
+        // Each value is 8 bytes, so it never fits in the entry itself.
+        let start = self.u32(entry + 8)? as usize;
+        (0..count).map(|i| {
+            let offset = start.checked_add(8 * i)?;
+            Some((self.u32(offset)?, self.u32(offset.checked_add(4)?)?))
+        }).collect()
+    }
+
+    /// Returns the values of the IFD entry at `entry`, if they are integers.
+    fn values(&self, entry: usize) -> Option<Vec<u32>> {
+        let size = match self.u16(entry + 2)? {
+            3 => 2, // SHORT
+            4 | 13 => 4, // LONG or IFD
+            _ => return None,
+        };
+        let count = self.u32(entry + 4)? as usize;
+        if count > MAX_VALUES { return None; }
+        // Values that fit are stored in the entry itself.
+        let start = if size * count <= 4 { entry + 8 } else { self.u32(entry + 8)? as usize };
+        (0..count).map(|i| {
+            let offset = start.checked_add(size * i)?;
+            if size == 2 { self.u16(offset).map(u32::from) } else { self.u32(offset) }
+        }).collect()
+    }
+}
@@ -0,0 +1,212 @@
+//! Writing ZIP archives of files that are already compressed, e.g. photos.
+//!
+//! The files are stored, not compressed, and the CRC of each is computed as
+//! it is read, so an archive can be streamed without reading anything twice.
+//! The size of the archive is known in advance. ZIP64 is not supported, so
+//! archives are limited to 4GiB and 65535 files.
+
+use std::fs::{File};
+use std::io::{self, Read};
+use std::path::{PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::server::{civil_from_days};
+
+/// A file to put in an archive.
+pub struct Entry {
+    /// The name of the file in the archive.
+    pub name: String,
+
+    /// The file to read.
+    pub path: PathBuf,
+
+    /// The length of the file, which must not change.
+    pub size: u64,
+
+    /// The modification time of the file.
+    pub mtime: SystemTime,
+}
+
+impl Entry {
+    /// The number of bytes that this entry adds to an archive.
+    fn archive_size(&self) -> u64 {
+        let name = self.name.len() as u64;
+        LOCAL_HEADER_SIZE + name + self.size + DESCRIPTOR_SIZE + CENTRAL_HEADER_SIZE + name
+    }
+
+    /// Returns the MS-DOS time and date of `self.mtime`, in UTC.
+    fn dos_time_date(&self) -> (u16, u16) {
+        let seconds = self.mtime.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+        let (days, seconds) = (seconds / 86400, seconds % 86400);
+        let (year, month, day) = civil_from_days(days);
+        // MS-DOS dates start in 1980.
+        if !(1980..2108).contains(&year) { return (0, 0x21); }
+        let time = ((seconds / 3600) << 11) | ((seconds / 60 % 60) << 5) | (seconds % 60 / 2);
+        let date = ((year - 1980) << 9) | ((month + 1) << 5) | day;
+        (time as u16, date as u16)
+    }
+}
+
+const LOCAL_HEADER_SIZE: u64 = 30;
+const DESCRIPTOR_SIZE: u64 = 16;
+const CENTRAL_HEADER_SIZE: u64 = 46;
+const END_SIZE: u64 = 22;
+
+/// The version of the ZIP format that we need, i.e. 2.0.
+const VERSION: u16 = 20;
+
+/// Bit 3: The sizes and CRC follow the data. Bit 11: Names are UTF-8.
+const FLAGS: u16 = 0x0808;
+
+/// The file currently being copied into the archive.
+struct Current {
+    /// The index of the entry.
+    index: usize,
+
+    /// The rest of the file.
+    file: io::Take<File>,
+
+    /// The CRC of the part of the file already copied.
+    crc: crc32fast::Hasher,
+
+    /// The offset in the archive of the entry's local header.
+    offset: u32,
+}
+
+/// A ZIP archive, generated as it is read.
+pub struct ZipStream {
+    entries: Vec<Entry>,
+
+    /// The length of the archive.
+    length: u64,
+
+    /// Bytes to return before reading anything else.
+    buffer: Vec<u8>,
+
+    /// The number of bytes of `buffer` already returned.
+    pos: usize,
+
+    /// The entry whose data is being returned, if any.
+    current: Option<Current>,
+
+    /// The number of entries started.
+    started: usize,
+
+    /// The number of bytes of the archive before the next entry.
+    offset: u64,
+
+    /// The central directory records of the entries finished so far.
+    central: Vec<u8>,
+
+    /// Whether the end of the archive is in `buffer`.
+    finished: bool,
+}
+
+impl ZipStream {
+    /// Returns `None` if the archive would be too big.
+    pub fn new(entries: Vec<Entry>) -> Option<Self> {
+        if entries.len() > usize::from(u16::MAX) { return None; }
+        if entries.iter().any(|entry| entry.name.len() > usize::from(u16::MAX)) { return None; }
+        let length = entries.iter().map(Entry::archive_size).sum::<u64>() + END_SIZE;
+        if length > u64::from(u32::MAX) { return None; }
+        Some(Self {
+            entries,
+            length,
+            buffer: Vec::new(),
+            pos: 0,
+            current: None,
+            started: 0,
+            offset: 0,
+            central: Vec::new(),
+            finished: false,
+        })
+    }
+
+    /// The length of the archive.
+    pub fn len(&self) -> u64 { self.length }
+
+    /// Append to `self.buffer` the local header of the next entry, and
+    /// start copying it.
+    fn start_entry(&mut self) -> io::Result<()> {
+        let index = self.started;
+        let entry = &self.entries[index];
+        let file = File::open(&entry.path)?.take(entry.size);
+        let (time, date) = entry.dos_time_date();
+        let b = &mut self.buffer;
+        b.extend(0x04034b50u32.to_le_bytes());
+        for x in [VERSION, FLAGS, 0, time, date] { b.extend(x.to_le_bytes()); }
+        // The CRC and sizes are in the data descriptor.
+        for x in [0u32, 0, 0] { b.extend(x.to_le_bytes()); }
+        for x in [entry.name.len() as u16, 0] { b.extend(x.to_le_bytes()); }
+        b.extend(entry.name.as_bytes());
+        self.current = Some(Current {index, file, crc: crc32fast::Hasher::new(), offset: self.offset as u32});
+        self.started += 1;
+        self.offset += entry.archive_size() - CENTRAL_HEADER_SIZE - entry.name.len() as u64;
+        Ok(())
+    }
+
+    /// Append to `self.buffer` the data descriptor of `current`, and to
+    /// `self.central` its central directory record.
+    fn finish_entry(&mut self, current: Current) {
+        let entry = &self.entries[current.index];
+        let crc = current.crc.finalize();
+        let size = entry.size as u32;
+        let (time, date) = entry.dos_time_date();
+        for x in [0x08074b50, crc, size, size] { self.buffer.extend(x.to_le_bytes()); }
+        let c = &mut self.central;
+        c.extend(0x02014b50u32.to_le_bytes());
+        for x in [VERSION, VERSION, FLAGS, 0, time, date] { c.extend(x.to_le_bytes()); }
+        for x in [crc, size, size] { c.extend(x.to_le_bytes()); }
+        for x in [entry.name.len() as u16, 0, 0, 0, 0] { c.extend(x.to_le_bytes()); }
+        for x in [0, current.offset] { c.extend(x.to_le_bytes()); }
+        c.extend(entry.name.as_bytes());
+    }
+
+    /// Append to `self.buffer` the central directory and the end record.
+    fn finish(&mut self) {
+        let count = self.entries.len() as u16;
+        let b = &mut self.buffer;
+        b.extend(&self.central);
+        b.extend(0x06054b50u32.to_le_bytes());
+        for x in [0, 0, count, count] { b.extend(x.to_le_bytes()); }
+        for x in [self.central.len() as u32, self.offset as u32] { b.extend(x.to_le_bytes()); }
+        b.extend(0u16.to_le_bytes());
+        self.finished = true;
+    }
+}
+
+impl Read for ZipStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() { return Ok(0); }
+        loop {
+            if self.pos < self.buffer.len() {
+                let n = buf.len().min(self.buffer.len() - self.pos);
+                buf[..n].copy_from_slice(&self.buffer[self.pos..][..n]);
+                self.pos += n;
+                return Ok(n);
+            }
+            self.buffer.clear();
+            self.pos = 0;
+            if let Some(mut current) = self.current.take() {
+                let n = current.file.read(buf)?;
+                if n > 0 {
+                    current.crc.update(&buf[..n]);
+                    self.current = Some(current);
+                    return Ok(n);
+                }
+                if current.file.limit() > 0 {
+                    // The archive would not have the promised length.
+                    let name = &self.entries[current.index].name;
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("{} got shorter", name)));
+                }
+                self.finish_entry(current);
+            } else if self.started < self.entries.len() {
+                self.start_entry()?;
+            } else if !self.finished {
+                self.finish();
+            } else {
+                return Ok(0);
+            }
+        }
+    }
+}